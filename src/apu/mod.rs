@@ -0,0 +1,405 @@
+mod units;
+mod square;
+mod wave;
+mod noise;
+
+use std::mem;
+
+use self::square::Square;
+use self::wave::Wave;
+use self::noise::Noise;
+
+pub const DEFAULT_SAMPLE_RATE : u32 = 44100;
+
+const M_CYCLES_PER_SECOND : u32 = 1048576;
+
+// The frame sequencer runs at 512 Hz
+const SEQUENCER_PERIOD : u32 = 2048;
+
+// Audio processing unit: two pulse channels, a wave channel and a noise
+// channel, mapped at 0xFF10 - 0xFF3F. Mixed stereo output is resampled to
+// the rate chosen by the caller and queued until taken.
+pub struct Apu {
+    square1 : Square,
+    square2 : Square,
+    wave : Wave,
+    noise : Noise,
+    // Last values written to 0xFF10 - 0xFF2F, for reading back
+    registers : [u8; 0x20],
+    nr50 : u8,
+    nr51 : u8,
+    powered : bool,
+    sequencer_timer : u32,
+    sequencer_step : u8,
+    sample_rate : u32,
+    sample_clock : u32,
+    // Sums of the output since the last sample, averaged into it. Index 0
+    // is the mix, 1 - 4 are the single channels.
+    sums : [(f32, f32); 5],
+    sum_count : u32,
+    // High-pass filters removing the DC offset of the DACs, like the
+    // capacitors on the real output
+    capacitors : [(f32, f32); 5],
+    charge_factor : f32,
+    samples : Vec<i16>,
+    // Each channel on its own, panned and scaled like in the mix; only
+    // filled while capturing is turned on
+    capture_channels : bool,
+    channel_samples : [Vec<i16>; 4]
+}
+
+impl Apu<> {
+    pub fn new() -> Self {
+        let mut apu = Apu {
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            registers: [0; 0x20],
+            nr50: 0,
+            nr51: 0,
+            powered: false,
+            sequencer_timer: 0,
+            sequencer_step: 0,
+            sample_rate: 0,
+            sample_clock: 0,
+            sums: [(0.0, 0.0); 5],
+            sum_count: 0,
+            capacitors: [(0.0, 0.0); 5],
+            charge_factor: 0.0,
+            samples: Vec::new(),
+            capture_channels: false,
+            channel_samples: [Vec::new(), Vec::new(), Vec::new(), Vec::new()]
+        };
+
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+
+        // Values left by the DMG boot ROM once its chime has faded out
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF11, 0x80);
+        apu.write(0xFF12, 0xF3);
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF25, 0xF3);
+
+        apu
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+        self.sample_clock = 0;
+        self.charge_factor = 0.999958f32.powf(4194304.0 / rate as f32);
+    }
+
+    // Interleaved left/right samples produced since the last call
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        mem::take(&mut self.samples)
+    }
+
+    pub fn set_capture_channels(&mut self, capture: bool) {
+        self.capture_channels = capture;
+    }
+
+    // Like take_samples, for channel 1 - 4 alone. Empty unless capturing
+    // was turned on with set_capture_channels.
+    pub fn take_channel_samples(&mut self, channel: usize) -> Vec<i16> {
+        mem::take(&mut self.channel_samples[channel - 1])
+    }
+
+    // Advances the APU by one M-cycle
+    pub fn tick(&mut self) {
+        if self.powered {
+            self.square1.tick(4);
+            self.square2.tick(4);
+            self.wave.tick(4);
+            self.noise.tick(4);
+
+            self.sequencer_timer += 1;
+
+            if self.sequencer_timer == SEQUENCER_PERIOD {
+                self.sequencer_timer = 0;
+                self.clock_sequencer();
+            }
+        }
+
+        let outputs = self.channel_outputs();
+
+        for (i, &(left, right)) in outputs.iter().enumerate() {
+            self.sums[0].0 += left;
+            self.sums[0].1 += right;
+
+            if self.capture_channels {
+                self.sums[i + 1].0 += left;
+                self.sums[i + 1].1 += right;
+            }
+        }
+
+        self.sum_count += 1;
+
+        self.sample_clock += self.sample_rate;
+
+        if self.sample_clock >= M_CYCLES_PER_SECOND {
+            self.sample_clock -= M_CYCLES_PER_SECOND;
+            self.emit_sample();
+        }
+    }
+
+    // Length counters run at 256 Hz, the sweep at 128 Hz and envelopes at
+    // 64 Hz
+    fn clock_sequencer(&mut self) {
+        match self.sequencer_step {
+            0 | 4 => self.clock_length(),
+            2 | 6 => {
+                self.clock_length();
+                self.square1.clock_sweep();
+            }
+            7 => {
+                self.square1.clock_envelope();
+                self.square2.clock_envelope();
+                self.noise.clock_envelope();
+            }
+            _ => {}
+        }
+
+        self.sequencer_step = (self.sequencer_step + 1) & 0x07;
+    }
+
+    fn clock_length(&mut self) {
+        self.square1.clock_length();
+        self.square2.clock_length();
+        self.wave.clock_length();
+        self.noise.clock_length();
+    }
+
+    // Left and right output of every channel after panning and master
+    // volume. Each is between -0.25 and 0.25, so the sum stays within -1.0
+    // and 1.0.
+    fn channel_outputs(&self) -> [(f32, f32); 4] {
+        let channels = [
+            (self.square1.dac_enabled(), self.square1.output()),
+            (self.square2.dac_enabled(), self.square2.output()),
+            (self.wave.dac_enabled(), self.wave.output()),
+            (self.noise.dac_enabled(), self.noise.output())
+        ];
+
+        let left_volume = ((self.nr50 >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (self.nr50 & 0x07) as f32 + 1.0;
+
+        let mut outputs = [(0.0, 0.0); 4];
+
+        for (i, &(dac, value)) in channels.iter().enumerate() {
+            // A disabled DAC outputs nothing, while an enabled one maps 0 -
+            // 15 to a voltage between 1 and -1
+            if !self.powered || !dac {
+                continue;
+            }
+
+            let analog = 1.0 - value as f32 / 7.5;
+
+            if self.nr51 & (0x10 << i) != 0 {
+                outputs[i].0 = analog * left_volume / 32.0;
+            }
+
+            if self.nr51 & (0x01 << i) != 0 {
+                outputs[i].1 = analog * right_volume / 32.0;
+            }
+        }
+
+        outputs
+    }
+
+    fn emit_sample(&mut self) {
+        let streams = if self.capture_channels { 5 } else { 1 };
+
+        for stream in 0..streams {
+            let (left, right) = self.sums[stream];
+            let left = self.high_pass(stream, true, left / self.sum_count as f32);
+            let right = self.high_pass(stream, false, right / self.sum_count as f32);
+
+            let samples = if stream == 0 {
+                &mut self.samples
+            } else {
+                &mut self.channel_samples[stream - 1]
+            };
+
+            samples.push(to_pcm(left));
+            samples.push(to_pcm(right));
+
+            self.sums[stream] = (0.0, 0.0);
+        }
+
+        self.sum_count = 0;
+    }
+
+    fn high_pass(&mut self, stream: usize, left: bool, input: f32) -> f32 {
+        let capacitor = if left {
+            &mut self.capacitors[stream].0
+        } else {
+            &mut self.capacitors[stream].1
+        };
+
+        let output = input - *capacitor;
+        *capacitor = input - output * self.charge_factor;
+        output
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF26 => {
+                let status = (self.square1.enabled as u8) |
+                    (self.square2.enabled as u8) << 1 |
+                    (self.wave.enabled as u8) << 2 |
+                    (self.noise.enabled as u8) << 3;
+
+                (self.powered as u8) << 7 | status
+            }
+            0xFF30..=0xFF3F => self.wave.read_ram((address - 0xFF30) as usize),
+            0xFF10..=0xFF2F => self.registers[(address - 0xFF10) as usize],
+            _ => 0xFF
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            // Wave RAM stays accessible while the APU is off
+            0xFF30..=0xFF3F => return self.wave.write_ram((address - 0xFF30) as usize, value),
+            0xFF26 => return self.set_power(value & 0x80 != 0),
+            0xFF10..=0xFF2F => {}
+            _ => return
+        }
+
+        if !self.powered {
+            // Only the length counters can be loaded while the APU is off
+            match address {
+                0xFF11 => self.square1.length.load(value & 0x3F),
+                0xFF16 => self.square2.length.load(value & 0x3F),
+                0xFF1B => self.wave.length.load(value),
+                0xFF20 => self.noise.length.load(value & 0x3F),
+                _ => {}
+            }
+
+            return;
+        }
+
+        self.registers[(address - 0xFF10) as usize] = value;
+
+        match address {
+            0xFF10..=0xFF14 => self.square1.write(address - 0xFF10, value),
+            0xFF16..=0xFF19 => self.square2.write(address - 0xFF15, value),
+            0xFF1A..=0xFF1E => self.wave.write(address - 0xFF1A, value),
+            0xFF20..=0xFF23 => self.noise.write(address - 0xFF1F, value),
+            0xFF24 => self.nr50 = value,
+            0xFF25 => self.nr51 = value,
+            _ => {}
+        }
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if on && !self.powered {
+            // The frame sequencer restarts so the next step is 0
+            self.sequencer_timer = 0;
+            self.sequencer_step = 0;
+        } else if !on && self.powered {
+            // Powering off clears every register but wave RAM
+            let mut ram = [0; 16];
+
+            for (i, byte) in ram.iter_mut().enumerate() {
+                *byte = self.wave.read_ram(i);
+            }
+
+            self.square1 = Square::new(true);
+            self.square2 = Square::new(false);
+            self.wave = Wave::new();
+            self.noise = Noise::new();
+            self.registers = [0; 0x20];
+            self.nr50 = 0;
+            self.nr51 = 0;
+
+            for (i, &byte) in ram.iter().enumerate() {
+                self.wave.write_ram(i, byte);
+            }
+        }
+
+        self.powered = on;
+    }
+}
+
+fn to_pcm(value: f32) -> i16 {
+    (value.clamp(-1.0, 1.0) * 32767.0) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Apu, SEQUENCER_PERIOD};
+
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.tick();
+        }
+    }
+
+    #[test]
+    fn samples_come_at_the_chosen_rate() {
+        let mut apu = Apu::new();
+        apu.set_sample_rate(32768);
+
+        // 1/32 of a second
+        run(&mut apu, 32768);
+        assert_eq!(apu.take_samples().len(), 1024 * 2);
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn channels_are_only_captured_on_request() {
+        let mut apu = Apu::new();
+        run(&mut apu, 1024);
+        assert!(apu.take_channel_samples(1).is_empty());
+
+        apu.set_capture_channels(true);
+        run(&mut apu, 1024);
+
+        let mixed = apu.take_samples().len();
+        assert!(mixed > 0);
+
+        for channel in 1..5 {
+            assert_eq!(apu.take_channel_samples(channel).len(), mixed / 2);
+        }
+    }
+
+    #[test]
+    fn trigger_enables_a_channel_until_its_length_runs_out() {
+        let mut apu = Apu::new();
+
+        // Square 2 with the DAC on and 2 steps of length left
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF16, 62);
+        apu.write(0xFF19, 0xC0);
+        assert_eq!(apu.read(0xFF26) & 0x02, 0x02);
+
+        // Length is clocked on steps 0 and 2 of the sequencer
+        run(&mut apu, SEQUENCER_PERIOD * 2);
+        assert_eq!(apu.read(0xFF26) & 0x02, 0x02);
+
+        run(&mut apu, SEQUENCER_PERIOD);
+        assert_eq!(apu.read(0xFF26) & 0x02, 0x00);
+    }
+
+    #[test]
+    fn power_off_clears_registers_but_not_wave_ram() {
+        let mut apu = Apu::new();
+        apu.write(0xFF30, 0x12);
+        apu.write(0xFF24, 0x55);
+
+        apu.write(0xFF26, 0x00);
+        assert_eq!(apu.read(0xFF26), 0x00);
+        assert_eq!(apu.read(0xFF24), 0x00);
+        assert_eq!(apu.read(0xFF30), 0x12);
+
+        // Registers ignore writes until it is back on
+        apu.write(0xFF24, 0x55);
+        assert_eq!(apu.read(0xFF24), 0x00);
+
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF24, 0x55);
+        assert_eq!(apu.read(0xFF24), 0x55);
+    }
+}
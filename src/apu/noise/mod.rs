@@ -0,0 +1,113 @@
+use super::units::{Envelope, LengthCounter};
+
+// Base periods in T-cycles for each divisor code of NR43
+static DIVISORS : [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Channel 4, a linear feedback shift register clocked at a configurable
+// rate
+pub struct Noise {
+    pub enabled : bool,
+    pub length : LengthCounter,
+    envelope : Envelope,
+    shift : u8,
+    // Narrow mode feeds back into bit 6 too, giving a 7-bit sequence
+    narrow : bool,
+    divisor : u8,
+    timer : i32,
+    lfsr : u16
+}
+
+impl Noise<> {
+    pub fn new() -> Self {
+        Noise {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            shift: 0,
+            narrow: false,
+            divisor: 0,
+            timer: 0,
+            lfsr: 0x7FFF
+        }
+    }
+
+    // Register 1 to 4 of the channel (NR41 - NR44)
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            1 => self.length.load(value & 0x3F),
+            2 => {
+                self.envelope.write(value);
+
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.shift = value >> 4;
+                self.narrow = value & 0x08 != 0;
+                self.divisor = value & 0x07;
+            }
+            _ => {
+                self.length.enabled = value & 0x40 != 0;
+
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    fn period(&self) -> i32 {
+        DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+    }
+
+    pub fn tick(&mut self, cycles: i32) {
+        // Shifts of 14 and 15 stop the LFSR
+        if self.shift >= 14 {
+            return;
+        }
+
+        self.timer -= cycles;
+
+        while self.timer <= 0 {
+            self.timer += self.period();
+
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 0x1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+
+            if self.narrow {
+                self.lfsr = (self.lfsr & !0x40) | (feedback << 6);
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 0x1 != 0 {
+            return 0;
+        }
+
+        self.envelope.volume()
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+}
@@ -0,0 +1,180 @@
+use super::units::{Envelope, LengthCounter};
+
+// Waveforms for 12.5%, 25%, 50% and 75% duty, first step in bit 7
+static DUTY : [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+// Pulse channel 1 (with frequency sweep) or 2 (without)
+pub struct Square {
+    pub enabled : bool,
+    has_sweep : bool,
+    sweep_period : u8,
+    sweep_negate : bool,
+    sweep_shift : u8,
+    sweep_timer : u8,
+    sweep_enabled : bool,
+    shadow : u16,
+    duty : u8,
+    duty_step : u8,
+    pub length : LengthCounter,
+    envelope : Envelope,
+    frequency : u16,
+    timer : i32
+}
+
+impl Square<> {
+    pub fn new(has_sweep: bool) -> Self {
+        Square {
+            enabled: false,
+            has_sweep,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            shadow: 0,
+            duty: 0,
+            duty_step: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            frequency: 0,
+            timer: 0
+        }
+    }
+
+    // Register 0 to 4 of the channel (NRx0 - NRx4)
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                if self.has_sweep {
+                    self.sweep_period = (value >> 4) & 0x07;
+                    self.sweep_negate = value & 0x08 != 0;
+                    self.sweep_shift = value & 0x07;
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value & 0x3F);
+            }
+            2 => {
+                self.envelope.write(value);
+
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+
+        if self.has_sweep {
+            self.shadow = self.frequency;
+            self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+            self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+
+            if self.sweep_shift != 0 {
+                self.sweep_frequency();
+            }
+        }
+    }
+
+    // Advances the frequency timer by the given number of T-cycles
+    pub fn tick(&mut self, cycles: i32) {
+        self.timer -= cycles;
+
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_step = (self.duty_step + 1) & 0x07;
+        }
+    }
+
+    // Current digital output, 0 to 15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        if (DUTY[self.duty as usize] >> (7 - self.duty_step)) & 0x1 != 0 {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if !self.has_sweep {
+            return;
+        }
+
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+
+        if self.sweep_timer != 0 {
+            return;
+        }
+
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+
+        if self.sweep_enabled && self.sweep_period != 0 {
+            let frequency = self.sweep_frequency();
+
+            if frequency <= 2047 && self.sweep_shift != 0 {
+                self.frequency = frequency;
+                self.shadow = frequency;
+
+                // The new value is checked for overflow once more, without
+                // being written back
+                self.sweep_frequency();
+            }
+        }
+    }
+
+    // Next sweep frequency; going past 2047 silences the channel
+    fn sweep_frequency(&mut self) -> u16 {
+        let delta = self.shadow >> self.sweep_shift;
+
+        let frequency = if self.sweep_negate {
+            self.shadow.wrapping_sub(delta)
+        } else {
+            self.shadow + delta
+        };
+
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+
+        frequency
+    }
+}
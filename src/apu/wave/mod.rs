@@ -0,0 +1,110 @@
+use super::units::LengthCounter;
+
+// Channel 3, playing back the 32 4-bit samples of wave RAM
+pub struct Wave {
+    pub enabled : bool,
+    dac : bool,
+    pub length : LengthCounter,
+    volume_code : u8,
+    frequency : u16,
+    timer : i32,
+    position : u8,
+    sample : u8,
+    ram : [u8; 16]
+}
+
+impl Wave<> {
+    pub fn new() -> Self {
+        Wave {
+            enabled: false,
+            dac: false,
+            length: LengthCounter::new(256),
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            ram: [0; 16]
+        }
+    }
+
+    // Register 0 to 4 of the channel (NR30 - NR34)
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.dac = value & 0x80 != 0;
+
+                if !self.dac {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value),
+            2 => self.volume_code = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    pub fn read_ram(&self, index: usize) -> u8 {
+        self.ram[index]
+    }
+
+    pub fn write_ram(&mut self, index: usize, value: u8) {
+        self.ram[index] = value;
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.dac
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    pub fn tick(&mut self, cycles: i32) {
+        self.timer -= cycles;
+
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) & 0x1F;
+
+            // High nibble first
+            let byte = self.ram[self.position as usize / 2];
+            self.sample = if self.position & 0x1 == 0 { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        // Mute, 100%, 50% and 25%
+        match self.volume_code {
+            0 => 0,
+            1 => self.sample,
+            2 => self.sample >> 1,
+            _ => self.sample >> 2
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+}
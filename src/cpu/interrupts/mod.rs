@@ -0,0 +1,94 @@
+use super::Address;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    VBlank,
+    LcdStat,
+    Timer,
+    Serial,
+    Joypad
+}
+
+// Ordered from highest to lowest priority
+static PRIORITY : [Interrupt; 5] = [
+    Interrupt::VBlank,
+    Interrupt::LcdStat,
+    Interrupt::Timer,
+    Interrupt::Serial,
+    Interrupt::Joypad
+];
+
+impl Interrupt<> {
+    // Bit of IE and IF belonging to the interrupt
+    pub fn mask(&self) -> u8 {
+        match *self {
+            Interrupt::VBlank => 0x01,
+            Interrupt::LcdStat => 0x02,
+            Interrupt::Timer => 0x04,
+            Interrupt::Serial => 0x08,
+            Interrupt::Joypad => 0x10
+        }
+    }
+
+    pub fn vector(&self) -> Address {
+        match *self {
+            Interrupt::VBlank => 0x40,
+            Interrupt::LcdStat => 0x48,
+            Interrupt::Timer => 0x50,
+            Interrupt::Serial => 0x58,
+            Interrupt::Joypad => 0x60
+        }
+    }
+}
+
+// IE (0xFFFF) and IF (0xFF0F)
+pub struct Interrupts {
+    enable : u8,
+    flags : u8
+}
+
+impl Interrupts<> {
+    pub fn new() -> Self {
+        Interrupts {
+            enable: 0,
+            flags: 0
+        }
+    }
+
+    pub fn request(&mut self, interrupt: Interrupt) {
+        self.flags |= interrupt.mask();
+    }
+
+    pub fn acknowledge(&mut self, interrupt: Interrupt) {
+        self.flags &= !interrupt.mask();
+    }
+
+    // All eight bits of IE are plain storage
+    pub fn read_enable(&self) -> u8 {
+        self.enable
+    }
+
+    pub fn write_enable(&mut self, value: u8) {
+        self.enable = value;
+    }
+
+    // The upper three bits of IF are unused and read as 1
+    pub fn read_flags(&self) -> u8 {
+        self.flags | 0xE0
+    }
+
+    pub fn write_flags(&mut self, value: u8) {
+        self.flags = value & 0x1F;
+    }
+
+    // Interrupts both requested and enabled
+    pub fn pending(&self) -> u8 {
+        self.enable & self.flags & 0x1F
+    }
+
+    pub fn highest(&self) -> Option<Interrupt> {
+        let pending = self.pending();
+
+        PRIORITY.iter().cloned().find(|interrupt| pending & interrupt.mask() != 0)
+    }
+}
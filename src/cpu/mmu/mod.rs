@@ -0,0 +1,264 @@
+use rom::{self, Mapper};
+use timer::Timer;
+use ppu::{Ppu, Renderer};
+use apu::Apu;
+
+use super::Address;
+use super::interrupts::Interrupts;
+
+// Bits of each I/O register that are not backed by any latch and always
+// read back as 1 on DMG hardware. Registers that do not exist at all are
+// fully unmapped and read as 0xFF.
+static IO_UNUSED_BITS : [u8; 0x80] = [
+    // 0xFF00 - 0xFF0F: P1, SB, SC, -, DIV, TIMA, TMA, TAC, -, ..., IF
+    0xC0, 0x00, 0x7E, 0xFF, 0x00, 0x00, 0x00, 0xF8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xE0,
+    // 0xFF10 - 0xFF1F: NR10 - NR34
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+    // 0xFF20 - 0xFF2F: NR41 - NR52
+    0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    // 0xFF30 - 0xFF3F: wave RAM
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // 0xFF40 - 0xFF4F: LCDC, STAT, SCY, SCX, LY, LYC, DMA, BGP, OBP0, OBP1, WY, WX
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF
+];
+
+// I/O register contents left behind by the DMG boot ROM.
+// The sound registers are set up by the Apu itself.
+static IO_POST_BOOT : [(Address, u8); 2] = [
+    (0xFF00, 0x30), (0xFF02, 0x00)
+];
+
+pub struct Mmu {
+    mapper : Box<dyn Mapper>,
+    vram : [u8; 0x2000],
+    wram : [u8; 0x2000],
+    oam : [u8; 0xA0],
+    io : [u8; 0x80],
+    hram : [u8; 0x7F],
+    pub interrupts : Interrupts,
+    pub timer : Timer,
+    pub ppu : Ppu,
+    pub apu : Apu
+}
+
+impl Mmu<> {
+    pub fn new(cart: rom::Cartridge, renderer: Renderer) -> Self {
+        Mmu::with_mapper(cart.into_mapper(), renderer)
+    }
+
+    pub fn with_mapper(mapper: Box<dyn Mapper>, renderer: Renderer) -> Self {
+        let mut mmu = Mmu {
+            mapper,
+            vram: [0; 0x2000],
+            wram: [0; 0x2000],
+            oam: [0; 0xA0],
+            io: [0; 0x80],
+            hram: [0; 0x7F],
+            interrupts: Interrupts::new(),
+            timer: Timer::new(),
+            ppu: Ppu::new(renderer),
+            apu: Apu::new()
+        };
+
+        for &(address, value) in IO_POST_BOOT.iter() {
+            mmu.io[(address - 0xFF00) as usize] = value;
+        }
+
+        // The boot ROM leaves a VBlank request behind
+        mmu.interrupts.write_flags(0x01);
+
+        mmu
+    }
+
+    pub fn mapper(&self) -> &dyn Mapper {
+        &*self.mapper
+    }
+
+    pub fn mapper_mut(&mut self) -> &mut dyn Mapper {
+        &mut *self.mapper
+    }
+
+    // Advances every device on the bus by the given number of M-cycles
+    pub fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            self.timer.tick(&mut self.interrupts);
+            self.ppu.tick(&self.vram, &self.oam, &mut self.interrupts);
+            self.apu.tick();
+        }
+
+        self.mapper.tick(cycles);
+    }
+
+    pub fn read_byte(&self, address: Address) -> u8 {
+        let addr = address as usize;
+
+        match address {
+            0x0000..=0x7FFF => self.mapper.read_rom(address),
+            0x8000..=0x9FFF => self.vram[addr - 0x8000],
+            0xA000..=0xBFFF => self.mapper.read_ram(address),
+            0xC000..=0xDFFF => self.wram[addr - 0xC000],
+            // Echo RAM mirrors 0xC000 - 0xDDFF
+            0xE000..=0xFDFF => self.wram[addr - 0xE000],
+            0xFE00..=0xFE9F => self.oam[addr - 0xFE00],
+            // DMG reads zero from the unusable area
+            0xFEA0..=0xFEFF => 0x00,
+            0xFF00 => {
+                // No buttons are pressed, so every input line reads high
+                0xCF | (self.io[0] & 0x30)
+            }
+            0xFF04..=0xFF07 => self.timer.read(address),
+            0xFF0F => self.interrupts.read_flags(),
+            0xFF10..=0xFF3F => self.apu.read(address) | IO_UNUSED_BITS[addr - 0xFF00],
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.ppu.read(address),
+            0xFF01..=0xFF7F => {
+                let index = addr - 0xFF00;
+                self.io[index] | IO_UNUSED_BITS[index]
+            }
+            0xFF80..=0xFFFE => self.hram[addr - 0xFF80],
+            _ => self.interrupts.read_enable()
+        }
+    }
+
+    pub fn read_word(&self, address: Address) -> u16 {
+        let lo = self.read_byte(address) as u16;
+        let hi = self.read_byte(address.wrapping_add(1)) as u16;
+
+        hi << 8 | lo
+    }
+
+    pub fn write_byte(&mut self, address: Address, value: u8) {
+        let addr = address as usize;
+
+        match address {
+            0x0000..=0x7FFF => self.mapper.write_register(address, value),
+            0x8000..=0x9FFF => self.vram[addr - 0x8000] = value,
+            0xA000..=0xBFFF => self.mapper.write_ram(address, value),
+            0xC000..=0xDFFF => self.wram[addr - 0xC000] = value,
+            0xE000..=0xFDFF => self.wram[addr - 0xE000] = value,
+            0xFE00..=0xFE9F => self.oam[addr - 0xFE00] = value,
+            0xFEA0..=0xFEFF => {}
+            0xFF04..=0xFF07 => self.timer.write(address, value),
+            0xFF0F => self.interrupts.write_flags(value),
+            0xFF10..=0xFF3F => self.apu.write(address, value),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.ppu.write(address, value),
+            0xFF46 => {
+                self.io[0x46] = value;
+                self.dma(value);
+            }
+            0xFF00..=0xFF7F => self.io[addr - 0xFF00] = value,
+            0xFF80..=0xFFFE => self.hram[addr - 0xFF80] = value,
+            _ => self.interrupts.write_enable(value)
+        }
+    }
+
+    pub fn write_word(&mut self, address: Address, value: u16) {
+        self.write_byte(address, (value & 0xFF) as u8);
+        self.write_byte(address.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    // OAM DMA copies 160 bytes from value * 0x100 into OAM. The transfer is
+    // done at once rather than over 160 M-cycles.
+    fn dma(&mut self, value: u8) {
+        let source = (value as Address) << 8;
+
+        for i in 0..0xA0 {
+            let byte = self.read_byte(source + i);
+            self.oam[i as usize] = byte;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ppu::Renderer;
+    use rom::Cartridge;
+
+    use super::Mmu;
+
+    // Plain 32 KiB cartridge whose ROM bytes are their address' low byte
+    fn mmu() -> Mmu {
+        let mut rom : Vec<u8> = (0..0x8000).map(|address| address as u8).collect();
+        rom[0x147] = 0x00;
+        rom[0x148] = 0x00;
+        rom[0x149] = 0x00;
+
+        Mmu::new(Cartridge::new(rom).unwrap(), Renderer::Scanline)
+    }
+
+    #[test]
+    fn rom_is_read_through_the_mapper() {
+        let mut mmu = mmu();
+
+        assert_eq!(mmu.read_byte(0x0042), 0x42);
+        assert_eq!(mmu.read_word(0x7FFE), 0xFFFE);
+
+        // Writes only reach the controller registers
+        mmu.write_byte(0x0042, 0x00);
+        assert_eq!(mmu.read_byte(0x0042), 0x42);
+    }
+
+    #[test]
+    fn echo_ram_mirrors_work_ram() {
+        let mut mmu = mmu();
+
+        mmu.write_byte(0xC123, 0x5A);
+        assert_eq!(mmu.read_byte(0xE123), 0x5A);
+
+        mmu.write_byte(0xFDFF, 0xA5);
+        assert_eq!(mmu.read_byte(0xDDFF), 0xA5);
+    }
+
+    #[test]
+    fn unusable_area_ignores_writes() {
+        let mut mmu = mmu();
+
+        mmu.write_byte(0xFEA0, 0x12);
+        assert_eq!(mmu.read_byte(0xFEA0), 0x00);
+    }
+
+    #[test]
+    fn unused_io_bits_read_as_set() {
+        let mut mmu = mmu();
+
+        mmu.write_byte(0xFF02, 0x00);
+        assert_eq!(mmu.read_byte(0xFF02), 0x7E);
+
+        // No register lives at 0xFF03
+        mmu.write_byte(0xFF03, 0x00);
+        assert_eq!(mmu.read_byte(0xFF03), 0xFF);
+
+        mmu.write_byte(0xFF0F, 0x00);
+        assert_eq!(mmu.read_byte(0xFF0F), 0xE0);
+    }
+
+    #[test]
+    fn high_ram_and_interrupt_enable() {
+        let mut mmu = mmu();
+
+        mmu.write_byte(0xFF80, 0x11);
+        mmu.write_byte(0xFFFE, 0x22);
+        mmu.write_byte(0xFFFF, 0x1F);
+
+        assert_eq!(mmu.read_byte(0xFF80), 0x11);
+        assert_eq!(mmu.read_byte(0xFFFE), 0x22);
+        assert_eq!(mmu.interrupts.read_enable() & 0x1F, 0x1F);
+    }
+
+    #[test]
+    fn dma_copies_a_page_into_oam() {
+        let mut mmu = mmu();
+
+        for i in 0..0xA0 {
+            mmu.write_byte(0xC100 + i, i as u8 ^ 0xFF);
+        }
+
+        mmu.write_byte(0xFF46, 0xC1);
+
+        for i in 0..0xA0 {
+            assert_eq!(mmu.read_byte(0xFE00 + i), i as u8 ^ 0xFF);
+        }
+    }
+}
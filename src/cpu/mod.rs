@@ -1,31 +1,13 @@
-extern crate num;
 extern crate std;
 
-use cpu::num::traits::PrimInt as PrimInt;
-
 type Address = u16;
 
 mod registers;
+mod mmu;
+mod interrupts;
 
-pub struct Mmu;
-
-impl Mmu<> {
-    pub fn read_byte(&self, address: Address) -> i8 {
-        unimplemented!();
-    }
-
-    pub fn read_word(&self, address: Address) -> i16 {
-        unimplemented!();
-    }
-
-    pub fn write_byte(&self, address: Address, value: i8) {
-        unimplemented!();
-    }
-
-    pub fn write_word(&self, address: Address, value: i16) {
-        unimplemented!();
-    }
-}
+pub use self::mmu::Mmu;
+pub use self::interrupts::{Interrupt, Interrupts};
 
 pub enum CpuFlags {
     C,
@@ -34,2053 +16,2129 @@ pub enum CpuFlags {
     Z
 }
 
-fn check_half_carry<T>(a: T, b: T) -> bool where T: PrimInt {
-    let nibble_mask = T::from(0xF).unwrap();
-    let half_mask = T::from(0x10).unwrap();
-    return ((a & nibble_mask) + (b & nibble_mask) & half_mask) == half_mask;
+impl CpuFlags<> {
+    // Bit of the F register holding the flag
+    fn mask(&self) -> u8 {
+        match *self {
+            CpuFlags::C => 0x10,
+            CpuFlags::H => 0x20,
+            CpuFlags::N => 0x40,
+            CpuFlags::Z => 0x80
+        }
+    }
 }
 
-fn check_carry<T>(a: T, b: T) -> bool where T: PrimInt {
-    let byte_mask = T::from(0xFF).unwrap();
-    let carry_mask = T::from(0x100).unwrap();
-    
-    return (((a & byte_mask) + (b & byte_mask)) & carry_mask) == carry_mask;
-}
+// Base duration of every opcode in M-cycles. Conditional instructions list
+// the cost of the branch not being taken; CB-prefixed instructions are
+// accounted for by their own decoder. Illegal opcodes take the one cycle of
+// their fetch before the CPU locks up.
+static OP_CYCLES : [u32; 256] = [
+    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,
+    1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+    2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+    2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 0, 3, 6, 2, 4,
+    2, 3, 3, 1, 3, 4, 2, 4, 2, 4, 3, 1, 3, 1, 2, 4,
+    3, 3, 2, 1, 1, 4, 2, 4, 4, 1, 4, 1, 1, 1, 2, 4,
+    3, 3, 2, 1, 1, 4, 2, 4, 3, 2, 4, 1, 1, 1, 2, 4
+];
 
 pub struct Cpu {
     pub registers : registers::File,
     pub mmu : Mmu,
-    flags : u8
+    ime : bool,
+    // Instructions left until a pending EI sets IME
+    ei_delay : u8,
+    halted : bool,
+    halt_bug : bool,
+    locked : bool
 }
 
 #[allow(dead_code)]
 impl Cpu<> {
-    // pub fn new(mut mmu: Mmu) -> Self {
-    //    let mut myself = Cpu{ mmu: mmu };
-    //    return myself;
-    // }
+    pub fn new(mmu: Mmu) -> Self {
+        let mut myself = Cpu {
+            registers: registers::File::new(),
+            mmu,
+            ime: false,
+            ei_delay: 0,
+            halted: false,
+            halt_bug: false,
+            locked: false
+        };
 
-    pub fn toggle_flag(&mut self, flags: CpuFlags) {
-        self.flags |= match flags {
-            CpuFlags::C => 0x10,
-            CpuFlags::H => 0x20,
-            CpuFlags::N => 0x40,
-            CpuFlags::Z => 0x80,
+        // DMG state right after the boot ROM hands control to the cartridge
+        myself.registers.set_af(0x01B0);
+        myself.registers.set_bc(0x0013);
+        myself.registers.set_de(0x00D8);
+        myself.registers.set_hl(0x014D);
+        myself.registers.sp.write(0xFFFE);
+        myself.registers.pc.write(0x0100);
+
+        myself
+    }
+
+    // Set by HALT until an interrupt is pending
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // Set for good by an illegal opcode
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    // Runs a single instruction, or services an interrupt, and returns how
+    // many M-cycles it took. The rest of the machine is advanced by the same
+    // amount.
+    pub fn step(&mut self) -> u32 {
+        let cycles = self.execute();
+        self.mmu.tick(cycles);
+        cycles
+    }
+
+    fn execute(&mut self) -> u32 {
+        // An illegal opcode freezes the CPU for good
+        if self.locked {
+            return 1;
+        }
+
+        let pending = self.mmu.interrupts.pending() != 0;
+
+        // A pending interrupt ends HALT even when IME is clear, in which
+        // case execution simply resumes after the HALT
+        if self.halted {
+            if !pending {
+                return 1;
+            }
+
+            self.halted = false;
+        }
+
+        if self.ime && pending {
+            return self.dispatch_interrupt();
+        }
+
+        let op_code = self.fetch_byte();
+        let cycles = self.opexec(op_code);
+
+        if self.ei_delay > 0 {
+            self.ei_delay -= 1;
+
+            if self.ei_delay == 0 {
+                self.ime = true;
+            }
+        }
+
+        cycles
+    }
+
+    // Pushes PC and jumps to the vector of the highest priority pending
+    // interrupt, which takes 5 M-cycles
+    fn dispatch_interrupt(&mut self) -> u32 {
+        let interrupt = match self.mmu.interrupts.highest() {
+            Some(interrupt) => interrupt,
+            None => return 0
         };
+
+        self.ime = false;
+        self.mmu.interrupts.acknowledge(interrupt);
+
+        let pc = self.registers.pc.read();
+        self.push_word(pc);
+        self.registers.pc.jmp(interrupt.vector());
+
+        5
+    }
+
+    fn fetch_byte(&mut self) -> u8 {
+        let pc = self.registers.pc.read();
+
+        // The HALT bug fails to increment PC, so the byte following HALT
+        // is read twice
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.registers.pc.write(pc.wrapping_add(1));
+        }
+
+        self.mmu.read_byte(pc)
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        let lo = self.fetch_byte() as u16;
+        let hi = self.fetch_byte() as u16;
+        hi << 8 | lo
+    }
+
+    pub fn toggle_flag(&mut self, flags: CpuFlags) {
+        let f = self.registers.f() | flags.mask();
+        self.registers.set_f(f);
     }
 
     pub fn untoggle_flag(&mut self, flags: CpuFlags) {
-         self.flags &= match flags {
-            CpuFlags::C => 0x10,
-            CpuFlags::H => 0x20,
-            CpuFlags::N => 0x40,
-            CpuFlags::Z => 0x80,
-        };
+        let f = self.registers.f() & !flags.mask();
+        self.registers.set_f(f);
+    }
+
+    pub fn get_flag(&self, flags: CpuFlags) -> bool {
+        self.registers.f() & flags.mask() != 0
+    }
+
+    fn set_flag(&mut self, flags: CpuFlags, value: bool) {
+        if value {
+            self.toggle_flag(flags);
+        } else {
+            self.untoggle_flag(flags);
+        }
+    }
+
+    fn read_hl(&mut self) -> u8 {
+        let address = self.registers.hl();
+        self.mmu.read_byte(address)
+    }
+
+    fn write_hl(&mut self, value: u8) {
+        let address = self.registers.hl();
+        self.mmu.write_byte(address, value);
+    }
+
+    fn push_word(&mut self, value: u16) {
+        let address = self.registers.sp.push(2);
+        self.mmu.write_word(address, value);
+    }
+
+    fn pop_word(&mut self) -> u16 {
+        let address = self.registers.sp.read();
+        self.registers.sp.pop(2);
+        self.mmu.read_word(address)
+    }
+
+    // Control flow helpers return the extra M-cycles spent when the branch
+    // is taken
+
+    fn jump_relative(&mut self, condition: bool) -> u32 {
+        let offset = self.fetch_byte() as i8;
+
+        if !condition {
+            return 0;
+        }
+
+        self.registers.pc.jr(offset);
+        1
+    }
+
+    fn jump(&mut self, condition: bool) -> u32 {
+        let address = self.fetch_word();
+
+        if !condition {
+            return 0;
+        }
+
+        self.registers.pc.jmp(address);
+        1
+    }
+
+    fn call(&mut self, condition: bool) -> u32 {
+        let address = self.fetch_word();
+
+        if !condition {
+            return 0;
+        }
+
+        let pc = self.registers.pc.read();
+        self.push_word(pc);
+        self.registers.pc.jmp(address);
+        3
+    }
+
+    fn ret(&mut self, condition: bool) -> u32 {
+        if !condition {
+            return 0;
+        }
+
+        let address = self.pop_word();
+        self.registers.pc.jmp(address);
+        3
+    }
+
+    fn restart(&mut self, address: Address) {
+        let pc = self.registers.pc.read();
+        self.push_word(pc);
+        self.registers.pc.jmp(address);
+    }
+
+    fn alu_add(&mut self, b: u8, carry: bool) {
+        let a = self.registers.a();
+        let c = (carry && self.get_flag(CpuFlags::C)) as u8;
+        let result = a.wrapping_add(b).wrapping_add(c);
+
+        self.set_flag(CpuFlags::Z, result == 0);
+        self.untoggle_flag(CpuFlags::N);
+        self.set_flag(CpuFlags::H, (a & 0xF) + (b & 0xF) + c > 0xF);
+        self.set_flag(CpuFlags::C, a as u16 + b as u16 + c as u16 > 0xFF);
+
+        self.registers.set_a(result);
+    }
+
+    fn alu_sub(&mut self, b: u8, carry: bool) {
+        let a = self.registers.a();
+        let c = (carry && self.get_flag(CpuFlags::C)) as u8;
+        let result = a.wrapping_sub(b).wrapping_sub(c);
+
+        self.set_flag(CpuFlags::Z, result == 0);
+        self.toggle_flag(CpuFlags::N);
+        self.set_flag(CpuFlags::H, (a & 0xF) < (b & 0xF) + c);
+        self.set_flag(CpuFlags::C, (a as u16) < b as u16 + c as u16);
+
+        self.registers.set_a(result);
+    }
+
+    fn alu_and(&mut self, value: u8) {
+        let result = self.registers.a() & value;
+
+        self.set_flag(CpuFlags::Z, result == 0);
+        self.untoggle_flag(CpuFlags::N);
+        self.toggle_flag(CpuFlags::H);
+        self.untoggle_flag(CpuFlags::C);
+
+        self.registers.set_a(result);
+    }
+
+    fn alu_xor(&mut self, value: u8) {
+        let result = self.registers.a() ^ value;
+
+        self.set_flag(CpuFlags::Z, result == 0);
+        self.untoggle_flag(CpuFlags::N);
+        self.untoggle_flag(CpuFlags::H);
+        self.untoggle_flag(CpuFlags::C);
+
+        self.registers.set_a(result);
+    }
+
+    fn alu_or(&mut self, value: u8) {
+        let result = self.registers.a() | value;
+
+        self.set_flag(CpuFlags::Z, result == 0);
+        self.untoggle_flag(CpuFlags::N);
+        self.untoggle_flag(CpuFlags::H);
+        self.untoggle_flag(CpuFlags::C);
+
+        self.registers.set_a(result);
+    }
+
+    // CP is a SUB that throws the result away
+    fn alu_cp(&mut self, value: u8) {
+        let a = self.registers.a();
+        self.alu_sub(value, false);
+        self.registers.set_a(a);
+    }
+
+    fn inc8(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_add(1);
+
+        self.set_flag(CpuFlags::Z, result == 0);
+        self.untoggle_flag(CpuFlags::N);
+        self.set_flag(CpuFlags::H, value & 0xF == 0xF);
+
+        result
+    }
+
+    fn dec8(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_sub(1);
+
+        self.set_flag(CpuFlags::Z, result == 0);
+        self.toggle_flag(CpuFlags::N);
+        self.set_flag(CpuFlags::H, value & 0xF == 0);
+
+        result
     }
 
-    pub fn get_flag(&self, flags: CpuFlags) -> i8 {
-        (match flags {
-            CpuFlags::C => self.flags & 0x10,
-            CpuFlags::H => self.flags & 0x20,
-            CpuFlags::N => self.flags & 0x40,
-            CpuFlags::Z => self.flags & 0x80,
-        }) as i8
+    fn add_hl(&mut self, value: u16) {
+        let hl = self.registers.hl();
+
+        self.untoggle_flag(CpuFlags::N);
+        self.set_flag(CpuFlags::H, (hl & 0xFFF) + (value & 0xFFF) > 0xFFF);
+        self.set_flag(CpuFlags::C, hl as u32 + value as u32 > 0xFFFF);
+
+        self.registers.set_hl(hl.wrapping_add(value));
+    }
+
+    // SP plus a signed immediate, shared by ADD SP, r8 and LD HL, SP + r8.
+    // Flags come from the unsigned addition of the low byte.
+    fn add_sp(&mut self) -> Address {
+        let sp = self.registers.sp.read();
+        let offset = self.fetch_byte() as i8 as u16;
+
+        self.untoggle_flag(CpuFlags::Z);
+        self.untoggle_flag(CpuFlags::N);
+        self.set_flag(CpuFlags::H, (sp & 0xF) + (offset & 0xF) > 0xF);
+        self.set_flag(CpuFlags::C, (sp & 0xFF) + (offset & 0xFF) > 0xFF);
+
+        sp.wrapping_add(offset)
+    }
+
+    fn daa(&mut self) {
+        let mut a = self.registers.a();
+        let mut carry = self.get_flag(CpuFlags::C);
+        let half_carry = self.get_flag(CpuFlags::H);
+
+        if !self.get_flag(CpuFlags::N) {
+            if carry || a > 0x99 {
+                a = a.wrapping_add(0x60);
+                carry = true;
+            }
+
+            if half_carry || a & 0xF > 0x9 {
+                a = a.wrapping_add(0x6);
+            }
+        } else {
+            if carry {
+                a = a.wrapping_sub(0x60);
+            }
+
+            if half_carry {
+                a = a.wrapping_sub(0x6);
+            }
+        }
+
+        self.set_flag(CpuFlags::Z, a == 0);
+        self.untoggle_flag(CpuFlags::H);
+        self.set_flag(CpuFlags::C, carry);
+
+        self.registers.set_a(a);
+    }
+
+    fn set_shift_flags(&mut self, result: u8, carry: bool) {
+        self.set_flag(CpuFlags::Z, result == 0);
+        self.untoggle_flag(CpuFlags::N);
+        self.untoggle_flag(CpuFlags::H);
+        self.set_flag(CpuFlags::C, carry);
+    }
+
+    fn rlc(&mut self, value: u8) -> u8 {
+        let result = value.rotate_left(1);
+        self.set_shift_flags(result, value & 0x80 != 0);
+        result
+    }
+
+    fn rrc(&mut self, value: u8) -> u8 {
+        let result = value.rotate_right(1);
+        self.set_shift_flags(result, value & 0x1 != 0);
+        result
+    }
+
+    fn rl(&mut self, value: u8) -> u8 {
+        let carry = self.get_flag(CpuFlags::C) as u8;
+        let result = value << 1 | carry;
+        self.set_shift_flags(result, value & 0x80 != 0);
+        result
+    }
+
+    fn rr(&mut self, value: u8) -> u8 {
+        let carry = self.get_flag(CpuFlags::C) as u8;
+        let result = value >> 1 | carry << 7;
+        self.set_shift_flags(result, value & 0x1 != 0);
+        result
+    }
+
+    fn sla(&mut self, value: u8) -> u8 {
+        let result = value << 1;
+        self.set_shift_flags(result, value & 0x80 != 0);
+        result
+    }
+
+    // Arithmetic shift, bit 7 keeps its value
+    fn sra(&mut self, value: u8) -> u8 {
+        let result = ((value as i8) >> 1) as u8;
+        self.set_shift_flags(result, value & 0x1 != 0);
+        result
     }
 
-    pub fn opexec(&mut self, op_code: u8) {
+    fn swap(&mut self, value: u8) -> u8 {
+        let result = value.rotate_left(4);
+        self.set_shift_flags(result, false);
+        result
+    }
+
+    fn srl(&mut self, value: u8) -> u8 {
+        let result = value >> 1;
+        self.set_shift_flags(result, value & 0x1 != 0);
+        result
+    }
+
+    // Operands of the CB table in encoding order: B, C, D, E, H, L, (HL), A
+    fn read_operand(&mut self, index: u8) -> u8 {
+        match index {
+            0 => self.registers.b(),
+            1 => self.registers.c(),
+            2 => self.registers.d(),
+            3 => self.registers.e(),
+            4 => self.registers.h(),
+            5 => self.registers.l(),
+            6 => self.read_hl(),
+            _ => self.registers.a()
+        }
+    }
+
+    fn write_operand(&mut self, index: u8, value: u8) {
+        match index {
+            0 => self.registers.set_b(value),
+            1 => self.registers.set_c(value),
+            2 => self.registers.set_d(value),
+            3 => self.registers.set_e(value),
+            4 => self.registers.set_h(value),
+            5 => self.registers.set_l(value),
+            6 => self.write_hl(value),
+            _ => self.registers.set_a(value)
+        }
+    }
+
+    // Decodes the byte following a 0xCB prefix and returns the M-cycles of
+    // the whole instruction, prefix included
+    pub fn cbexec(&mut self, op_code: u8) -> u32 {
+        let operand = op_code & 0x7;
+        let bit = (op_code >> 3) & 0x7;
+        let value = self.read_operand(operand);
+
+        match op_code >> 6 {
+            // RLC, RRC, RL, RR, SLA, SRA, SWAP, SRL
+            0 => {
+                let result = match bit {
+                    0 => self.rlc(value),
+                    1 => self.rrc(value),
+                    2 => self.rl(value),
+                    3 => self.rr(value),
+                    4 => self.sla(value),
+                    5 => self.sra(value),
+                    6 => self.swap(value),
+                    _ => self.srl(value)
+                };
+
+                self.write_operand(operand, result);
+            }
+            // BIT b, r only reads its operand
+            1 => {
+                self.set_flag(CpuFlags::Z, value & (1 << bit) == 0);
+                self.untoggle_flag(CpuFlags::N);
+                self.toggle_flag(CpuFlags::H);
+
+                return if operand == 6 { 3 } else { 2 };
+            }
+            // RES b, r
+            2 => self.write_operand(operand, value & !(1 << bit)),
+            // SET b, r
+            _ => self.write_operand(operand, value | (1 << bit))
+        }
+
+        if operand == 6 { 4 } else { 2 }
+    }
+
+    pub fn opexec(&mut self, op_code: u8) -> u32 {
+        let mut cycles = OP_CYCLES[op_code as usize];
+
         match op_code {
             // NOP
             0x00 => {}
             // LD BC, d16
             0x01 => {
-                let pc = self.registers.pc.read();
-                let data = self.mmu.read_word(pc + 1);
-                self.registers.bc.write(data as i16);
+                let data = self.fetch_word();
+                self.registers.set_bc(data);
             }
             // LD (BC), A
             0x02 => {
-                let address = self.registers.bc.read() as u16;
-                self.mmu.write_byte(address, self.registers.af.read_lo());
+                let address = self.registers.bc();
+                let a = self.registers.a();
+                self.mmu.write_byte(address, a);
             }
             // INC BC
             0x03 => {
-                let val = self.registers.bc.read() + 1;
-                self.registers.bc.write(val);
+                let value = self.registers.bc();
+                self.registers.set_bc(value.wrapping_add(1));
             }
             // INC B
             0x04 => {
-                self.untoggle_flag(CpuFlags::N);
-
-                if !check_half_carry(self.registers.bc.read_lo(), 1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                let bval = self.registers.bc.read_lo();
-
-                self.registers.bc.write_lo(bval + 1);
-                let result = self.registers.bc.read_lo();
-
-                if result == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let b = self.registers.b();
+                let result = self.inc8(b);
+                self.registers.set_b(result);
             }
             // DEC B
             0x05 => {
-                self.toggle_flag(CpuFlags::N);
-
-                if !check_half_carry(self.registers.bc.read_lo(), -1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                let bval = self.registers.bc.read_lo();
-
-                self.registers.bc.write_lo(bval - 1);
-                let result = self.registers.bc.read_lo();
-
-                if result == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let b = self.registers.b();
+                let result = self.dec8(b);
+                self.registers.set_b(result);
             }
+            // LD B, d8
             0x06 => {
-                let pcval = self.registers.pc.read() as i16;
-                self.registers.bc.write_lo(self.mmu.read_byte(pcval as u16 + 1));
+                let data = self.fetch_byte();
+                self.registers.set_b(data);
             }
+            // RLCA
             0x07 => {
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
+                let a = self.registers.a();
+                let result = self.rlc(a);
+                self.registers.set_a(result);
                 self.untoggle_flag(CpuFlags::Z);
-
-                let mut value = self.registers.af.read_lo();
-                for _ in 0..8 {
-                    let mib : bool = (value & (1 << 7)) == (1 << 7);
-                    value <<= 1;
-                    value |= mib as i8;
-
-                    match mib {
-                        false => self.untoggle_flag(CpuFlags::C),
-                        true => self.toggle_flag(CpuFlags::C)
-                    }
-                }
             }
             // LD (a16), SP
             0x08 => {
-                let data = self.mmu.read_word(self.registers.pc.read() as u16 + 1) as u16;
-                self.mmu.write_word(data, self.registers.sp.read() as i16);
+                let address = self.fetch_word() as Address;
+                let sp = self.registers.sp.read();
+                self.mmu.write_word(address, sp);
             }
             // ADD HL, BC
             0x09 => {
-                if !check_half_carry(self.registers.hl.read(), self.registers.bc.read()) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                if !check_carry(self.registers.hl.read(), self.registers.bc.read()) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                
-                let result = self.registers.hl.read() + self.registers.bc.read();
-
-                self.registers.hl.write(result);
-
-                self.untoggle_flag(CpuFlags::N);
+                let value = self.registers.bc();
+                self.add_hl(value);
             }
             // LD A, (BC)
             0x0A => {
-                let address = self.registers.bc.read() as u16;
+                let address = self.registers.bc();
                 let value = self.mmu.read_byte(address);
-                self.registers.af.write_lo(value);
+                self.registers.set_a(value);
             }
             // DEC BC
             0x0B => {
-                let bcval = self.registers.bc.read();
-                self.registers.bc.write(bcval - 1);
+                let value = self.registers.bc();
+                self.registers.set_bc(value.wrapping_sub(1));
             }
             // INC C
             0x0C => {
-                if !check_half_carry(self.registers.bc.read_hi(), 1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-                
-                self.untoggle_flag(CpuFlags::N);
-
-                let result = self.registers.bc.read_hi() + 1;
-
-                if result == 0 {
-                    self.toggle_flag(CpuFlags::Z)
-                }
-
-                self.registers.bc.write_hi(result);
+                let c = self.registers.c();
+                let result = self.inc8(c);
+                self.registers.set_c(result);
             }
             // DEC C
             0x0D => {
-                if !check_half_carry(self.registers.bc.read_hi(), -1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                let result = self.registers.bc.read_hi() - 1;
-
-                if result == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-                
-                self.registers.bc.write_hi(result);
+                let c = self.registers.c();
+                let result = self.dec8(c);
+                self.registers.set_c(result);
             }
             // LD C, d8
             0x0E => {
-                let data = self.mmu.read_byte(self.registers.pc.read() as u16 + 1);
-                self.registers.bc.write_hi(data);
+                let data = self.fetch_byte();
+                self.registers.set_c(data);
             }
+            // RRCA
             0x0F => {
+                let a = self.registers.a();
+                let result = self.rrc(a);
+                self.registers.set_a(result);
                 self.untoggle_flag(CpuFlags::Z);
-                self.untoggle_flag(CpuFlags::N);
-                self.untoggle_flag(CpuFlags::H);
-
-                let mut value = self.registers.af.read_lo();
-
-                for _ in 0..8 {
-                    let lsb : u8 = value as u8 & 0x1;
-                    value >>= 0x1;
-                    value |= (lsb << 0x7) as i8;
-
-                    if value == 0 {
-                        self.untoggle_flag(CpuFlags::C);
-                    } else {
-                        self.toggle_flag(CpuFlags::C);
-                    }
-                }
-
-                self.registers.af.write_lo(value);
             }
+            // STOP
             0x10 => {
-
+                self.fetch_byte();
             }
+            // LD DE, d16
             0x11 => {
-                let data = self.mmu.read_byte(self.registers.pc.read() as u16 + 1);
-                self.registers.bc.write_hi(data);
+                let data = self.fetch_word();
+                self.registers.set_de(data);
             }
+            // LD (DE), A
             0x12 => {
-                let address = self.mmu.read_byte(self.registers.pc.read()) as u16;
-                self.mmu.write_byte(address, self.registers.af.read_lo());
+                let address = self.registers.de();
+                let a = self.registers.a();
+                self.mmu.write_byte(address, a);
             }
+            // INC DE
             0x13 => {
-                let de = self.registers.de.read();
-                self.registers.de.write(de + 1);
+                let value = self.registers.de();
+                self.registers.set_de(value.wrapping_add(1));
             }
+            // INC D
             0x14 => {
-                let d = self.registers.de.read_lo();
-                
-                if !check_half_carry(d, 1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                if d + 1 == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                self.registers.de.write_lo(d + 1);
+                let d = self.registers.d();
+                let result = self.inc8(d);
+                self.registers.set_d(result);
             }
+            // DEC D
             0x15 => {
-                let d = self.registers.de.read_lo();
-
-                if !check_half_carry(d, -1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                if d + 1 == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                self.registers.de.write_lo(d - 1);
+                let d = self.registers.d();
+                let result = self.dec8(d);
+                self.registers.set_d(result);
             }
+            // LD D, d8
             0x16 => {
-                let pcval = self.registers.pc.read() as i16;
-                let data = self.mmu.read_byte(pcval as u16 + 1);
-                self.registers.de.write_lo(data);
+                let data = self.fetch_byte();
+                self.registers.set_d(data);
             }
             // RLA
             0x17 => {
-
+                let a = self.registers.a();
+                let result = self.rl(a);
+                self.registers.set_a(result);
+                self.untoggle_flag(CpuFlags::Z);
             }
             // JR r8
             0x18 => {
-                let mut pcval = self.registers.pc.read() as i16;
-                pcval += self.mmu.read_byte(pcval as u16 + 1) as i16;
-                self.registers.pc.write(pcval);
+                self.jump_relative(true);
             }
             // ADD HL, DE
             0x19 => {
-                let hl = self.registers.hl.read();
-                let de = self.registers.de.read();
-
-                self.untoggle_flag(CpuFlags::N);
-
-                if !check_carry(hl, de) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(hl, de) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.registers.hl.write(hl + de);
+                let value = self.registers.de();
+                self.add_hl(value);
             }
             // LD A, (DE)
             0x1A => {
-                let de = self.registers.de.read() as Address;
-                let memdata = self.mmu.read_byte(de);
-                self.registers.af.write_lo(memdata);
+                let address = self.registers.de();
+                let value = self.mmu.read_byte(address);
+                self.registers.set_a(value);
             }
             // DEC DE
             0x1B => {
-                let de = self.registers.de.read();
-                self.registers.de.write(de - 1);
+                let value = self.registers.de();
+                self.registers.set_de(value.wrapping_sub(1));
             }
             // INC E
             0x1C => {
-                let e = self.registers.de.read_hi();
-
-                if !check_half_carry(e, 1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                if e + 1 == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                self.registers.de.write_hi(e + 1);
+                let e = self.registers.e();
+                let result = self.inc8(e);
+                self.registers.set_e(result);
             }
             // DEC E
             0x1D => {
-                let e = self.registers.de.read_hi();
-
-                if !check_half_carry(e, -1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                if e - 1 == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                self.registers.de.write_hi(e - 1);
+                let e = self.registers.e();
+                let result = self.dec8(e);
+                self.registers.set_e(result);
             }
             // LD E, d8
             0x1E => {
-                let pcval = self.registers.pc.read() as Address;
-                let memdata = self.mmu.read_byte(pcval + 1);
-                self.registers.de.write_hi(memdata);
+                let data = self.fetch_byte();
+                self.registers.set_e(data);
             }
             // RRA
             0x1F => {
-                
+                let a = self.registers.a();
+                let result = self.rr(a);
+                self.registers.set_a(result);
+                self.untoggle_flag(CpuFlags::Z);
             }
+            // JR NZ, r8
             0x20 => {
-
+                let condition = !self.get_flag(CpuFlags::Z);
+                cycles += self.jump_relative(condition);
             }
+            // LD HL, d16
             0x21 => {
-
+                let data = self.fetch_word();
+                self.registers.set_hl(data);
             }
+            // LD (HL+), A
             0x22 => {
-
+                let address = self.registers.hl();
+                let a = self.registers.a();
+                self.mmu.write_byte(address, a);
+                self.registers.set_hl(address.wrapping_add(1));
             }
+            // INC HL
             0x23 => {
-                
+                let value = self.registers.hl();
+                self.registers.set_hl(value.wrapping_add(1));
             }
+            // INC H
             0x24 => {
-
+                let h = self.registers.h();
+                let result = self.inc8(h);
+                self.registers.set_h(result);
             }
+            // DEC H
             0x25 => {
-
+                let h = self.registers.h();
+                let result = self.dec8(h);
+                self.registers.set_h(result);
             }
+            // LD H, d8
             0x26 => {
-
+                let data = self.fetch_byte();
+                self.registers.set_h(data);
             }
+            // DAA
             0x27 => {
-
+                self.daa();
             }
+            // JR Z, r8
             0x28 => {
-                
+                let condition = self.get_flag(CpuFlags::Z);
+                cycles += self.jump_relative(condition);
             }
+            // ADD HL, HL
             0x29 => {
-
+                let value = self.registers.hl();
+                self.add_hl(value);
             }
+            // LD A, (HL+)
             0x2A => {
-
+                let address = self.registers.hl();
+                let value = self.mmu.read_byte(address);
+                self.registers.set_a(value);
+                self.registers.set_hl(address.wrapping_add(1));
             }
+            // DEC HL
             0x2B => {
-
+                let value = self.registers.hl();
+                self.registers.set_hl(value.wrapping_sub(1));
             }
+            // INC L
             0x2C => {
-                
+                let l = self.registers.l();
+                let result = self.inc8(l);
+                self.registers.set_l(result);
             }
+            // DEC L
             0x2D => {
-
+                let l = self.registers.l();
+                let result = self.dec8(l);
+                self.registers.set_l(result);
             }
+            // LD L, d8
             0x2E => {
-
+                let data = self.fetch_byte();
+                self.registers.set_l(data);
             }
+            // CPL
             0x2F => {
-
+                let a = self.registers.a();
+                self.registers.set_a(!a);
+                self.toggle_flag(CpuFlags::N);
+                self.toggle_flag(CpuFlags::H);
             }
+            // JR NC, r8
             0x30 => {
-
+                let condition = !self.get_flag(CpuFlags::C);
+                cycles += self.jump_relative(condition);
             }
+            // LD SP, d16
             0x31 => {
-
+                let data = self.fetch_word();
+                self.registers.sp.write(data);
             }
+            // LD (HL-), A
             0x32 => {
-
+                let address = self.registers.hl();
+                let a = self.registers.a();
+                self.mmu.write_byte(address, a);
+                self.registers.set_hl(address.wrapping_sub(1));
             }
+            // INC SP
             0x33 => {
-                
+                let value = self.registers.sp.read();
+                self.registers.sp.write(value.wrapping_add(1));
             }
+            // INC (HL)
             0x34 => {
-
+                let value = self.read_hl();
+                let result = self.inc8(value);
+                self.write_hl(result);
             }
+            // DEC (HL)
             0x35 => {
-
+                let value = self.read_hl();
+                let result = self.dec8(value);
+                self.write_hl(result);
             }
+            // LD (HL), d8
             0x36 => {
-
+                let data = self.fetch_byte();
+                self.write_hl(data);
             }
+            // SCF
             0x37 => {
-
+                self.untoggle_flag(CpuFlags::N);
+                self.untoggle_flag(CpuFlags::H);
+                self.toggle_flag(CpuFlags::C);
             }
+            // JR C, r8
             0x38 => {
-                
+                let condition = self.get_flag(CpuFlags::C);
+                cycles += self.jump_relative(condition);
             }
+            // ADD HL, SP
             0x39 => {
-
+                let value = self.registers.sp.read();
+                self.add_hl(value);
             }
+            // LD A, (HL-)
             0x3A => {
-
+                let address = self.registers.hl();
+                let value = self.mmu.read_byte(address);
+                self.registers.set_a(value);
+                self.registers.set_hl(address.wrapping_sub(1));
             }
+            // DEC SP
             0x3B => {
-
+                let value = self.registers.sp.read();
+                self.registers.sp.write(value.wrapping_sub(1));
             }
+            // INC A
             0x3C => {
-                
+                let a = self.registers.a();
+                let result = self.inc8(a);
+                self.registers.set_a(result);
             }
+            // DEC A
             0x3D => {
-
+                let a = self.registers.a();
+                let result = self.dec8(a);
+                self.registers.set_a(result);
             }
+            // LD A, d8
             0x3E => {
-
+                let data = self.fetch_byte();
+                self.registers.set_a(data);
             }
+            // CCF
             0x3F => {
-
-            }
-            0x40 => {
-
+                let carry = self.get_flag(CpuFlags::C);
+                self.untoggle_flag(CpuFlags::N);
+                self.untoggle_flag(CpuFlags::H);
+                self.set_flag(CpuFlags::C, !carry);
             }
+            // LD B, B
+            0x40 => {}
+            // LD B, C
             0x41 => {
-
+                let c = self.registers.c();
+                self.registers.set_b(c);
             }
+            // LD B, D
             0x42 => {
-
+                let d = self.registers.d();
+                self.registers.set_b(d);
             }
+            // LD B, E
             0x43 => {
-                
+                let e = self.registers.e();
+                self.registers.set_b(e);
             }
+            // LD B, H
             0x44 => {
-
+                let h = self.registers.h();
+                self.registers.set_b(h);
             }
+            // LD B, L
             0x45 => {
-
+                let l = self.registers.l();
+                self.registers.set_b(l);
             }
+            // LD B, (HL)
             0x46 => {
-
+                let value = self.read_hl();
+                self.registers.set_b(value);
             }
+            // LD B, A
             0x47 => {
-
+                let a = self.registers.a();
+                self.registers.set_b(a);
             }
+            // LD C, B
             0x48 => {
-                
-            }
-            0x49 => {
-
+                let b = self.registers.b();
+                self.registers.set_c(b);
             }
+            // LD C, C
+            0x49 => {}
+            // LD C, D
             0x4A => {
-
+                let d = self.registers.d();
+                self.registers.set_c(d);
             }
+            // LD C, E
             0x4B => {
-
+                let e = self.registers.e();
+                self.registers.set_c(e);
             }
+            // LD C, H
             0x4C => {
-                
+                let h = self.registers.h();
+                self.registers.set_c(h);
             }
+            // LD C, L
             0x4D => {
-
+                let l = self.registers.l();
+                self.registers.set_c(l);
             }
+            // LD C, (HL)
             0x4E => {
-
+                let value = self.read_hl();
+                self.registers.set_c(value);
             }
+            // LD C, A
             0x4F => {
-
+                let a = self.registers.a();
+                self.registers.set_c(a);
             }
-            0x50 => {}
-            0x51 => {
-
+            // LD D, B
+            0x50 => {
+                let b = self.registers.b();
+                self.registers.set_d(b);
             }
-            0x52 => {
-
+            // LD D, C
+            0x51 => {
+                let c = self.registers.c();
+                self.registers.set_d(c);
             }
+            // LD D, D
+            0x52 => {}
+            // LD D, E
             0x53 => {
-                
+                let e = self.registers.e();
+                self.registers.set_d(e);
             }
+            // LD D, H
             0x54 => {
-
+                let h = self.registers.h();
+                self.registers.set_d(h);
             }
+            // LD D, L
             0x55 => {
-
+                let l = self.registers.l();
+                self.registers.set_d(l);
             }
+            // LD D, (HL)
             0x56 => {
-
+                let value = self.read_hl();
+                self.registers.set_d(value);
             }
+            // LD D, A
             0x57 => {
-
+                let a = self.registers.a();
+                self.registers.set_d(a);
             }
+            // LD E, B
             0x58 => {
-                
+                let b = self.registers.b();
+                self.registers.set_e(b);
             }
+            // LD E, C
             0x59 => {
-
+                let c = self.registers.c();
+                self.registers.set_e(c);
             }
+            // LD E, D
             0x5A => {
-
-            }
-            0x5B => {
-
+                let d = self.registers.d();
+                self.registers.set_e(d);
             }
+            // LD E, E
+            0x5B => {}
+            // LD E, H
             0x5C => {
-                
+                let h = self.registers.h();
+                self.registers.set_e(h);
             }
+            // LD E, L
             0x5D => {
-
+                let l = self.registers.l();
+                self.registers.set_e(l);
             }
+            // LD E, (HL)
             0x5E => {
-
+                let value = self.read_hl();
+                self.registers.set_e(value);
             }
+            // LD E, A
             0x5F => {
-
+                let a = self.registers.a();
+                self.registers.set_e(a);
             }
+            // LD H, B
             0x60 => {
-
+                let b = self.registers.b();
+                self.registers.set_h(b);
             }
+            // LD H, C
             0x61 => {
-
+                let c = self.registers.c();
+                self.registers.set_h(c);
             }
+            // LD H, D
             0x62 => {
-
+                let d = self.registers.d();
+                self.registers.set_h(d);
             }
+            // LD H, E
             0x63 => {
-                
-            }
-            0x64 => {
-
+                let e = self.registers.e();
+                self.registers.set_h(e);
             }
+            // LD H, H
+            0x64 => {}
+            // LD H, L
             0x65 => {
-
+                let l = self.registers.l();
+                self.registers.set_h(l);
             }
+            // LD H, (HL)
             0x66 => {
-
+                let value = self.read_hl();
+                self.registers.set_h(value);
             }
+            // LD H, A
             0x67 => {
-
+                let a = self.registers.a();
+                self.registers.set_h(a);
             }
+            // LD L, B
             0x68 => {
-                
+                let b = self.registers.b();
+                self.registers.set_l(b);
             }
+            // LD L, C
             0x69 => {
-
+                let c = self.registers.c();
+                self.registers.set_l(c);
             }
+            // LD L, D
             0x6A => {
-
+                let d = self.registers.d();
+                self.registers.set_l(d);
             }
+            // LD L, E
             0x6B => {
-
+                let e = self.registers.e();
+                self.registers.set_l(e);
             }
+            // LD L, H
             0x6C => {
-                
-            }
-            0x6D => {
-
+                let h = self.registers.h();
+                self.registers.set_l(h);
             }
+            // LD L, L
+            0x6D => {}
+            // LD L, (HL)
             0x6E => {
-
+                let value = self.read_hl();
+                self.registers.set_l(value);
             }
+            // LD L, A
             0x6F => {
-
+                let a = self.registers.a();
+                self.registers.set_l(a);
             }
-            0x70 => {}
+            // LD (HL), B
+            0x70 => {
+                let b = self.registers.b();
+                self.write_hl(b);
+            }
+            // LD (HL), C
             0x71 => {
-
+                let c = self.registers.c();
+                self.write_hl(c);
             }
+            // LD (HL), D
             0x72 => {
-
+                let d = self.registers.d();
+                self.write_hl(d);
             }
+            // LD (HL), E
             0x73 => {
-                
+                let e = self.registers.e();
+                self.write_hl(e);
             }
+            // LD (HL), H
             0x74 => {
-
+                let h = self.registers.h();
+                self.write_hl(h);
             }
+            // LD (HL), L
             0x75 => {
-
+                let l = self.registers.l();
+                self.write_hl(l);
             }
+            // HALT
             0x76 => {
-
+                // With IME clear and an interrupt already pending, HALT does not
+                // halt and triggers the HALT bug instead
+                if !self.ime && self.mmu.interrupts.pending() != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
             }
+            // LD (HL), A
             0x77 => {
-
+                let a = self.registers.a();
+                self.write_hl(a);
             }
+            // LD A, B
             0x78 => {
-                
+                let b = self.registers.b();
+                self.registers.set_a(b);
             }
+            // LD A, C
             0x79 => {
-
+                let c = self.registers.c();
+                self.registers.set_a(c);
             }
+            // LD A, D
             0x7A => {
-
+                let d = self.registers.d();
+                self.registers.set_a(d);
             }
+            // LD A, E
             0x7B => {
-
+                let e = self.registers.e();
+                self.registers.set_a(e);
             }
+            // LD A, H
             0x7C => {
-                
+                let h = self.registers.h();
+                self.registers.set_a(h);
             }
+            // LD A, L
             0x7D => {
-
+                let l = self.registers.l();
+                self.registers.set_a(l);
             }
+            // LD A, (HL)
             0x7E => {
-
-            }
-            0x7F => {
-
+                let value = self.read_hl();
+                self.registers.set_a(value);
             }
+            // LD A, A
+            0x7F => {}
             // ADD A, B
             0x80 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.bc.read_lo();
-
-                if !check_carry(a, b) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, b) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                self.registers.af.write_lo(b + a);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let b = self.registers.b();
+                self.alu_add(b, false);
             }
             // ADD A, C
             0x81 => {
-                let a = self.registers.af.read_lo();
-                let c = self.registers.bc.read_hi();
-
-                if !check_carry(a, c) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, c) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                self.registers.af.write_lo(c + a);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let c = self.registers.c();
+                self.alu_add(c, false);
             }
             // ADD A, D
             0x82 => {
-                let a = self.registers.af.read_lo();
-                let d = self.registers.de.read_lo();
-
-                if !check_carry(a, d) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, d) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                self.registers.af.write_lo(d + a);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let d = self.registers.d();
+                self.alu_add(d, false);
             }
             // ADD A, E
             0x83 => {
-                let a = self.registers.af.read_lo();
-                let e = self.registers.de.read_hi();
-
-                if !check_carry(a, e) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, e) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                self.registers.af.write_lo(e + a);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let e = self.registers.e();
+                self.alu_add(e, false);
             }
             // ADD A, H
             0x84 => {
-                let a = self.registers.af.read_lo();
-                let h = self.registers.hl.read_lo();
-
-                if !check_carry(a, h) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, h) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                self.registers.af.write_lo(h + a);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let h = self.registers.h();
+                self.alu_add(h, false);
             }
             // ADD A, L
             0x85 => {
-                let a = self.registers.af.read_lo();
-                let l = self.registers.hl.read_lo();
-
-                if !check_carry(a, l) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, l) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                self.registers.af.write_lo(l + a);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let l = self.registers.l();
+                self.alu_add(l, false);
             }
             // ADD A, (HL)
             0x86 => {
-                let f = self.registers.af.read_lo();
-
-                let address = self.registers.hl.read() as Address; 
-                let s = self.mmu.read_byte(address);
-
-                if !check_carry(f, s) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(f, s) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                self.registers.af.write_lo(f + s);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let value = self.read_hl();
+                self.alu_add(value, false);
             }
             // ADD A, A
             0x87 => {
-                let a = self.registers.af.read_lo();
-                let a2 = self.registers.af.read_lo();
-
-                if !check_carry(a, a2) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, a2) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                self.registers.af.write_lo(a2 + a);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let a = self.registers.a();
+                self.alu_add(a, false);
             }
             // ADC A, B
             0x88 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.bc.read_lo();
-
-                if !check_carry(a, b) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, b) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                let c = (self.get_flag(CpuFlags::C) != 0) as i8;                
-                self.registers.af.write_lo(a + b + c);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let b = self.registers.b();
+                self.alu_add(b, true);
             }
             // ADC A, C
             0x89 => {
-                let a = self.registers.af.read_lo();
-                let c = self.registers.bc.read_hi();
-
-                if !check_carry(a, c) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, c) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                let carry = (self.get_flag(CpuFlags::C) != 0) as i8;
-                self.registers.af.write_lo(a + c + carry);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let c = self.registers.c();
+                self.alu_add(c, true);
             }
             // ADC A, D
             0x8A => {
-                let a = self.registers.af.read_lo();
-                let d = self.registers.de.read_lo();
-
-                if !check_carry(a, d) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, d) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                let c = (self.get_flag(CpuFlags::C) != 0) as i8;
-                self.registers.af.write_lo(a + d + c);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let d = self.registers.d();
+                self.alu_add(d, true);
             }
             // ADC A, E
             0x8B => {
-                let a = self.registers.af.read_lo();
-                let e = self.registers.de.read_hi();
-
-                if !check_carry(a, e) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, e) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                let c = (self.get_flag(CpuFlags::C) != 0) as i8;
-                self.registers.af.write_lo(a + e + c);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let e = self.registers.e();
+                self.alu_add(e, true);
             }
             // ADC A, H
             0x8C => {
-                let a = self.registers.af.read_lo();
-                let h = self.registers.hl.read_lo();
-
-                if !check_carry(a, h) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, h) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                let c = (self.get_flag(CpuFlags::C) != 0) as i8;
-                self.registers.af.write_lo(a + h + c);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let h = self.registers.h();
+                self.alu_add(h, true);
             }
             // ADC A, L
             0x8D => {
-                let a = self.registers.af.read_lo();
-                let l = self.registers.hl.read_hi();
-
-                if !check_carry(a, l) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, l) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                let c = (self.get_flag(CpuFlags::C) != 0) as i8;
-                self.registers.af.write_lo(a + l + c);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let l = self.registers.l();
+                self.alu_add(l, true);
             }
             // ADC A, (HL)
             0x8E => {
-                let f = self.registers.af.read_lo();
-
-                let address = self.registers.hl.read() as Address;
-                let s = self.mmu.read_byte(address);
-
-                if !check_carry(f, s) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(f, s) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                let c = (self.get_flag(CpuFlags::C) != 0) as i8;
-                self.registers.af.write_lo(f + s + c);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let value = self.read_hl();
+                self.alu_add(value, true);
             }
             // ADC A, A
             0x8F => {
-                let a = self.registers.af.read_lo();
-                let a2 = self.registers.af.read_lo();
-
-                if !check_carry(a, a2) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, a2) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.untoggle_flag(CpuFlags::N);
-
-                let c = (self.get_flag(CpuFlags::C) != 0) as i8;
-                self.registers.af.write_lo(a + a2 + c);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let a = self.registers.a();
+                self.alu_add(a, true);
             }
             // SUB B
             0x90 => {
-                let b = self.registers.bc.read_lo();
-
-                if !check_carry(b, -1) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(b, -1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                self.registers.bc.write_lo(b - 1);
-
-                if self.registers.bc.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let b = self.registers.b();
+                self.alu_sub(b, false);
             }
             // SUB C
             0x91 => {
-                let c = self.registers.bc.read_hi();
-
-                if !check_carry(c, -1) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(c, -1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                self.registers.bc.write_hi(c - 1);
-
-                if self.registers.bc.read_hi() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let c = self.registers.c();
+                self.alu_sub(c, false);
             }
             // SUB D
             0x92 => {
-                let d = self.registers.de.read_lo();
-
-                if !check_carry(d, -1) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(d, -1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                self.registers.de.write_lo(d - 1);
-
-                if self.registers.de.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let d = self.registers.d();
+                self.alu_sub(d, false);
             }
             // SUB E
             0x93 => {
-                let e = self.registers.de.read_hi();
-
-                if !check_carry(e, -1) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(e, -1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                self.registers.de.write_hi(e - 1);
-
-                if self.registers.de.read_hi() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let e = self.registers.e();
+                self.alu_sub(e, false);
             }
             // SUB H
             0x94 => {
-                let h = self.registers.hl.read_lo();
-
-                if !check_carry(h, -1) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(h, -1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                self.registers.hl.write_lo(h - 1);
-
-                if self.registers.hl.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let h = self.registers.h();
+                self.alu_sub(h, false);
             }
             // SUB L
             0x95 => {
-                let l = self.registers.hl.read_hi();
-
-                if !check_carry(l, -1) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(l, -1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                self.registers.hl.write_hi(l - 1);
-
-                if self.registers.hl.read_hi() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let l = self.registers.l();
+                self.alu_sub(l, false);
             }
             // SUB (HL)
             0x96 => {
-                let address = self.registers.hl.read() as Address;
-                let l = self.mmu.read_word(address);
-
-                if !check_carry(l, -1) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(l, -1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                self.mmu.write_word(address, l - 1);
-
-                if l - 1 == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let value = self.read_hl();
+                self.alu_sub(value, false);
             }
             // SUB A
             0x97 => {
-                let a = self.registers.af.read_lo();
-
-                if !check_carry(a, -1) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, -1) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                self.registers.af.write_lo(a - 1);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let a = self.registers.a();
+                self.alu_sub(a, false);
             }
             // SBC A, B
             0x98 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.bc.read_lo();
-
-                if !check_carry(a, b) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, b) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                let has_carry = (self.get_flag(CpuFlags::C) != 0) as i8;
-
-                self.registers.af.write_lo(a - b + has_carry);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let b = self.registers.b();
+                self.alu_sub(b, true);
             }
             // SBC A, C
             0x99 => {
-                let a = self.registers.af.read_lo();
-                let c = self.registers.bc.read_hi();
-
-                if !check_carry(a, c) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, c) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                let has_carry = (self.get_flag(CpuFlags::C) != 0) as i8;
-
-                self.registers.af.write_lo(a - c + has_carry);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let c = self.registers.c();
+                self.alu_sub(c, true);
             }
             // SBC A, D
             0x9A => {
-                let a = self.registers.af.read_lo();
-                let d = self.registers.de.read_lo();
-
-                if !check_carry(a, d) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, d) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                let has_carry = (self.get_flag(CpuFlags::C) != 0) as i8;
-
-                self.registers.af.write_lo(a - d + has_carry);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let d = self.registers.d();
+                self.alu_sub(d, true);
             }
             // SBC A, E
             0x9B => {
-                let a = self.registers.af.read_lo();
-                let e = self.registers.de.read_hi();
-
-                if !check_carry(a, e) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, e) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                let has_carry = (self.get_flag(CpuFlags::C) != 0) as i8;
-
-                self.registers.af.write_lo(a - e + has_carry);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let e = self.registers.e();
+                self.alu_sub(e, true);
             }
             // SBC A, H
             0x9C => {
-                let a = self.registers.af.read_lo();
-                let h = self.registers.hl.read_lo();
-
-                if !check_carry(a, h) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, h) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                let has_carry = (self.get_flag(CpuFlags::C) != 0) as i8;
-
-                self.registers.af.write_lo(a - h + has_carry);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let h = self.registers.h();
+                self.alu_sub(h, true);
             }
             // SBC A, L
             0x9D => {
-                let a = self.registers.af.read_lo();
-                let l = self.registers.hl.read_hi();
-
-                if !check_carry(a, l) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, l) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                let has_carry = (self.get_flag(CpuFlags::C) != 0) as i8;
-
-                self.registers.af.write_lo(a - l + has_carry);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let l = self.registers.l();
+                self.alu_sub(l, true);
             }
             // SBC A, (HL)
             0x9E => {
-                let a = self.registers.af.read_lo();
-
-                let hl = self.registers.hl.read();
-                let data = self.mmu.read_byte(hl as Address);
-
-                if !check_carry(a, data) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, data) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                let has_carry = (self.get_flag(CpuFlags::C) != 0) as i8;
-
-                self.registers.af.write_lo(a - data + has_carry);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let value = self.read_hl();
+                self.alu_sub(value, true);
             }
             // SBC A, A
             0x9F => {
-                let a = self.registers.af.read_lo();
-
-                if !check_carry(a, a) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if !check_half_carry(a, a) {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
-
-                let has_carry = (self.get_flag(CpuFlags::C) != 0) as i8;
-
-                self.registers.af.write_lo(a - a + has_carry);
-
-                if self.registers.af.read_lo() == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
+                let a = self.registers.a();
+                self.alu_sub(a, true);
             }
             // AND B
             0xA0 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.bc.read_lo();
-
-                self.untoggle_flag(CpuFlags::N);
-                self.toggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::C);
-
-                if a & b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a & b);
+                let b = self.registers.b();
+                self.alu_and(b);
             }
             // AND C
             0xA1 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.bc.read_hi();
-
-                self.untoggle_flag(CpuFlags::N);
-                self.toggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::C);
-
-                if a & b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a & b);
+                let c = self.registers.c();
+                self.alu_and(c);
             }
             // AND D
             0xA2 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.de.read_lo();
-
-                self.untoggle_flag(CpuFlags::N);
-                self.toggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::C);
-
-                if a & b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a & b);
+                let d = self.registers.d();
+                self.alu_and(d);
             }
             // AND E
             0xA3 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.de.read_hi();
-
-                self.untoggle_flag(CpuFlags::N);
-                self.toggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::C);
-
-                if a & b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a & b);
+                let e = self.registers.e();
+                self.alu_and(e);
             }
             // AND H
             0xA4 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.hl.read_lo();
-
-                self.untoggle_flag(CpuFlags::N);
-                self.toggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::C);
-
-                if a & b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a & b);
+                let h = self.registers.h();
+                self.alu_and(h);
             }
             // AND L
             0xA5 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.hl.read_hi();
-
-                self.untoggle_flag(CpuFlags::N);
-                self.toggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::C);
-
-                if a & b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a & b);
+                let l = self.registers.l();
+                self.alu_and(l);
             }
             // AND (HL)
             0xA6 => {
-                let a = self.registers.af.read_lo();
-                let address = self.registers.hl.read() as Address;
-                let b = self.mmu.read_byte(address);
-
-                self.untoggle_flag(CpuFlags::N);
-                self.toggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::C);
-
-                if a & b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a & b);
+                let value = self.read_hl();
+                self.alu_and(value);
             }
             // AND A
             0xA7 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.af.read_lo();
-
-                self.untoggle_flag(CpuFlags::N);
-                self.toggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::C);
-
-                if a & b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a & b);
+                let a = self.registers.a();
+                self.alu_and(a);
             }
             // XOR B
             0xA8 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.bc.read_lo();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a ^ b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a ^ b);
+                let b = self.registers.b();
+                self.alu_xor(b);
             }
             // XOR C
             0xA9 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.bc.read_hi();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a ^ b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a ^ b);
+                let c = self.registers.c();
+                self.alu_xor(c);
             }
             // XOR D
             0xAA => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.de.read_lo();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a ^ b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a ^ b);
+                let d = self.registers.d();
+                self.alu_xor(d);
             }
             // XOR E
-            0xAB => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.de.read_hi();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a ^ b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a ^ b);
+            0xAB => {
+                let e = self.registers.e();
+                self.alu_xor(e);
             }
             // XOR H
             0xAC => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.hl.read_lo();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a ^ b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a ^ b);
+                let h = self.registers.h();
+                self.alu_xor(h);
             }
             // XOR L
             0xAD => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.hl.read_hi();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a ^ b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a ^ b);
+                let l = self.registers.l();
+                self.alu_xor(l);
             }
             // XOR (HL)
             0xAE => {
-                let a = self.registers.af.read_lo();
-                let address = self.registers.hl.read();
-                let b = self.mmu.read_byte(address as Address);
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a ^ b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a ^ b);
+                let value = self.read_hl();
+                self.alu_xor(value);
             }
             // XOR A
             0xAF => {
-                let a = self.registers.af.read_lo();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a ^ a == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a ^ a);
+                let a = self.registers.a();
+                self.alu_xor(a);
             }
             // OR B
             0xB0 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.bc.read_lo();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a | b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a | b);
+                let b = self.registers.b();
+                self.alu_or(b);
             }
             // OR C
             0xB1 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.bc.read_hi();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a | b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a | b);
+                let c = self.registers.c();
+                self.alu_or(c);
             }
             // OR D
             0xB2 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.de.read_lo();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a | b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a | b);
+                let d = self.registers.d();
+                self.alu_or(d);
             }
             // OR E
             0xB3 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.de.read_hi();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a | b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a | b);
+                let e = self.registers.e();
+                self.alu_or(e);
             }
             // OR H
             0xB4 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.hl.read_lo();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a | b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a | b);
+                let h = self.registers.h();
+                self.alu_or(h);
             }
             // OR L
             0xB5 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.hl.read_hi();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a | b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a | b);
+                let l = self.registers.l();
+                self.alu_or(l);
             }
             // OR (HL)
             0xB6 => {
-                let a = self.registers.af.read_lo();
-                let address = self.registers.hl.read() as Address;
-                let b = self.mmu.read_byte(address);
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a | b == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a | b);
+                let value = self.read_hl();
+                self.alu_or(value);
             }
             // OR A
             0xB7 => {
-                let a = self.registers.af.read_lo();
-                
-                self.untoggle_flag(CpuFlags::C);
-                self.untoggle_flag(CpuFlags::H);
-                self.untoggle_flag(CpuFlags::N);
-
-                if a | a == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                self.registers.af.write_lo(a | a);
+                let a = self.registers.a();
+                self.alu_or(a);
             }
             // CP B
             0xB8 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.bc.read_lo();
-
-                let result = a - b;
-
-                if result == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                if !check_carry(a, -b) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if a < b {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
+                let b = self.registers.b();
+                self.alu_cp(b);
             }
             // CP C
             0xB9 => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.bc.read_hi();
-
-                let result = a - b;
-
-                if result == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                if !check_carry(a, -b) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if a < b {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
+                let c = self.registers.c();
+                self.alu_cp(c);
             }
             // CP D
             0xBA => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.de.read_lo();
-
-                let result = a - b;
-
-                if result == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                if !check_carry(a, -b) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if a < b {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
+                let d = self.registers.d();
+                self.alu_cp(d);
             }
             // CP E
             0xBB => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.de.read_hi();
-
-                let result = a - b;
-
-                if result == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                if !check_carry(a, -b) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if a < b {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
+                let e = self.registers.e();
+                self.alu_cp(e);
             }
             // CP H
             0xBC => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.hl.read_lo();
-
-                let result = a - b;
-
-                if result == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                if !check_carry(a, -b) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if a < b {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
+                let h = self.registers.h();
+                self.alu_cp(h);
             }
             // CP L
             0xBD => {
-                let a = self.registers.af.read_lo();
-                let b = self.registers.hl.read_hi();
-
-                let result = a - b;
-
-                if result == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                if !check_carry(a, -b) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if a < b {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
+                let l = self.registers.l();
+                self.alu_cp(l);
             }
             // CP (HL)
             0xBE => {
-                let a = self.registers.af.read_lo();
-                let address = self.registers.hl.read() as Address;
-                let b = self.mmu.read_byte(address);
-
-                let result = a - b;
-
-                if result == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                if !check_carry(a, -b) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if a < b {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
+                let value = self.read_hl();
+                self.alu_cp(value);
             }
             // CP A
             0xBF => {
-                let a = self.registers.af.read_lo();
-
-                let result = a - a;
-
-                if result == 0 {
-                    self.toggle_flag(CpuFlags::Z);
-                }
-
-                if !check_carry(a, -a) {
-                    self.toggle_flag(CpuFlags::C);
-                }
-
-                if a < a {
-                    self.toggle_flag(CpuFlags::H);
-                }
-
-                self.toggle_flag(CpuFlags::N);
+                let a = self.registers.a();
+                self.alu_cp(a);
             }
             // RET NZ
             0xC0 => {
+                let condition = !self.get_flag(CpuFlags::Z);
+                cycles += self.ret(condition);
             }
+            // POP BC
             0xC1 => {
-
+                let value = self.pop_word();
+                self.registers.set_bc(value);
             }
+            // JP NZ, a16
             0xC2 => {
-
+                let condition = !self.get_flag(CpuFlags::Z);
+                cycles += self.jump(condition);
             }
+            // JP a16
             0xC3 => {
-                
+                self.jump(true);
             }
+            // CALL NZ, a16
             0xC4 => {
-
+                let condition = !self.get_flag(CpuFlags::Z);
+                cycles += self.call(condition);
             }
+            // PUSH BC
             0xC5 => {
-
+                let value = self.registers.bc();
+                self.push_word(value);
             }
+            // ADD A, d8
             0xC6 => {
-
+                let data = self.fetch_byte();
+                self.alu_add(data, false);
             }
+            // RST 00H
             0xC7 => {
-
+                self.restart(0x00);
             }
+            // RET Z
             0xC8 => {
-                
+                let condition = self.get_flag(CpuFlags::Z);
+                cycles += self.ret(condition);
             }
+            // RET
             0xC9 => {
-
+                self.ret(true);
             }
+            // JP Z, a16
             0xCA => {
-
+                let condition = self.get_flag(CpuFlags::Z);
+                cycles += self.jump(condition);
             }
+            // PREFIX CB
             0xCB => {
-
+                let cb_code = self.fetch_byte();
+                cycles = self.cbexec(cb_code);
             }
+            // CALL Z, a16
             0xCC => {
-                
+                let condition = self.get_flag(CpuFlags::Z);
+                cycles += self.call(condition);
             }
+            // CALL a16
             0xCD => {
-
+                self.call(true);
             }
+            // ADC A, d8
             0xCE => {
-
+                let data = self.fetch_byte();
+                self.alu_add(data, true);
             }
+            // RST 08H
             0xCF => {
-
+                self.restart(0x08);
             }
+            // RET NC
             0xD0 => {
-
+                let condition = !self.get_flag(CpuFlags::C);
+                cycles += self.ret(condition);
             }
+            // POP DE
             0xD1 => {
-
+                let value = self.pop_word();
+                self.registers.set_de(value);
             }
+            // JP NC, a16
             0xD2 => {
-
+                let condition = !self.get_flag(CpuFlags::C);
+                cycles += self.jump(condition);
             }
+            // Illegal opcode, hangs the CPU
             0xD3 => {
-                
+                self.locked = true;
             }
+            // CALL NC, a16
             0xD4 => {
-
+                let condition = !self.get_flag(CpuFlags::C);
+                cycles += self.call(condition);
             }
+            // PUSH DE
             0xD5 => {
-
+                let value = self.registers.de();
+                self.push_word(value);
             }
+            // SUB d8
             0xD6 => {
-
+                let data = self.fetch_byte();
+                self.alu_sub(data, false);
             }
+            // RST 10H
             0xD7 => {
-
+                self.restart(0x10);
             }
+            // RET C
             0xD8 => {
-                
+                let condition = self.get_flag(CpuFlags::C);
+                cycles += self.ret(condition);
             }
+            // RETI
             0xD9 => {
-
+                self.ret(true);
+                self.ime = true;
             }
+            // JP C, a16
             0xDA => {
-
+                let condition = self.get_flag(CpuFlags::C);
+                cycles += self.jump(condition);
             }
+            // Illegal opcode, hangs the CPU
             0xDB => {
-
+                self.locked = true;
             }
+            // CALL C, a16
             0xDC => {
-                
+                let condition = self.get_flag(CpuFlags::C);
+                cycles += self.call(condition);
             }
+            // Illegal opcode, hangs the CPU
             0xDD => {
-
+                self.locked = true;
             }
+            // SBC A, d8
             0xDE => {
-
+                let data = self.fetch_byte();
+                self.alu_sub(data, true);
             }
+            // RST 18H
             0xDF => {
-
+                self.restart(0x18);
             }
-            0xE0 => {}
+            // LDH (a8), A
+            0xE0 => {
+                let offset = self.fetch_byte();
+                let a = self.registers.a();
+                self.mmu.write_byte(0xFF00 + offset as Address, a);
+            }
+            // POP HL
             0xE1 => {
-
+                let value = self.pop_word();
+                self.registers.set_hl(value);
             }
+            // LD (C), A
             0xE2 => {
-
+                let c = self.registers.c();
+                let a = self.registers.a();
+                self.mmu.write_byte(0xFF00 + c as Address, a);
             }
+            // Illegal opcode, hangs the CPU
             0xE3 => {
-                
+                self.locked = true;
             }
+            // Illegal opcode, hangs the CPU
             0xE4 => {
-
+                self.locked = true;
             }
+            // PUSH HL
             0xE5 => {
-
+                let value = self.registers.hl();
+                self.push_word(value);
             }
+            // AND d8
             0xE6 => {
-
+                let data = self.fetch_byte();
+                self.alu_and(data);
             }
+            // RST 20H
             0xE7 => {
-
+                self.restart(0x20);
             }
+            // ADD SP, r8
             0xE8 => {
-                
+                let result = self.add_sp();
+                self.registers.sp.write(result);
             }
+            // JP (HL)
             0xE9 => {
-
+                let address = self.registers.hl();
+                self.registers.pc.jmp(address);
             }
+            // LD (a16), A
             0xEA => {
-
+                let address = self.fetch_word() as Address;
+                let a = self.registers.a();
+                self.mmu.write_byte(address, a);
             }
+            // Illegal opcode, hangs the CPU
             0xEB => {
-
+                self.locked = true;
             }
+            // Illegal opcode, hangs the CPU
             0xEC => {
-                
+                self.locked = true;
             }
+            // Illegal opcode, hangs the CPU
             0xED => {
-
+                self.locked = true;
             }
+            // XOR d8
             0xEE => {
-
+                let data = self.fetch_byte();
+                self.alu_xor(data);
             }
+            // RST 28H
             0xEF => {
-
+                self.restart(0x28);
+            }
+            // LDH A, (a8)
+            0xF0 => {
+                let offset = self.fetch_byte();
+                let value = self.mmu.read_byte(0xFF00 + offset as Address);
+                self.registers.set_a(value);
             }
-            0xF0 => {}
+            // POP AF
             0xF1 => {
-
+                let value = self.pop_word();
+                self.registers.set_af(value);
             }
+            // LD A, (C)
             0xF2 => {
-
+                let c = self.registers.c();
+                let value = self.mmu.read_byte(0xFF00 + c as Address);
+                self.registers.set_a(value);
             }
+            // DI
             0xF3 => {
-                
+                self.ime = false;
+                self.ei_delay = 0;
             }
+            // Illegal opcode, hangs the CPU
             0xF4 => {
-
+                self.locked = true;
             }
+            // PUSH AF
             0xF5 => {
-
+                let value = self.registers.af();
+                self.push_word(value);
             }
+            // OR d8
             0xF6 => {
-
+                let data = self.fetch_byte();
+                self.alu_or(data);
             }
+            // RST 30H
             0xF7 => {
-
+                self.restart(0x30);
             }
+            // LD HL, SP + r8
             0xF8 => {
-                
+                let result = self.add_sp();
+                self.registers.set_hl(result);
             }
+            // LD SP, HL
             0xF9 => {
-
+                let hl = self.registers.hl();
+                self.registers.sp.write(hl);
             }
+            // LD A, (a16)
             0xFA => {
-
+                let address = self.fetch_word() as Address;
+                let value = self.mmu.read_byte(address);
+                self.registers.set_a(value);
             }
+            // EI
             0xFB => {
-
+                // IME is only set after the instruction following EI
+                self.ei_delay = 2;
             }
+            // Illegal opcode, hangs the CPU
             0xFC => {
-                
+                self.locked = true;
             }
+            // Illegal opcode, hangs the CPU
             0xFD => {
-
+                self.locked = true;
             }
+            // CP d8
             0xFE => {
-
+                let data = self.fetch_byte();
+                self.alu_cp(data);
             }
+            // RST 38H
             0xFF => {
-
-            }
-            _ => {
-                panic!("Invalid instruction");
+                self.restart(0x38);
             }
         }
+
+        cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ppu::Renderer;
+    use rom::Cartridge;
+
+    use super::{Cpu, CpuFlags, Interrupt, Mmu, OP_CYCLES};
+
+    // Where test programs are loaded, right after the cartridge header
+    const START : u16 = 0x0150;
+
+    // Machine with the program in ROM at START and PC pointing to it
+    fn cpu(program: &[u8]) -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        rom[START as usize..START as usize + program.len()].copy_from_slice(program);
+
+        let mut cpu = Cpu::new(Mmu::new(Cartridge::new(rom).unwrap(), Renderer::Scanline));
+        cpu.registers.pc.write(START);
+        cpu
+    }
+
+    #[test]
+    fn step_advances_pc_past_the_operands() {
+        // NOP; LD BC, 0x1234; LD A, 0x56
+        let mut cpu = cpu(&[0x00, 0x01, 0x34, 0x12, 0x3E, 0x56]);
+
+        assert_eq!(cpu.step(), 1);
+        assert_eq!(cpu.registers.pc.read(), START + 1);
+
+        assert_eq!(cpu.step(), 3);
+        assert_eq!(cpu.registers.pc.read(), START + 4);
+        assert_eq!(cpu.registers.bc(), 0x1234);
+
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.registers.pc.read(), START + 6);
+        assert_eq!(cpu.registers.a(), 0x56);
+    }
+
+    #[test]
+    fn step_follows_jumps() {
+        // JP 0x0200
+        let mut cpu = cpu(&[0xC3, 0x00, 0x02]);
+
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.registers.pc.read(), 0x0200);
+    }
+
+    #[test]
+    fn illegal_opcodes_cost_one_cycle() {
+        let illegal = [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD];
+
+        for &op_code in illegal.iter() {
+            assert_eq!(OP_CYCLES[op_code], 1, "opcode 0x{:02X}", op_code);
+        }
+
+        // Only the CB prefix is free, its second byte decides the cost
+        for (op_code, &cycles) in OP_CYCLES.iter().enumerate() {
+            assert_eq!(cycles == 0, op_code == 0xCB, "opcode 0x{:02X}", op_code);
+        }
+
+        let mut cpu = cpu(&[0xD3]);
+        assert_eq!(cpu.step(), 1);
+        assert!(cpu.is_locked());
+        assert_eq!(cpu.step(), 1);
+    }
+
+    #[test]
+    fn taken_branches_cost_more() {
+        // XOR A; JR NZ, +2; JR Z, +2
+        let mut cpu = cpu(&[0xAF, 0x20, 0x02, 0x28, 0x02]);
+
+        cpu.step();
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.registers.pc.read(), START + 3);
+
+        assert_eq!(cpu.step(), 3);
+        assert_eq!(cpu.registers.pc.read(), START + 7);
+    }
+
+    #[test]
+    fn call_and_ret_go_through_the_stack() {
+        // CALL 0x0160 ... at 0x0160: RET
+        let mut program = vec![0; 0x11];
+        program[..3].copy_from_slice(&[0xCD, 0x60, 0x01]);
+        program[0x10] = 0xC9;
+        let mut cpu = cpu(&program);
+
+        assert_eq!(cpu.step(), 6);
+        assert_eq!(cpu.registers.pc.read(), 0x0160);
+        assert_eq!(cpu.registers.sp.read(), 0xFFFC);
+        assert_eq!(cpu.mmu.read_word(0xFFFC), START + 3);
+
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.registers.pc.read(), START + 3);
+        assert_eq!(cpu.registers.sp.read(), 0xFFFE);
+    }
+
+    #[test]
+    fn add_and_sub_set_half_carry_and_carry() {
+        // LD A, 0x0F; ADD A, 0x01; LD A, 0xF0; ADD A, 0x10; SUB 0x01
+        let mut cpu = cpu(&[0x3E, 0x0F, 0xC6, 0x01, 0x3E, 0xF0, 0xC6, 0x10, 0xD6, 0x01]);
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.a(), 0x10);
+        assert_eq!(cpu.registers.f(), 0x20);
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.a(), 0x00);
+        assert_eq!(cpu.registers.f(), 0x90);
+
+        cpu.step();
+        assert_eq!(cpu.registers.a(), 0xFF);
+        assert_eq!(cpu.registers.f(), 0x70);
+    }
+
+    #[test]
+    fn compare_leaves_a_alone() {
+        // LD A, 0x42; CP 0x42; CP 0x43
+        let mut cpu = cpu(&[0x3E, 0x42, 0xFE, 0x42, 0xFE, 0x43]);
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.a(), 0x42);
+        assert!(cpu.get_flag(CpuFlags::Z));
+        assert!(cpu.get_flag(CpuFlags::N));
+
+        cpu.step();
+        assert!(!cpu.get_flag(CpuFlags::Z));
+        assert!(cpu.get_flag(CpuFlags::C));
+    }
+
+    #[test]
+    fn sp_offsets_take_flags_from_the_low_byte() {
+        // LD SP, 0x00FF; ADD SP, 1; LD HL, SP-1
+        let mut cpu = cpu(&[0x31, 0xFF, 0x00, 0xE8, 0x01, 0xF8, 0xFF]);
+
+        cpu.step();
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.registers.sp.read(), 0x0100);
+        assert_eq!(cpu.registers.f(), 0x30);
+
+        assert_eq!(cpu.step(), 3);
+        assert_eq!(cpu.registers.hl(), 0x00FF);
+        assert_eq!(cpu.registers.f(), 0x00);
+    }
+
+    #[test]
+    fn carry_flag_instructions() {
+        // SCF; CCF; CPL
+        let mut cpu = cpu(&[0x37, 0x3F, 0x2F]);
+        cpu.registers.set_a(0x5A);
+        cpu.registers.set_f(0x80);
+
+        cpu.step();
+        assert_eq!(cpu.registers.f(), 0x90);
+
+        cpu.step();
+        assert_eq!(cpu.registers.f(), 0x80);
+
+        cpu.step();
+        assert_eq!(cpu.registers.a(), 0xA5);
+        assert_eq!(cpu.registers.f(), 0xE0);
+    }
+
+    #[test]
+    fn cb_instructions_decode_operand_and_bit() {
+        // SWAP A; SRA B; BIT 7, B; SET 3, (HL); RES 0, (HL); BIT 3, (HL)
+        let mut cpu = cpu(&[0xCB, 0x37, 0xCB, 0x28, 0xCB, 0x78, 0xCB, 0xDE, 0xCB, 0x86, 0xCB, 0x5E]);
+        cpu.registers.set_a(0x12);
+        cpu.registers.set_b(0x81);
+        cpu.registers.set_hl(0xC000);
+        cpu.mmu.write_byte(0xC000, 0x01);
+
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.registers.a(), 0x21);
+
+        // The sign bit stays, bit 0 goes to the carry
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.registers.b(), 0xC0);
+        assert!(cpu.get_flag(CpuFlags::C));
+
+        assert_eq!(cpu.step(), 2);
+        assert!(!cpu.get_flag(CpuFlags::Z));
+        assert!(cpu.get_flag(CpuFlags::H));
+
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.mmu.read_byte(0xC000), 0x08);
+
+        assert_eq!(cpu.step(), 3);
+        assert!(!cpu.get_flag(CpuFlags::Z));
+    }
+
+    // Adds or subtracts two BCD numbers and adjusts the result with DAA
+    fn bcd(a: u8, b: u8, subtract: bool) -> (u8, u8) {
+        // LD A, a; ADD A, b or SUB b; DAA
+        let op_code = if subtract { 0xD6 } else { 0xC6 };
+        let mut cpu = cpu(&[0x3E, a, op_code, b, 0x27]);
+
+        cpu.step();
+        cpu.step();
+        cpu.step();
+
+        (cpu.registers.a(), cpu.registers.f())
+    }
+
+    #[test]
+    fn daa_adjusts_bcd_results() {
+        assert_eq!(bcd(0x15, 0x27, false), (0x42, 0x00));
+        assert_eq!(bcd(0x09, 0x01, false), (0x10, 0x00));
+        assert_eq!(bcd(0x99, 0x01, false), (0x00, 0x90));
+        assert_eq!(bcd(0x50, 0x70, false), (0x20, 0x10));
+
+        // N is kept, H is always cleared
+        assert_eq!(bcd(0x42, 0x15, true), (0x27, 0x40));
+        assert_eq!(bcd(0x10, 0x01, true), (0x09, 0x40));
+        assert_eq!(bcd(0x00, 0x01, true), (0x99, 0x50));
+        assert_eq!(bcd(0x15, 0x15, true), (0x00, 0xC0));
+    }
+
+    #[test]
+    fn low_nibble_of_f_is_always_zero() {
+        // LD BC, 0x12FF; PUSH BC; POP AF
+        let mut cpu = cpu(&[0x01, 0xFF, 0x12, 0xC5, 0xF1]);
+
+        cpu.step();
+        cpu.step();
+        cpu.step();
+
+        assert_eq!(cpu.registers.a(), 0x12);
+        assert_eq!(cpu.registers.f(), 0xF0);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        // EI; NOP; NOP
+        let mut cpu = cpu(&[0xFB, 0x00, 0x00]);
+        cpu.mmu.interrupts.write_enable(0x04);
+        cpu.mmu.interrupts.write_flags(0x00);
+        cpu.mmu.interrupts.request(Interrupt::Timer);
+
+        cpu.step();
+        assert_eq!(cpu.step(), 1);
+        assert_eq!(cpu.registers.pc.read(), START + 2);
+
+        assert_eq!(cpu.step(), 5);
+        assert_eq!(cpu.registers.pc.read(), 0x50);
+        assert_eq!(cpu.mmu.read_word(cpu.registers.sp.read()), START + 2);
+        assert_eq!(cpu.mmu.interrupts.read_flags() & 0x1F, 0);
+    }
+
+    #[test]
+    fn highest_priority_interrupt_goes_first() {
+        // EI; NOP
+        let mut cpu = cpu(&[0xFB, 0x00]);
+        cpu.mmu.interrupts.write_enable(0x1F);
+        cpu.mmu.interrupts.write_flags(0x00);
+        cpu.mmu.interrupts.request(Interrupt::Joypad);
+        cpu.mmu.interrupts.request(Interrupt::LcdStat);
+
+        cpu.step();
+        cpu.step();
+        cpu.step();
+
+        assert_eq!(cpu.registers.pc.read(), 0x48);
+        assert_eq!(cpu.mmu.interrupts.read_flags() & 0x1F, 0x10);
+    }
+
+    #[test]
+    fn halt_without_ime_resumes_after_an_interrupt() {
+        // HALT; LD A, 0x42
+        let mut cpu = cpu(&[0x76, 0x3E, 0x42]);
+        cpu.mmu.interrupts.write_enable(0x04);
+        cpu.mmu.interrupts.write_flags(0x00);
+
+        cpu.step();
+        assert!(cpu.is_halted());
+
+        assert_eq!(cpu.step(), 1);
+        assert_eq!(cpu.registers.pc.read(), START + 1);
+
+        cpu.mmu.interrupts.request(Interrupt::Timer);
+        cpu.step();
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.registers.a(), 0x42);
+        assert_eq!(cpu.registers.pc.read(), START + 3);
+    }
+
+    #[test]
+    fn halt_bug_reads_the_next_byte_twice() {
+        // HALT; INC A
+        let mut cpu = cpu(&[0x76, 0x3C]);
+        cpu.registers.set_a(0);
+        cpu.mmu.interrupts.write_enable(0x04);
+        cpu.mmu.interrupts.request(Interrupt::Timer);
+
+        cpu.step();
+        assert!(!cpu.is_halted());
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.a(), 2);
+        assert_eq!(cpu.registers.pc.read(), START + 2);
     }
 }
@@ -1,191 +1,204 @@
 pub struct SP {
-    val : i16
+    val : u16
 }
 
 impl SP<> {
-    pub fn pop(&mut self, size: i16) -> super::Address {
-        self.val += size;
-        self.val as super::Address
+    pub fn pop(&mut self, size: u16) -> super::Address {
+        self.val = self.val.wrapping_add(size);
+        self.val
     }
 
-    pub fn push(&mut self, size: i16) -> super::Address {
-        self.val -= size;
-        self.val as super::Address
+    pub fn push(&mut self, size: u16) -> super::Address {
+        self.val = self.val.wrapping_sub(size);
+        self.val
     }
 
-    pub fn read(&mut self) -> super::Address {
-        self.val as super::Address
+    pub fn write(&mut self, value: u16) {
+        self.val = value;
+    }
+
+    pub fn read(&self) -> super::Address {
+        self.val
     }
 }
 
 pub struct PC {
-    val : i16
+    val : u16
 }
 
 impl PC<> {
-    pub fn jr(&mut self, offset: i16) {
-        self.val += offset;
+    pub fn jr(&mut self, offset: i8) {
+        self.val = self.val.wrapping_add(offset as u16);
     }
 
     pub fn jmp(&mut self, address: super::Address) {
-        self.val = address as i16;
+        self.val = address;
     }
 
-    pub fn write(&mut self, value: i16) {
+    pub fn write(&mut self, value: u16) {
         self.val = value;
     }
 
-    pub fn read(&mut self) -> super::Address {
-        self.val as super::Address
+    pub fn read(&self) -> super::Address {
+        self.val
     }
 }
 
-pub struct AF {
-    lo : i8,
-    hi : i8
+// 8-bit registers and the 16-bit pairs they form. The first register of a
+// pair is its high byte. Only the upper nibble of F holds flags; the lower
+// one is hardwired to zero.
+pub struct File {
+    a : u8,
+    f : u8,
+    b : u8,
+    c : u8,
+    d : u8,
+    e : u8,
+    h : u8,
+    l : u8,
+    pub pc: PC,
+    pub sp: SP
 }
 
-impl AF<> {
-    pub fn write(&mut self, value: i16) -> &mut Self {
-        self.lo = (value & 0xFF) as i8;
-        self.hi = (value >> 0x8) as i8;
-        self
+impl File<> {
+    pub fn new() -> Self {
+        File {
+            a: 0,
+            f: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            pc: PC { val: 0 },
+            sp: SP { val: 0 }
+        }
     }
 
-    pub fn write_lo(&mut self, value: i8) -> &mut Self {
-        self.lo = value;
-        self
+    pub fn a(&self) -> u8 {
+        self.a
     }
 
-    pub fn write_hi(&mut self, value: i8) -> &mut Self {
-        self.hi = value;
-        self
+    pub fn set_a(&mut self, value: u8) {
+        self.a = value;
     }
 
-    pub fn read(&mut self) -> i16 {
-        (self.hi as i16).wrapping_shl(0x8) | (self.lo as i16)
+    pub fn f(&self) -> u8 {
+        self.f
     }
 
-    pub fn read_lo(&mut self) -> i8 {
-        self.lo
+    pub fn set_f(&mut self, value: u8) {
+        self.f = value & 0xF0;
     }
 
-    pub fn read_hi(&mut self) -> i8 {
-        self.hi
+    pub fn b(&self) -> u8 {
+        self.b
     }
-}
-
-pub struct BC {
-    lo : i8,
-    hi : i8
-}
 
-impl BC<> {
-    pub fn write(&mut self, value: i16) -> &mut Self {
-        self.lo = (value & 0xFF) as i8;
-        self.hi = (value >> 0x8) as i8;
-        self
+    pub fn set_b(&mut self, value: u8) {
+        self.b = value;
     }
 
-    pub fn write_lo(&mut self, value: i8) -> &mut Self {
-        self.lo = value;
-        self
+    pub fn c(&self) -> u8 {
+        self.c
     }
 
-    pub fn write_hi(&mut self, value: i8) -> &mut Self {
-        self.hi = value;
-        self
+    pub fn set_c(&mut self, value: u8) {
+        self.c = value;
     }
 
-    pub fn read(&mut self) -> i16 {
-        (self.hi as i16).wrapping_shl(0x8) | (self.lo as i16)
+    pub fn d(&self) -> u8 {
+        self.d
     }
 
-    pub fn read_lo(&mut self) -> i8 {
-        self.lo
+    pub fn set_d(&mut self, value: u8) {
+        self.d = value;
     }
 
-    pub fn read_hi(&mut self) -> i8 {
-        self.hi
+    pub fn e(&self) -> u8 {
+        self.e
     }
-}
-
-pub struct DE {
-    lo: i8,
-    hi: i8
-}
 
-impl DE<> {
-    pub fn write(&mut self, value: i16) -> &mut Self {
-        self.lo = (value & 0xFF) as i8;
-        self.hi = (value >> 0x8) as i8;
-        self
+    pub fn set_e(&mut self, value: u8) {
+        self.e = value;
     }
 
-    pub fn write_lo(&mut self, value: i8) -> &mut Self {
-        self.lo = value;
-        self
+    pub fn h(&self) -> u8 {
+        self.h
     }
 
-    pub fn write_hi(&mut self, value: i8) -> &mut Self {
-        self.hi = value;
-        self
+    pub fn set_h(&mut self, value: u8) {
+        self.h = value;
     }
 
-    pub fn read(&mut self) -> i16 {
-        (self.hi as i16).wrapping_shl(0x8) | (self.lo as i16)
+    pub fn l(&self) -> u8 {
+        self.l
     }
 
-    pub fn read_lo(&mut self) -> i8 {
-        self.lo
+    pub fn set_l(&mut self, value: u8) {
+        self.l = value;
     }
 
-    pub fn read_hi(&mut self) -> i8 {
-        self.hi
+    pub fn af(&self) -> u16 {
+        (self.a as u16) << 0x8 | (self.f as u16)
     }
-}
 
-pub struct HL {
-    lo: i8,
-    hi: i8
-}
+    pub fn set_af(&mut self, value: u16) {
+        self.a = (value >> 0x8) as u8;
+        self.f = (value & 0xF0) as u8;
+    }
 
-impl HL<> {
-    pub fn write(&mut self, value: i16) -> &mut Self {
-        self.lo = (value & 0xFF) as i8;
-        self.hi = (value >> 0x8) as i8;
-        self
+    pub fn bc(&self) -> u16 {
+        (self.b as u16) << 0x8 | (self.c as u16)
     }
 
-    pub fn write_lo(&mut self, value: i8) -> &mut Self {
-        self.lo = value;
-        self
+    pub fn set_bc(&mut self, value: u16) {
+        self.b = (value >> 0x8) as u8;
+        self.c = (value & 0xFF) as u8;
     }
 
-    pub fn write_hi(&mut self, value: i8) -> &mut Self {
-        self.hi = value;
-        self
+    pub fn de(&self) -> u16 {
+        (self.d as u16) << 0x8 | (self.e as u16)
     }
 
-    pub fn read(&mut self) -> i16 {
-        (self.hi as i16).wrapping_shl(0x8) | (self.lo as i16)
+    pub fn set_de(&mut self, value: u16) {
+        self.d = (value >> 0x8) as u8;
+        self.e = (value & 0xFF) as u8;
     }
 
-    pub fn read_lo(&mut self) -> i8 {
-        self.lo
+    pub fn hl(&self) -> u16 {
+        (self.h as u16) << 0x8 | (self.l as u16)
     }
 
-    pub fn read_hi(&mut self) -> i8 {
-        self.hi
+    pub fn set_hl(&mut self, value: u16) {
+        self.h = (value >> 0x8) as u8;
+        self.l = (value & 0xFF) as u8;
     }
 }
 
-#[allow(dead_code)]
-pub struct File {
-    pub af: AF,
-    pub bc: BC,
-    pub de: DE,
-    pub hl: HL,
-    pub pc: PC,
-    pub sp: SP
+#[cfg(test)]
+mod tests {
+    use super::File;
+
+    #[test]
+    fn first_register_of_a_pair_is_the_high_byte() {
+        let mut registers = File::new();
+
+        registers.set_bc(0x1234);
+        registers.set_de(0x5678);
+        registers.set_hl(0x9ABC);
+
+        assert_eq!((registers.b(), registers.c()), (0x12, 0x34));
+        assert_eq!((registers.d(), registers.e()), (0x56, 0x78));
+        assert_eq!((registers.h(), registers.l()), (0x9A, 0xBC));
+
+        registers.set_b(0xAA);
+        registers.set_e(0xBB);
+        registers.set_h(0xCC);
+
+        assert_eq!(registers.bc(), 0xAA34);
+        assert_eq!(registers.de(), 0x56BB);
+        assert_eq!(registers.hl(), 0xCCBC);
+    }
 }
@@ -0,0 +1,333 @@
+use std::fs;
+
+use rom::{self, Header, Title, ROMType, ROMSize, RAMSize, ConType, DestinationCode, GBSGB_Indicator};
+
+use super::value;
+
+const USAGE : &str = "usage: gba-sim fix <rom> [options]
+
+Rewrites the header of <rom> in place, like rgbfix. Numbers can be given in
+decimal, or in hexadecimal with a 0x, 0X or $ prefix.
+
+  --validate            same as --fix lhg
+  --fix <lhg>           repair the Nintendo logo (l), the header checksum (h)
+                        and/or the global checksum (g)
+  --title <text>        title, padded with zeros. It can be 16 characters
+                        long, 11 for CGB games
+  --manufacturer <xxxx> four character manufacturer code of CGB games
+  --licensee <xx>       two character new licensee code; also sets the old
+                        licensee code to 0x33
+  --old-licensee <n>    old licensee code
+  --cgb                 mark the game as supporting CGB functions
+  --cgb-only            mark the game as only running on a CGB
+  --sgb                 mark the game as supporting SGB functions
+  --mapper <n>          cartridge type
+  --ram-size <n>        RAM size code
+  --rom-version <n>     mask ROM version number
+  --non-japanese        set the destination code to non-Japanese
+  --pad <n>             pad the ROM with byte <n> to the next valid size and
+                        set the ROM size code to match";
+
+const TITLE_LENGTH : usize = 16;
+const CGB_TITLE_LENGTH : usize = 11;
+
+// Games need this old licensee code for the SGB to enable its functions
+const SGB_LICENSEE : u8 = 0x33;
+
+struct FixOptions {
+    rom : String,
+    logo : bool,
+    header_checksum : bool,
+    global_checksum : bool,
+    title : Option<String>,
+    manufacturer : Option<String>,
+    licensee : Option<[u8; 2]>,
+    old_licensee : Option<u8>,
+    cgb : bool,
+    cgb_only : bool,
+    sgb : bool,
+    mapper : Option<u8>,
+    ram_size : Option<u8>,
+    rom_version : Option<u8>,
+    non_japanese : bool,
+    pad : Option<u8>
+}
+
+fn parse_args(args: &[String]) -> Result<FixOptions, String> {
+    let mut options = FixOptions {
+        rom: String::new(),
+        logo: false,
+        header_checksum: false,
+        global_checksum: false,
+        title: None,
+        manufacturer: None,
+        licensee: None,
+        old_licensee: None,
+        cgb: false,
+        cgb_only: false,
+        sgb: false,
+        mapper: None,
+        ram_size: None,
+        rom_version: None,
+        non_japanese: false,
+        pad: None
+    };
+
+    let mut rom = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--validate" => {
+                options.logo = true;
+                options.header_checksum = true;
+                options.global_checksum = true;
+            }
+            "--fix" => {
+                for flag in value(&mut args, arg)?.chars() {
+                    match flag {
+                        'l' => options.logo = true,
+                        'h' => options.header_checksum = true,
+                        'g' => options.global_checksum = true,
+                        _ => return Err(format!("unknown --fix flag {}", flag))
+                    }
+                }
+            }
+            "--title" => options.title = Some(value(&mut args, arg)?.clone()),
+            "--manufacturer" => {
+                let manufacturer = value(&mut args, arg)?;
+
+                if manufacturer.len() != 4 {
+                    return Err("the manufacturer code must be four characters long".to_string());
+                }
+
+                options.manufacturer = Some(manufacturer.clone());
+            }
+            "--licensee" => {
+                let licensee = value(&mut args, arg)?.as_bytes();
+
+                if licensee.len() != 2 {
+                    return Err("the licensee code must be two characters long".to_string());
+                }
+
+                options.licensee = Some([licensee[0], licensee[1]]);
+            }
+            "--old-licensee" => options.old_licensee = Some(byte(value(&mut args, arg)?)?),
+            "--cgb" => options.cgb = true,
+            "--cgb-only" => options.cgb_only = true,
+            "--sgb" => options.sgb = true,
+            "--mapper" => options.mapper = Some(byte(value(&mut args, arg)?)?),
+            "--ram-size" => options.ram_size = Some(byte(value(&mut args, arg)?)?),
+            "--rom-version" => options.rom_version = Some(byte(value(&mut args, arg)?)?),
+            "--non-japanese" => options.non_japanese = true,
+            "--pad" => options.pad = Some(byte(value(&mut args, arg)?)?),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if rom.is_none() => rom = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg))
+        }
+    }
+
+    options.rom = match rom {
+        Some(rom) => rom,
+        None => return Err("no rom given".to_string())
+    };
+
+    Ok(options)
+}
+
+fn byte(text: &str) -> Result<u8, String> {
+    let hex = text.strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .or_else(|| text.strip_prefix('$'));
+
+    let parsed = match hex {
+        Some(digits) => u8::from_str_radix(digits, 16),
+        None => text.parse()
+    };
+
+    match parsed {
+        Ok(byte) => Ok(byte),
+        Err(_) => Err(format!("{} is not a valid byte", text))
+    }
+}
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(message) => return Err(format!("{}\n{}", message, USAGE))
+    };
+
+    let mut data = match fs::read(&options.rom) {
+        Ok(data) => data,
+        Err(err) => return Err(format!("could not read {}: {}", options.rom, err))
+    };
+
+    if let Some(value) = options.pad {
+        let size = match ROMSize::for_length(data.len()) {
+            Some(size) => size,
+            None => return Err(format!("{} is too large for any ROM size", options.rom))
+        };
+
+        data.resize(size.bytes(), value);
+    }
+
+    if data.len() < 0x150 {
+        return Err(format!("{} is too small to hold a cartridge header, pad it first", options.rom));
+    }
+
+    // Set before parsing, so ROMs with an unknown type can be fixed too
+    if let Some(code) = options.mapper {
+        if ROMType::from_code(code).is_none() {
+            return Err(format!("unknown cartridge type 0x{:02X}", code));
+        }
+
+        data[0x147] = code;
+    }
+
+    let mut header = match Header::parse(&data) {
+        Ok(header) => header,
+        Err(err) => return Err(format!("could not read the header of {}: {}", options.rom, err))
+    };
+
+    apply(&options, &mut header, data.len())?;
+
+    // CGB games give the end of the title to the manufacturer code
+    let cgb = header.con_type.is_some() || data[0x143] & 0x80 != 0;
+    let limit = if cgb { CGB_TITLE_LENGTH } else { TITLE_LENGTH };
+
+    if header.title.bytes.len() > limit {
+        return Err(format!("the title can be at most {} characters long", limit));
+    }
+
+    if !cgb && header.manufacturer.is_some() {
+        return Err("only CGB games have a manufacturer code, pass --cgb or --cgb-only".to_string());
+    }
+
+    if let Err(err) = header.write(&mut data) {
+        return Err(format!("could not write the header: {}", err));
+    }
+
+    if options.header_checksum {
+        rom::fix_header_checksum(&mut data);
+    }
+
+    if options.global_checksum {
+        rom::fix_global_checksum(&mut data);
+    }
+
+    if let Err(err) = fs::write(&options.rom, &data) {
+        return Err(format!("could not write {}: {}", options.rom, err));
+    }
+
+    Ok(())
+}
+
+fn apply(options: &FixOptions, header: &mut Header, length: usize) -> Result<(), String> {
+    if options.logo {
+        header.restore_logo();
+    }
+
+    if let Some(ref title) = options.title {
+        header.title = Title { bytes: title.clone().into_bytes() };
+    }
+
+    if let Some(ref manufacturer) = options.manufacturer {
+        header.manufacturer = Some(manufacturer.clone());
+    }
+
+    if let Some(licensee) = options.licensee {
+        header.new_licensee = licensee;
+        header.old_licensee = SGB_LICENSEE;
+    }
+
+    if let Some(old_licensee) = options.old_licensee {
+        header.old_licensee = old_licensee;
+    }
+
+    if options.cgb {
+        header.con_type = Some(ConType::Color);
+    }
+
+    if options.cgb_only {
+        header.con_type = Some(ConType::ColorOnly);
+    }
+
+    if options.sgb {
+        header.sgb = Some(GBSGB_Indicator::SGB);
+
+        if header.old_licensee != SGB_LICENSEE {
+            eprintln!("warning: SGB functions are only enabled with old licensee code 0x33");
+        }
+    }
+
+    if let Some(code) = options.ram_size {
+        header.ram_size = match RAMSize::from_code(code) {
+            Some(size) => Some(size),
+            None => return Err(format!("unknown RAM size code 0x{:02X}", code))
+        };
+    }
+
+    if let Some(version) = options.rom_version {
+        header.mask_rom_vers_number = version;
+    }
+
+    if options.non_japanese {
+        header.dest_code = Some(DestinationCode::NonJapanese);
+    }
+
+    if options.pad.is_some() {
+        header.rom_size = ROMSize::for_length(length);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use rom::{self, Header};
+
+    use super::{byte, run};
+
+    #[test]
+    fn bytes_take_every_prefix() {
+        assert_eq!(byte("42"), Ok(42));
+        assert_eq!(byte("0x1B"), Ok(0x1B));
+        assert_eq!(byte("0X1b"), Ok(0x1B));
+        assert_eq!(byte("$FF"), Ok(0xFF));
+        assert!(byte("256").is_err());
+        assert!(byte("0x").is_err());
+        assert!(byte("0xG0").is_err());
+    }
+
+    #[test]
+    fn validate_repairs_a_corrupted_image() {
+        let path = env::temp_dir().join(format!("gba-sim-fix-{}.gb", process::id()));
+        let path = path.to_string_lossy().into_owned();
+
+        let mut data = vec![0; 0x8000];
+        data[0x134..0x138].copy_from_slice(b"TEST");
+        data[0x104] = 0x55;
+        data[0x14D] = 0x77;
+        data[0x14E] = 0x12;
+        data[0x14F] = 0x34;
+        data[0x4000] = 0x99;
+        fs::write(&path, &data).unwrap();
+
+        run(&[path.clone(), "--validate".to_string()]).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(rom::has_logo(&data, 0x104));
+        assert_eq!(data[0x14D], rom::header_checksum(&data));
+        assert_eq!((data[0x14E] as u16) << 8 | data[0x14F] as u16, rom::global_checksum(&data));
+
+        let header = Header::parse(&data).unwrap();
+        assert!(header.validate().is_ok());
+        assert_eq!(header.title.bytes, b"TEST");
+    }
+}
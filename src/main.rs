@@ -1,9 +1,440 @@
+extern crate png;
+
 mod cpu;
 mod rom;
+mod timer;
+mod ppu;
+mod apu;
+mod wav;
+mod save;
+mod fix;
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::process;
+
+use cpu::{Cpu, Mmu};
+use ppu::Renderer;
+use wav::WavWriter;
+use save::BatterySave;
+
+const USAGE : &str = "usage: gba-sim <rom|gbs> [--wav <file>] [--wav-channels] [--seconds <n>] [--rate <hz>] [--track <n|all>] [--camera <png>] [--save-interval <n>]
+               [--renderer <scanline|fifo>] [--screenshot <png>]
+       gba-sim fix <rom> [options]
+
+  --wav <file>      run without a display and record the mixed audio to <file>
+  --wav-channels    also record each channel to <file>.ch1.wav - .ch4.wav
+  --seconds <n>     emulated time to record, 60 by default
+  --rate <hz>       sample rate of the recording, 8000 - 192000, 44100 by
+                    default
+  --track <n|all>   songs of a .gbs file to record, may be repeated; the first
+                    song by default. With several songs <file> becomes
+                    <file>.track<n>.wav
+  --camera <png>    picture a Pocket Camera cartridge sees instead of noise
+  --save-interval <n>
+                    seconds of emulated time between writes of the battery
+                    save to <rom>.sav, 5 by default; 0 only writes on exit
+  --renderer <scanline|fifo>
+                    how the PPU draws: a whole line at once, the default, or
+                    dot by dot with a pixel FIFO for exact mode 3 timing
+  --screenshot <png>
+                    write the last frame drawn to <png> when the recording
+                    ends
+
+  fix               rewrite the header of <rom> like rgbfix, run it without
+                    options for the details";
+
+const CYCLES_PER_SECOND : u64 = 1048576;
+
+// Sample rates players commonly accept. Far lower ones alias every channel
+// and far higher ones only make the files larger.
+const MIN_SAMPLE_RATE : u32 = 8000;
+const MAX_SAMPLE_RATE : u32 = 192000;
+
+// Seconds between writes of the battery save
+const DEFAULT_SAVE_INTERVAL : u64 = 5;
+
+// Samples are moved from the APU to the files about once per frame
+const FLUSH_CYCLES : u64 = 17556;
+
+struct Options {
+    rom : String,
+    wav : Option<String>,
+    wav_channels : bool,
+    seconds : u64,
+    rate : u32,
+    // 1 based song numbers for GBS files
+    tracks : Vec<u8>,
+    all_tracks : bool,
+    camera : Option<String>,
+    // Seconds, 0 to only save on exit
+    save_interval : Option<u64>,
+    renderer : Option<Renderer>,
+    screenshot : Option<String>
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        rom: String::new(),
+        wav: None,
+        wav_channels: false,
+        seconds: 60,
+        rate: apu::DEFAULT_SAMPLE_RATE,
+        tracks: Vec::new(),
+        all_tracks: false,
+        camera: None,
+        save_interval: None,
+        renderer: None,
+        screenshot: None
+    };
+
+    let mut rom = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--wav" => options.wav = Some(value(&mut args, arg)?.clone()),
+            "--wav-channels" => options.wav_channels = true,
+            "--seconds" => options.seconds = number(value(&mut args, arg)?)?,
+            "--rate" => options.rate = number(value(&mut args, arg)?)?,
+            "--camera" => options.camera = Some(value(&mut args, arg)?.clone()),
+            "--screenshot" => options.screenshot = Some(value(&mut args, arg)?.clone()),
+            "--save-interval" => options.save_interval = Some(number(value(&mut args, arg)?)?),
+            "--renderer" => {
+                options.renderer = match value(&mut args, arg)?.as_str() {
+                    "scanline" => Some(Renderer::Scanline),
+                    "fifo" => Some(Renderer::Fifo),
+                    other => return Err(format!("unknown renderer {}", other))
+                };
+            }
+            "--track" => {
+                let track = value(&mut args, arg)?;
+
+                if track == "all" {
+                    options.all_tracks = true;
+                } else {
+                    options.tracks.push(number(track)?);
+                }
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if rom.is_none() => rom = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg))
+        }
+    }
+
+    options.rom = match rom {
+        Some(rom) => rom,
+        None => return Err("no rom given".to_string())
+    };
+
+    // Only a recording runs the machine, so these would do nothing without
+    // one
+    if options.wav.is_none() {
+        if options.wav_channels {
+            return Err("--wav-channels needs --wav".to_string());
+        }
+
+        if options.renderer.is_some() {
+            return Err("--renderer needs --wav".to_string());
+        }
+
+        if options.camera.is_some() {
+            return Err("--camera needs --wav".to_string());
+        }
+
+        if options.save_interval.is_some() {
+            return Err("--save-interval needs --wav".to_string());
+        }
+
+        if options.screenshot.is_some() {
+            return Err("--screenshot needs --wav".to_string());
+        }
+    }
+
+    if options.rate < MIN_SAMPLE_RATE || options.rate > MAX_SAMPLE_RATE {
+        return Err(format!("the sample rate must be between {} and {} Hz", MIN_SAMPLE_RATE, MAX_SAMPLE_RATE));
+    }
+
+    // Every second takes rate stereo 16-bit samples, and a WAV file holds
+    // less than 4 GiB of them. This also keeps the length in cycles far from
+    // overflowing.
+    let max_seconds = wav::MAX_DATA_SIZE as u64 / (options.rate as u64 * 4);
+
+    if options.seconds > max_seconds {
+        return Err(format!("at {} Hz a recording can last at most {} seconds", options.rate, max_seconds));
+    }
+
+    Ok(options)
+}
+
+fn value<'a, I: Iterator<Item = &'a String>>(args: &mut I, option: &str) -> Result<&'a String, String> {
+    match args.next() {
+        Some(value) => Ok(value),
+        None => Err(format!("{} needs a value", option))
+    }
+}
+
+fn number<T: std::str::FromStr>(text: &str) -> Result<T, String> {
+    match text.parse() {
+        Ok(number) => Ok(number),
+        Err(_) => Err(format!("{} is not a valid number", text))
+    }
+}
+
+// Runs the machine without a display for the requested time and writes
+// the APU output to WAV files. Each call of step advances it and returns the
+// M-cycles that went by, or why the machine cannot go on.
+fn capture_audio<F: FnMut(&mut Cpu) -> Result<u32, String>>(cpu: &mut Cpu, mut step: F, path: &str, options: &Options) -> Result<(), String> {
+    cpu.mmu.apu.set_sample_rate(options.rate);
+    cpu.mmu.apu.set_capture_channels(options.wav_channels);
+
+    let failed = |path: &str, error: io::Error| format!("could not write {}: {}", path, error);
+
+    let mut mixed = WavWriter::create(path, options.rate, 2).map_err(|error| failed(path, error))?;
+    let mut channels = Vec::new();
+
+    if options.wav_channels {
+        for channel in 1..5 {
+            let channel_path = channel_path(path, channel);
+            let writer = WavWriter::create(&channel_path, options.rate, 2).map_err(|error| failed(&channel_path, error))?;
+            channels.push((channel_path, writer));
+        }
+    }
+
+    let total = options.seconds * CYCLES_PER_SECOND;
+    let mut elapsed = 0;
+
+    while elapsed < total {
+        let target = (elapsed + FLUSH_CYCLES).min(total);
+
+        while elapsed < target {
+            elapsed += step(cpu)? as u64;
+        }
+
+        mixed.write_samples(&cpu.mmu.apu.take_samples()).map_err(|error| failed(path, error))?;
+
+        for (i, &mut (ref channel_path, ref mut writer)) in channels.iter_mut().enumerate() {
+            let samples = cpu.mmu.apu.take_channel_samples(i + 1);
+            writer.write_samples(&samples).map_err(|error| failed(channel_path, error))?;
+        }
+    }
+
+    mixed.finish().map_err(|error| failed(path, error))?;
+
+    for (channel_path, writer) in channels {
+        writer.finish().map_err(|error| failed(&channel_path, error))?;
+    }
+
+    Ok(())
+}
+
+// music.wav becomes music.ch1.wav
+fn channel_path(path: &str, channel: usize) -> String {
+    format!("{}.ch{}.wav", stem(path), channel)
+}
+
+fn stem(path: &str) -> &str {
+    path.strip_suffix(".wav").unwrap_or(path)
+}
+
+fn play_gbs(options: &Options) -> Result<(), String> {
+    let path = match options.wav {
+        Some(ref path) => path,
+        None => return Err("GBS files can only be recorded, pass --wav".to_string())
+    };
+
+    let gbs = match rom::Gbs::new_from_file(&options.rom) {
+        Ok(gbs) => gbs,
+        Err(error) => return Err(format!("could not load {}: {}", options.rom, error))
+    };
+    println!("{} - {} ({}), {} songs", gbs.title, gbs.author, gbs.copyright, gbs.song_count);
+
+    let tracks = if options.all_tracks {
+        (1..gbs.song_count as u16 + 1).map(|track| track as u8).collect()
+    } else if options.tracks.is_empty() {
+        vec![gbs.first_song]
+    } else {
+        options.tracks.clone()
+    };
+
+    for &track in tracks.iter() {
+        if track == 0 || track > gbs.song_count {
+            return Err(format!("there is no song {}", track));
+        }
+
+        let track_path = if tracks.len() > 1 {
+            format!("{}.track{:02}.wav", stem(path), track)
+        } else {
+            path.clone()
+        };
+
+        println!("Recording song {} to {}", track, track_path);
+
+        let (mut cpu, mut player) = match gbs.start(track - 1) {
+            Ok(started) => started,
+            Err(error) => return Err(format!("song {}: {}", track, error))
+        };
+
+        let step = |cpu: &mut Cpu| player.step(cpu).map_err(|error| format!("song {}: {}", track, error));
+        capture_audio(&mut cpu, step, &track_path, options)?;
+    }
+
+    Ok(())
+}
+
+// Writes a frame of the PPU as a grayscale PNG
+fn write_screenshot(path: &str, frame: &[u8]) -> Result<(), String> {
+    let failed = |error: &dyn std::fmt::Display| format!("could not write {}: {}", path, error);
+
+    // Shade 0 is white and 3 black
+    let data : Vec<u8> = frame.iter().map(|&shade| 0xFF - shade * 0x55).collect();
+
+    let file = File::create(path).map_err(|error| failed(&error))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), ppu::SCREEN_WIDTH as u32, ppu::SCREEN_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header().map_err(|error| failed(&error))?;
+    writer.write_image_data(&data).map_err(|error| failed(&error))
+}
+
+// Loads the .sav file of cartridges with a battery
+fn open_save(cpu: &mut Cpu, options: &Options) -> Option<BatterySave> {
+    if cpu.mmu.mapper().battery().is_empty() {
+        return None;
+    }
+
+    let interval = options.save_interval.unwrap_or(DEFAULT_SAVE_INTERVAL) * CYCLES_PER_SECOND;
+    let mut save = BatterySave::new(&BatterySave::path_for(&options.rom), interval);
+
+    if let Err(error) = save.load(cpu.mmu.mapper_mut()) {
+        eprintln!("could not load {}: {}", save.path(), error);
+        process::exit(1);
+    }
+
+    Some(save)
+}
+
+// Reports each header check on its own. None of them stop the ROM from
+// running.
+fn print_checks(cart: &rom::Cartridge) {
+    let header = &cart.header;
+
+    let checks = [
+        ("Nintendo logo", header.validate(), String::new()),
+        ("Header checksum", cart.verify_header_checksum(),
+            format!(" (stored 0x{:02X}, computed 0x{:02X})", header.compl_check, rom::header_checksum(&cart.rom))),
+        ("Global checksum", cart.verify_global_checksum(),
+            format!(" (stored 0x{:04X}, computed 0x{:04X})", header.global_checksum(), rom::global_checksum(&cart.rom)))
+    ];
+
+    for &(name, ref result, ref details) in checks.iter() {
+        match *result {
+            Ok(()) => println!("{}: ok", name),
+            Err(ref error) => println!("{}: {}{}", name, error, details)
+        }
+    }
+}
 
 fn main() {
     let args : Vec<String> = std::env::args().collect();
-    let cart = rom::Cartridge::new_from_file(&args[1]).unwrap();
+
+    if args.get(1).is_some_and(|arg| arg == "fix") {
+        if let Err(message) = fix::run(&args[2..]) {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+
+        return;
+    }
+
+    let options = match parse_args(&args[1..]) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            process::exit(1);
+        }
+    };
+
+    if options.rom.to_lowercase().ends_with(".gbs") {
+        if let Err(message) = play_gbs(&options) {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+
+        return;
+    }
+
+    let cart = match rom::Cartridge::new_from_file(&options.rom) {
+        Ok(cart) => cart,
+        Err(error) => {
+            eprintln!("could not load {}: {}", options.rom, error);
+            process::exit(1);
+        }
+    };
+
     println!("Cartridge info:");
     println!("{:?}", cart);
+    println!("Licensee: {}", cart.header.licensee().name().unwrap_or("unknown"));
+    print_checks(&cart);
+
+    if let Some(ref path) = options.wav {
+        let renderer = options.renderer.unwrap_or(Renderer::Scanline);
+        let mut cpu = Cpu::new(Mmu::new(cart, renderer));
+
+        if let Some(ref camera) = options.camera {
+            match rom::PngSource::new_from_file(camera) {
+                Ok(source) => cpu.mmu.mapper_mut().set_image_source(Box::new(source)),
+                Err(message) => {
+                    eprintln!("{}", message);
+                    process::exit(1);
+                }
+            }
+        }
+
+        // There is no force feedback to drive, so the motor is only logged
+        cpu.mmu.mapper_mut().on_rumble(Box::new(|motor| {
+            println!("Rumble motor {}", if motor { "on" } else { "off" });
+        }));
+
+        let mut save = open_save(&mut cpu, &options);
+        let mut frames = 0;
+
+        let result = capture_audio(&mut cpu, |cpu| {
+            let cycles = cpu.step();
+
+            if cpu.mmu.ppu.frame_finished() {
+                frames += 1;
+            }
+
+            if let Some(ref mut save) = save {
+                if let Err(error) = save.tick(cycles, cpu.mmu.mapper()) {
+                    eprintln!("could not write {}: {}", save.path(), error);
+                }
+            }
+
+            Ok(cycles)
+        }, path, &options);
+
+        if let Some(ref mut save) = save {
+            if let Err(error) = save.flush(cpu.mmu.mapper()) {
+                eprintln!("could not write {}: {}", save.path(), error);
+            }
+        }
+
+        if let Err(message) = result {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+
+        println!("Drew {} frames", frames);
+
+        if let Some(ref screenshot) = options.screenshot {
+            if let Err(message) = write_screenshot(screenshot, cpu.mmu.ppu.frame()) {
+                eprintln!("{}", message);
+                process::exit(1);
+            }
+        }
+    }
 }
+
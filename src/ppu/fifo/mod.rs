@@ -0,0 +1,524 @@
+use std::collections::VecDeque;
+
+use super::{Ppu, SCREEN_WIDTH, color_index, shade};
+
+// Dots the sprite fetcher needs once the background fetcher is paused
+const SPRITE_FETCH_DOTS : u8 = 6;
+
+// Longest wait for the background fetcher before a sprite fetch can start
+const MAX_FETCHER_WAIT : u8 = 5;
+
+#[derive(Clone, Copy, PartialEq)]
+enum FetchStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push
+}
+
+#[derive(Clone, Copy)]
+struct ObjPixel {
+    color : u8,
+    obp1 : bool,
+    behind_bg : bool
+}
+
+static TRANSPARENT : ObjPixel = ObjPixel { color: 0, obp1: false, behind_bg: false };
+
+// Sprite found by the OAM scan. Its row and height are fixed at that point,
+// so a later write to LCDC bit 2 cannot move the fetch outside the tile.
+#[derive(Clone, Copy)]
+struct LineSprite {
+    index : usize,
+    // Row of the sprite on the current line, before any vertical flip
+    row : u8,
+    height : u8
+}
+
+// State of the pixel pipeline during mode 3. Registers are sampled when a
+// tile is fetched or a pixel leaves the FIFO, so mid-line writes to SCX,
+// LCDC or the palettes take effect on the very next tile or pixel.
+pub struct Fifo {
+    bg : VecDeque<u8>,
+    obj : VecDeque<ObjPixel>,
+    step : FetchStep,
+    step_dots : u8,
+    // Tile column the background fetcher works on
+    fetch_x : u8,
+    tile : u8,
+    data_lo : u8,
+    data_hi : u8,
+    // The first tile of a line is fetched twice
+    first_fetch : bool,
+    // Pixels still to drop for the fine SCX scroll
+    discard : u8,
+    // Pixels pushed to the LCD on this line
+    lx : u8,
+    window : bool,
+    // WY matched LY at some point of the current frame
+    window_triggered : bool,
+    // Sprites found by the OAM scan, in the order they are fetched
+    sprites : Vec<LineSprite>,
+    next_sprite : usize,
+    // Dots left until the sprite being fetched is merged, 0 when there is
+    // none
+    sprite_dots : u8,
+    // Background or window tile the last sprite's leftmost pixel fell in
+    sprite_tile : Option<i32>
+}
+
+impl Fifo<> {
+    pub fn new() -> Self {
+        Fifo {
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            step: FetchStep::Tile,
+            step_dots: 0,
+            fetch_x: 0,
+            tile: 0,
+            data_lo: 0,
+            data_hi: 0,
+            first_fetch: true,
+            discard: 0,
+            lx: 0,
+            window: false,
+            window_triggered: false,
+            sprites: Vec::with_capacity(10),
+            next_sprite: 0,
+            sprite_dots: 0,
+            sprite_tile: None
+        }
+    }
+}
+
+impl Ppu<> {
+    // Prepares the pipeline at the transition from OAM scan to drawing
+    pub fn fifo_start(&mut self, oam: &[u8]) {
+        let height = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
+        let ly = self.ly as i32;
+
+        let sprites = self.scan_oam(oam).into_iter().map(|index| LineSprite {
+            index,
+            row: (ly - (oam[index * 4] as i32 - 16)) as u8,
+            height
+        }).collect();
+
+        let fifo = &mut self.fifo;
+
+        if self.ly == 0 {
+            fifo.window_triggered = false;
+        }
+
+        if self.ly == self.wy {
+            fifo.window_triggered = true;
+        }
+
+        fifo.bg.clear();
+        fifo.obj.clear();
+        fifo.step = FetchStep::Tile;
+        fifo.step_dots = 0;
+        fifo.fetch_x = 0;
+        fifo.first_fetch = true;
+        fifo.discard = self.scx & 0x07;
+        fifo.lx = 0;
+        fifo.window = false;
+        fifo.sprites = sprites;
+        fifo.next_sprite = 0;
+        fifo.sprite_dots = 0;
+        fifo.sprite_tile = None;
+    }
+
+    // Runs one M-cycle worth of dots and returns true once the whole line
+    // has been pushed out
+    pub fn fifo_tick(&mut self, vram: &[u8], oam: &[u8]) -> bool {
+        for _ in 0..4 {
+            if self.fifo_dot(vram, oam) {
+                if self.fifo.window {
+                    self.window_line += 1;
+                }
+
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn fifo_dot(&mut self, vram: &[u8], oam: &[u8]) -> bool {
+        // Sprites are only looked at once the first tile is in the FIFO
+        if self.fifo.sprite_dots == 0 && !self.fifo.bg.is_empty() && self.sprite_at_lx(oam) {
+            self.fifo.sprite_dots = self.sprite_penalty(oam);
+        }
+
+        // Both the fetcher and the pixel output stand still while a sprite
+        // is fetched, so every penalty adds to mode 3 as is
+        if self.fifo.sprite_dots > 0 {
+            self.fifo.sprite_dots -= 1;
+
+            if self.fifo.sprite_dots == 0 {
+                let sprite = self.fifo.sprites[self.fifo.next_sprite];
+                self.fifo.next_sprite += 1;
+                self.merge_sprite(vram, oam, sprite);
+            }
+
+            return false;
+        }
+
+        // The pixel leaves the FIFO before the fetcher runs, so a tile
+        // pushed on this dot only shows from the next one
+        let finished = self.shift_pixel();
+        self.fetcher_dot(vram);
+
+        finished
+    }
+
+    // Sends the next pixel to the LCD, if there is one. Returns true after
+    // the last pixel of the line.
+    fn shift_pixel(&mut self) -> bool {
+        if self.fifo.bg.is_empty() {
+            return false;
+        }
+
+        if !self.fifo.window && self.lcdc & 0x20 != 0 && self.fifo.window_triggered &&
+            self.fifo.lx as i32 + 7 >= self.wx as i32 {
+            // Switching to the window restarts the fetcher on an empty FIFO
+            let fifo = &mut self.fifo;
+            fifo.window = true;
+            fifo.sprite_tile = None;
+            fifo.bg.clear();
+            fifo.step = FetchStep::Tile;
+            fifo.step_dots = 0;
+            fifo.fetch_x = 0;
+            return false;
+        }
+
+        let bg = self.fifo.bg.pop_front().unwrap_or(0);
+
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return false;
+        }
+
+        let obj = self.fifo.obj.pop_front().unwrap_or(TRANSPARENT);
+
+        // On DMG, LCDC bit 0 turns background and window white
+        let bg = if self.lcdc & 0x01 != 0 { bg } else { 0 };
+
+        let pixel = if obj.color != 0 && self.lcdc & 0x02 != 0 && !(obj.behind_bg && bg != 0) {
+            shade(if obj.obp1 { self.obp1 } else { self.obp0 }, obj.color)
+        } else {
+            shade(self.bgp, bg)
+        };
+
+        self.frame[self.ly as usize * SCREEN_WIDTH + self.fifo.lx as usize] = pixel;
+        self.fifo.lx += 1;
+
+        self.fifo.lx as usize == SCREEN_WIDTH
+    }
+
+    // Whether the next sprite in line starts at or before the current pixel
+    fn sprite_at_lx(&self, oam: &[u8]) -> bool {
+        if self.lcdc & 0x02 == 0 {
+            return false;
+        }
+
+        match self.fifo.sprites.get(self.fifo.next_sprite) {
+            Some(sprite) => oam[sprite.index * 4 + 1] as u32 <= self.fifo.lx as u32 + 8,
+            None => false
+        }
+    }
+
+    // Dots the next sprite holds the line up for. The fetch takes 6, but
+    // first the background fetcher has to finish the tile the sprite's
+    // leftmost pixel is in, which costs up to 5 more the first time a tile
+    // is met. A sprite at X 0 always waits the full 5.
+    fn sprite_penalty(&mut self, oam: &[u8]) -> u8 {
+        let index = self.fifo.sprites[self.fifo.next_sprite].index;
+        let x = oam[index * 4 + 1] as i32;
+
+        // Position of the leftmost pixel in background or window space
+        let position = if self.fifo.window { x + 7 - self.wx as i32 } else { x + self.scx as i32 };
+        let tile = position >> 3;
+
+        let wait = if x == 0 {
+            MAX_FETCHER_WAIT
+        } else if self.fifo.sprite_tile == Some(tile) {
+            0
+        } else {
+            MAX_FETCHER_WAIT.saturating_sub((position & 0x07) as u8)
+        };
+
+        self.fifo.sprite_tile = Some(tile);
+
+        SPRITE_FETCH_DOTS + wait
+    }
+
+    fn fetcher_dot(&mut self, vram: &[u8]) {
+        let fifo_step = self.fifo.step;
+
+        if fifo_step != FetchStep::Push {
+            self.fifo.step_dots += 1;
+
+            // Every step but the push takes two dots
+            if self.fifo.step_dots < 2 {
+                return;
+            }
+
+            self.fifo.step_dots = 0;
+        }
+
+        match fifo_step {
+            FetchStep::Tile => {
+                let (map, x, y) = self.fetch_coordinates();
+                self.fifo.tile = vram[map + (y as usize / 8) * 32 + x as usize];
+                self.fifo.step = FetchStep::DataLow;
+            }
+            FetchStep::DataLow => {
+                let address = self.fetch_address();
+                self.fifo.data_lo = vram[address];
+                self.fifo.step = FetchStep::DataHigh;
+            }
+            FetchStep::DataHigh | FetchStep::Push => {
+                if fifo_step == FetchStep::DataHigh {
+                    let address = self.fetch_address();
+                    self.fifo.data_hi = vram[address + 1];
+                }
+
+                self.fifo.step = FetchStep::Push;
+
+                // Pixels only go in once the FIFO has drained
+                if !self.fifo.bg.is_empty() {
+                    return;
+                }
+
+                self.fifo.step = FetchStep::Tile;
+
+                if self.fifo.first_fetch {
+                    self.fifo.first_fetch = false;
+                    return;
+                }
+
+                for x in 0..8 {
+                    let color = color_index(self.fifo.data_lo, self.fifo.data_hi, x);
+                    self.fifo.bg.push_back(color);
+                }
+
+                self.fifo.fetch_x = self.fifo.fetch_x.wrapping_add(1);
+            }
+        }
+    }
+
+    // Tile map base, tile column and pixel row for the current fetch
+    fn fetch_coordinates(&self) -> (usize, u8, u8) {
+        if self.fifo.window {
+            let map = if self.lcdc & 0x40 != 0 { 0x1C00 } else { 0x1800 };
+            (map, self.fifo.fetch_x & 0x1F, self.window_line)
+        } else {
+            let map = if self.lcdc & 0x08 != 0 { 0x1C00 } else { 0x1800 };
+            let x = ((self.scx >> 3).wrapping_add(self.fifo.fetch_x)) & 0x1F;
+            (map, x, self.ly.wrapping_add(self.scy))
+        }
+    }
+
+    fn fetch_address(&self) -> usize {
+        let (_, _, y) = self.fetch_coordinates();
+        let tile = self.fifo.tile;
+
+        let tile_address = if self.lcdc & 0x10 != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + tile as i8 as i32 * 16) as usize
+        };
+
+        tile_address + (y as usize % 8) * 2
+    }
+
+    // Overlays a fetched sprite on the sprite FIFO. Pixels already held by
+    // an earlier sprite win, which gives the DMG priority rules.
+    fn merge_sprite(&mut self, vram: &[u8], oam: &[u8], sprite: LineSprite) {
+        let entry = &oam[sprite.index * 4..sprite.index * 4 + 4];
+        let attributes = entry[3];
+
+        let mut line = sprite.row;
+
+        if attributes & 0x40 != 0 {
+            line = sprite.height - 1 - line;
+        }
+
+        let tile = if sprite.height == 16 { entry[2] & 0xFE } else { entry[2] };
+        let address = tile as usize * 16 + line as usize * 2;
+        let start = entry[1] as i32 - 8;
+
+        while self.fifo.obj.len() < 8 {
+            self.fifo.obj.push_back(TRANSPARENT);
+        }
+
+        for column in 0..8 {
+            let slot = start + column - self.fifo.lx as i32;
+
+            if !(0..8).contains(&slot) {
+                continue;
+            }
+
+            let bit = if attributes & 0x20 != 0 { 7 - column } else { column };
+            let color = color_index(vram[address], vram[address + 1], bit as u8);
+
+            if self.fifo.obj[slot as usize].color == 0 {
+                self.fifo.obj[slot as usize] = ObjPixel {
+                    color,
+                    obp1: attributes & 0x10 != 0,
+                    behind_bg: attributes & 0x80 != 0
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Ppu, Renderer, SCREEN_WIDTH};
+
+    // Shortest mode 3: no scroll, window or sprites
+    const BASE_DOTS : u32 = 172;
+
+    // PPU on line 0 with the background and 8x8 sprites on, tile data at
+    // 0x8000 and the background map at 0x9800
+    fn ppu() -> Ppu {
+        let mut ppu = Ppu::new(Renderer::Fifo);
+        ppu.write(0xFF40, 0x93);
+        ppu
+    }
+
+    // Runs mode 3 to the end and returns how many dots it took
+    fn mode3_dots(ppu: &mut Ppu, vram: &[u8], oam: &[u8]) -> u32 {
+        ppu.fifo_start(oam);
+
+        let mut dots = 1;
+
+        while !ppu.fifo_dot(vram, oam) {
+            dots += 1;
+        }
+
+        dots
+    }
+
+    fn run_to(ppu: &mut Ppu, vram: &[u8], oam: &[u8], lx: u8) {
+        while ppu.fifo.lx < lx {
+            ppu.fifo_dot(vram, oam);
+        }
+    }
+
+    fn sprite_dots(scx: u8, xs: &[u8]) -> u32 {
+        let mut oam = [0; 0xA0];
+
+        for (i, &x) in xs.iter().enumerate() {
+            oam[i * 4] = 16;
+            oam[i * 4 + 1] = x;
+        }
+
+        let mut ppu = ppu();
+        ppu.write(0xFF43, scx);
+        mode3_dots(&mut ppu, &[0; 0x2000], &oam)
+    }
+
+    #[test]
+    fn fine_scroll_lengthens_mode3() {
+        for scx in 0..32 {
+            assert_eq!(sprite_dots(scx, &[]), BASE_DOTS + (scx & 0x07) as u32, "SCX {}", scx);
+        }
+    }
+
+    #[test]
+    fn sprite_penalty_depends_on_position_in_tile() {
+        // 11 dots for a sprite on a tile boundary, down to 6 from 5 pixels in
+        for x in 1..48u8 {
+            let wait = 5u32.saturating_sub((x & 0x07) as u32);
+            assert_eq!(sprite_dots(0, &[x]), BASE_DOTS + 6 + wait, "X {}", x);
+        }
+
+        // The fine scroll shifts the tiles under the sprite
+        assert_eq!(sprite_dots(3, &[8]), BASE_DOTS + 3 + 8);
+        assert_eq!(sprite_dots(3, &[13]), BASE_DOTS + 3 + 11);
+
+        // X 0 always costs the most
+        assert_eq!(sprite_dots(0, &[0]), BASE_DOTS + 11);
+        assert_eq!(sprite_dots(5, &[0]), BASE_DOTS + 5 + 11);
+    }
+
+    #[test]
+    fn sprites_on_one_tile_wait_once() {
+        assert_eq!(sprite_dots(0, &[8, 10]), BASE_DOTS + 11 + 6);
+        assert_eq!(sprite_dots(0, &[8, 8, 8]), BASE_DOTS + 11 + 6 + 6);
+        assert_eq!(sprite_dots(0, &[8, 16]), BASE_DOTS + 11 + 11);
+    }
+
+    #[test]
+    fn mid_line_palette_write_applies_from_next_pixel() {
+        let vram = [0; 0x2000];
+        let oam = [0; 0xA0];
+        let mut ppu = ppu();
+
+        ppu.fifo_start(&oam);
+        run_to(&mut ppu, &vram, &oam, 80);
+        ppu.write(0xFF47, 0xFF);
+        run_to(&mut ppu, &vram, &oam, SCREEN_WIDTH as u8);
+
+        let line = &ppu.frame()[..SCREEN_WIDTH];
+        assert!(line[..80].iter().all(|&shade| shade == 0));
+        assert!(line[80..].iter().all(|&shade| shade == 3));
+    }
+
+    #[test]
+    fn mid_line_scroll_write_applies_from_next_tile() {
+        let mut vram = [0; 0x2000];
+        let oam = [0; 0xA0];
+
+        // Tile 1 is solid color 3 and fills the right half of the map
+        for byte in vram[0x10..0x20].iter_mut() {
+            *byte = 0xFF;
+        }
+
+        for column in vram[0x1810..0x1820].iter_mut() {
+            *column = 1;
+        }
+
+        let mut ppu = ppu();
+        ppu.fifo_start(&oam);
+        run_to(&mut ppu, &vram, &oam, 40);
+        // From tile 6 on the fetcher reads columns 16 - 31
+        ppu.write(0xFF43, 80);
+        run_to(&mut ppu, &vram, &oam, SCREEN_WIDTH as u8);
+
+        // The tile already in the FIFO still comes out with the old scroll
+        let line = &ppu.frame()[..SCREEN_WIDTH];
+        assert!(line[..48].iter().all(|&shade| shade == 0));
+        assert!(line[48..].iter().all(|&shade| shade == 3));
+    }
+
+    #[test]
+    fn sprite_height_is_kept_from_oam_scan() {
+        let mut vram = [0; 0x2000];
+        let mut oam = [0; 0xA0];
+
+        // Y-flipped 8x16 sprite whose row 12 is on line 0, so tile 2 row 3
+        // is drawn
+        oam[0] = 4;
+        oam[1] = 8;
+        oam[2] = 2;
+        oam[3] = 0x40;
+        vram[2 * 16 + 3 * 2] = 0xFF;
+        vram[2 * 16 + 3 * 2 + 1] = 0xFF;
+
+        let mut ppu = ppu();
+        ppu.write(0xFF40, 0x97);
+        ppu.fifo_start(&oam);
+
+        // Switching to 8x8 sprites in mode 3 no longer changes the row
+        ppu.write(0xFF40, 0x93);
+        run_to(&mut ppu, &vram, &oam, SCREEN_WIDTH as u8);
+
+        let line = &ppu.frame()[..SCREEN_WIDTH];
+        assert!(line[..8].iter().all(|&shade| shade == 3));
+        assert_eq!(line[8], 0);
+    }
+}
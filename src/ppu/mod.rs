@@ -0,0 +1,628 @@
+use cpu::{Interrupt, Interrupts};
+
+mod fifo;
+
+pub const SCREEN_WIDTH : usize = 160;
+pub const SCREEN_HEIGHT : usize = 144;
+
+// Dots (T-cycles) spent in each part of a visible line
+const OAM_SCAN_DOTS : u32 = 80;
+const DRAWING_DOTS : u32 = 172;
+const LINE_DOTS : u32 = 456;
+
+const VBLANK_LINE : u8 = 144;
+const LAST_LINE : u8 = 153;
+
+const MAX_SPRITES_PER_LINE : usize = 10;
+
+// How mode 3 produces pixels. Scanline draws a whole line at once with a
+// fixed mode 3 length and is the fastest. Fifo runs the background and
+// sprite fetchers dot by dot, so mode 3 length varies with the fine scroll,
+// the window and sprites, and register writes in the middle of a line show
+// up where they happen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Renderer {
+    Scanline,
+    Fifo
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3
+}
+
+// LCD controller registers (0xFF40 - 0xFF45, 0xFF47 - 0xFF4B) plus the mode
+// state machine. VRAM and OAM stay on the Mmu and are lent to the PPU on
+// every tick.
+pub struct Ppu {
+    lcdc : u8,
+    stat : u8,
+    scy : u8,
+    scx : u8,
+    ly : u8,
+    lyc : u8,
+    bgp : u8,
+    obp0 : u8,
+    obp1 : u8,
+    wy : u8,
+    wx : u8,
+    mode : Mode,
+    dot : u32,
+    // Line of the window to draw next; only advances on lines that show it
+    window_line : u8,
+    // STAT interrupts fire on the rising edge of the OR of all sources
+    stat_line : bool,
+    frame : Vec<u8>,
+    frame_ready : bool,
+    renderer : Renderer,
+    fifo : fifo::Fifo
+}
+
+impl Ppu<> {
+    pub fn new(renderer: Renderer) -> Self {
+        Ppu {
+            // Values left by the DMG boot ROM
+            lcdc: 0x91,
+            stat: 0x00,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            mode: Mode::OamScan,
+            dot: 0,
+            window_line: 0,
+            stat_line: false,
+            frame: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_ready: false,
+            renderer,
+            fifo: fifo::Fifo::new()
+        }
+    }
+
+    // Last completed frame, one shade (0 = white, 3 = black) per pixel in
+    // row-major order
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    // Returns true once per frame, after the last visible line was drawn
+    pub fn frame_finished(&mut self) -> bool {
+        let ready = self.frame_ready;
+        self.frame_ready = false;
+        ready
+    }
+
+    // The CPU only sees the mode through STAT
+    #[cfg(test)]
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    // Advances the PPU by one M-cycle
+    pub fn tick(&mut self, vram: &[u8], oam: &[u8], interrupts: &mut Interrupts) {
+        if self.lcdc & 0x80 == 0 {
+            return;
+        }
+
+        self.dot += 4;
+
+        match self.mode {
+            Mode::OamScan => {
+                if self.dot >= OAM_SCAN_DOTS {
+                    self.mode = Mode::Drawing;
+
+                    if self.renderer == Renderer::Fifo {
+                        self.fifo_start(oam);
+                    }
+                }
+            }
+            Mode::Drawing => {
+                let finished = match self.renderer {
+                    Renderer::Scanline => {
+                        if self.dot >= OAM_SCAN_DOTS + DRAWING_DOTS {
+                            self.render_line(vram, oam);
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                    Renderer::Fifo => self.fifo_tick(vram, oam)
+                };
+
+                if finished {
+                    self.mode = Mode::HBlank;
+                }
+            }
+            Mode::HBlank | Mode::VBlank => {
+                if self.dot >= LINE_DOTS {
+                    self.dot -= LINE_DOTS;
+                    self.next_line(interrupts);
+                }
+            }
+        }
+
+        self.update_stat(interrupts);
+    }
+
+    fn next_line(&mut self, interrupts: &mut Interrupts) {
+        self.ly += 1;
+
+        if self.ly == VBLANK_LINE {
+            self.mode = Mode::VBlank;
+            self.window_line = 0;
+            self.frame_ready = true;
+            interrupts.request(Interrupt::VBlank);
+        } else if self.ly > LAST_LINE {
+            self.ly = 0;
+            self.mode = Mode::OamScan;
+        } else if self.ly < VBLANK_LINE {
+            self.mode = Mode::OamScan;
+        }
+    }
+
+    fn update_stat(&mut self, interrupts: &mut Interrupts) {
+        let line = (self.stat & 0x40 != 0 && self.ly == self.lyc) ||
+            (self.stat & 0x20 != 0 && self.mode == Mode::OamScan) ||
+            (self.stat & 0x10 != 0 && self.mode == Mode::VBlank) ||
+            (self.stat & 0x08 != 0 && self.mode == Mode::HBlank);
+
+        if line && !self.stat_line {
+            interrupts.request(Interrupt::LcdStat);
+        }
+
+        self.stat_line = line;
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF40 => self.lcdc,
+            0xFF41 => {
+                let coincidence = ((self.ly == self.lyc) as u8) << 2;
+
+                // Mode bits read as 0 while the LCD is off
+                let mode = if self.lcdc & 0x80 != 0 { self.mode as u8 } else { 0 };
+
+                0x80 | self.stat | coincidence | mode
+            }
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            _ => 0xFF
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF40 => {
+                let was_on = self.lcdc & 0x80 != 0;
+                self.lcdc = value;
+
+                if was_on && value & 0x80 == 0 {
+                    // Turning the LCD off resets it to the top of the screen
+                    self.ly = 0;
+                    self.dot = 0;
+                    self.window_line = 0;
+                    self.mode = Mode::HBlank;
+                } else if !was_on && value & 0x80 != 0 {
+                    self.mode = Mode::OamScan;
+                }
+            }
+            // Only the interrupt source selection is writable
+            0xFF41 => self.stat = value & 0x78,
+            0xFF42 => self.scy = value,
+            0xFF43 => self.scx = value,
+            // LY is read only
+            0xFF44 => {}
+            0xFF45 => self.lyc = value,
+            0xFF47 => self.bgp = value,
+            0xFF48 => self.obp0 = value,
+            0xFF49 => self.obp1 = value,
+            0xFF4A => self.wy = value,
+            0xFF4B => self.wx = value,
+            _ => {}
+        }
+    }
+
+    fn render_line(&mut self, vram: &[u8], oam: &[u8]) {
+        // Color indices before palette translation, needed to resolve the
+        // priority of sprites against the background
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+
+        // On DMG, LCDC bit 0 blanks both background and window
+        if self.lcdc & 0x01 != 0 {
+            let map = if self.lcdc & 0x08 != 0 { 0x1C00 } else { 0x1800 };
+            let y = self.ly.wrapping_add(self.scy);
+
+            for (x, color) in bg_colors.iter_mut().enumerate() {
+                let bg_x = (x as u8).wrapping_add(self.scx);
+                *color = self.tile_pixel(vram, map, bg_x, y);
+            }
+
+            if self.lcdc & 0x20 != 0 && self.ly >= self.wy && self.wx <= 166 {
+                let map = if self.lcdc & 0x40 != 0 { 0x1C00 } else { 0x1800 };
+                let start = self.wx as i32 - 7;
+
+                for x in start.max(0)..SCREEN_WIDTH as i32 {
+                    let window_x = (x - start) as u8;
+                    bg_colors[x as usize] = self.tile_pixel(vram, map, window_x, self.window_line);
+                }
+
+                self.window_line += 1;
+            }
+        }
+
+        let row = self.ly as usize * SCREEN_WIDTH;
+
+        for (pixel, &color) in self.frame[row..row + SCREEN_WIDTH].iter_mut().zip(bg_colors.iter()) {
+            *pixel = shade(self.bgp, color);
+        }
+
+        if self.lcdc & 0x02 != 0 {
+            self.render_sprites(vram, oam, &bg_colors);
+        }
+    }
+
+    // Color index of a background or window pixel from the given tile map
+    fn tile_pixel(&self, vram: &[u8], map: usize, x: u8, y: u8) -> u8 {
+        let tile = vram[map + (y as usize / 8) * 32 + x as usize / 8];
+
+        // LCDC bit 4 picks unsigned indices from 0x8000 or signed ones
+        // around 0x9000
+        let tile_address = if self.lcdc & 0x10 != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + tile as i8 as i32 * 16) as usize
+        };
+
+        let line = tile_address + (y as usize % 8) * 2;
+        color_index(vram[line], vram[line + 1], x % 8)
+    }
+
+    // OAM scan: indices of the first ten sprites overlapping the current
+    // line, ordered by priority. On DMG the sprite with the lower X wins and
+    // ties go to the lower OAM index; the sort is stable so OAM order
+    // survives.
+    fn scan_oam(&self, oam: &[u8]) -> Vec<usize> {
+        let height = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
+        let ly = self.ly as i32;
+        let mut sprites = Vec::with_capacity(MAX_SPRITES_PER_LINE);
+
+        for index in 0..40 {
+            let y = oam[index * 4] as i32 - 16;
+
+            if ly >= y && ly < y + height {
+                sprites.push(index);
+
+                if sprites.len() == MAX_SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+
+        sprites.sort_by_key(|&index| oam[index * 4 + 1]);
+        sprites
+    }
+
+    fn render_sprites(&mut self, vram: &[u8], oam: &[u8], bg_colors: &[u8; SCREEN_WIDTH]) {
+        let height = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
+        let ly = self.ly as i32;
+        let sprites = self.scan_oam(oam);
+
+        let mut drawn = [false; SCREEN_WIDTH];
+        let row = self.ly as usize * SCREEN_WIDTH;
+
+        for &index in sprites.iter() {
+            let entry = &oam[index * 4..index * 4 + 4];
+            let x = entry[1] as i32 - 8;
+            let attributes = entry[3];
+
+            let mut line = ly - (entry[0] as i32 - 16);
+
+            if attributes & 0x40 != 0 {
+                line = height - 1 - line;
+            }
+
+            // 8x16 sprites ignore bit 0 of the tile index
+            let tile = if height == 16 { entry[2] & 0xFE } else { entry[2] };
+            let address = tile as usize * 16 + line as usize * 2;
+            let palette = if attributes & 0x10 != 0 { self.obp1 } else { self.obp0 };
+
+            for column in 0..8 {
+                let screen_x = x + column;
+
+                if screen_x < 0 || screen_x >= SCREEN_WIDTH as i32 || drawn[screen_x as usize] {
+                    continue;
+                }
+
+                let bit = if attributes & 0x20 != 0 { 7 - column } else { column };
+                let color = color_index(vram[address], vram[address + 1], bit as u8);
+
+                // Color 0 is transparent and lets lower priority sprites
+                // through
+                if color == 0 {
+                    continue;
+                }
+
+                drawn[screen_x as usize] = true;
+
+                // A sprite behind the background only shows over color 0
+                if attributes & 0x80 != 0 && bg_colors[screen_x as usize] != 0 {
+                    continue;
+                }
+
+                self.frame[row + screen_x as usize] = shade(palette, color);
+            }
+        }
+    }
+}
+
+// Color index of the pixel in column x (0 is leftmost) of a tile row
+fn color_index(lo: u8, hi: u8, x: u8) -> u8 {
+    let shift = 7 - x;
+    ((hi >> shift) & 0x1) << 1 | ((lo >> shift) & 0x1)
+}
+
+fn shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0x3
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::{Interrupt, Interrupts};
+
+    use super::{Mode, Ppu, Renderer, SCREEN_WIDTH};
+
+    // M-cycles in a line
+    const LINE_CYCLES : u32 = 114;
+
+    fn run(ppu: &mut Ppu, vram: &[u8], oam: &[u8], interrupts: &mut Interrupts, cycles: u32) {
+        for _ in 0..cycles {
+            ppu.tick(vram, oam, interrupts);
+        }
+    }
+
+    fn requested(interrupts: &Interrupts, interrupt: Interrupt) -> bool {
+        interrupts.read_flags() & interrupt.mask() != 0
+    }
+
+    // Tile 1 is solid color 3, tile 2 solid color 1
+    fn vram() -> Vec<u8> {
+        let mut vram = vec![0; 0x2000];
+
+        for row in 0..8 {
+            vram[16 + row * 2] = 0xFF;
+            vram[16 + row * 2 + 1] = 0xFF;
+            vram[32 + row * 2] = 0xFF;
+        }
+
+        vram
+    }
+
+    fn sprite(oam: &mut [u8], index: usize, x: u8, attributes: u8) {
+        oam[index * 4] = 16;
+        oam[index * 4 + 1] = x;
+        oam[index * 4 + 2] = 1;
+        oam[index * 4 + 3] = attributes;
+    }
+
+    // Scanline PPU on line 0 with 8x8 sprites on
+    fn ppu() -> Ppu {
+        let mut ppu = Ppu::new(Renderer::Scanline);
+        ppu.write(0xFF40, 0x93);
+        ppu
+    }
+
+    #[test]
+    fn modes_follow_each_other_with_fixed_timing() {
+        let (vram, oam) = (vram(), [0; 0xA0]);
+        let mut ppu = ppu();
+        let mut interrupts = Interrupts::new();
+
+        // 80 dots of OAM scan, 172 of drawing and the rest of 456 in HBlank
+        run(&mut ppu, &vram, &oam, &mut interrupts, 19);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+        run(&mut ppu, &vram, &oam, &mut interrupts, 1);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        run(&mut ppu, &vram, &oam, &mut interrupts, 42);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        run(&mut ppu, &vram, &oam, &mut interrupts, 1);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        run(&mut ppu, &vram, &oam, &mut interrupts, 50);
+        assert_eq!((ppu.mode(), ppu.read(0xFF44)), (Mode::HBlank, 0));
+        run(&mut ppu, &vram, &oam, &mut interrupts, 1);
+        assert_eq!((ppu.mode(), ppu.read(0xFF44)), (Mode::OamScan, 1));
+        assert_eq!(ppu.read(0xFF41) & 0x03, Mode::OamScan as u8);
+
+        run(&mut ppu, &vram, &oam, &mut interrupts, 143 * LINE_CYCLES);
+        assert_eq!((ppu.mode(), ppu.read(0xFF44)), (Mode::VBlank, 144));
+
+        // Ten lines of VBlank, then the next frame starts
+        run(&mut ppu, &vram, &oam, &mut interrupts, 9 * LINE_CYCLES);
+        assert_eq!((ppu.mode(), ppu.read(0xFF44)), (Mode::VBlank, 153));
+        run(&mut ppu, &vram, &oam, &mut interrupts, LINE_CYCLES);
+        assert_eq!((ppu.mode(), ppu.read(0xFF44)), (Mode::OamScan, 0));
+    }
+
+    #[test]
+    fn vblank_interrupt_fires_when_line_144_starts() {
+        let (vram, oam) = (vram(), [0; 0xA0]);
+        let mut ppu = ppu();
+        let mut interrupts = Interrupts::new();
+
+        run(&mut ppu, &vram, &oam, &mut interrupts, 144 * LINE_CYCLES - 1);
+        assert!(!requested(&interrupts, Interrupt::VBlank));
+        assert!(!ppu.frame_finished());
+
+        run(&mut ppu, &vram, &oam, &mut interrupts, 1);
+        assert!(requested(&interrupts, Interrupt::VBlank));
+        assert!(ppu.frame_finished());
+        assert!(!ppu.frame_finished());
+    }
+
+    #[test]
+    fn ly_matching_lyc_sets_the_coincidence_flag_and_interrupt() {
+        let (vram, oam) = (vram(), [0; 0xA0]);
+        let mut ppu = ppu();
+        let mut interrupts = Interrupts::new();
+        ppu.write(0xFF45, 2);
+        ppu.write(0xFF41, 0x40);
+
+        run(&mut ppu, &vram, &oam, &mut interrupts, 2 * LINE_CYCLES - 1);
+        assert_eq!(ppu.read(0xFF41) & 0x04, 0);
+        assert!(!requested(&interrupts, Interrupt::LcdStat));
+
+        run(&mut ppu, &vram, &oam, &mut interrupts, 1);
+        assert_eq!(ppu.read(0xFF44), 2);
+        assert_eq!(ppu.read(0xFF41) & 0x04, 0x04);
+        assert!(requested(&interrupts, Interrupt::LcdStat));
+
+        // No second interrupt while LY stays on LYC
+        interrupts.acknowledge(Interrupt::LcdStat);
+        run(&mut ppu, &vram, &oam, &mut interrupts, LINE_CYCLES - 1);
+        assert!(!requested(&interrupts, Interrupt::LcdStat));
+
+        run(&mut ppu, &vram, &oam, &mut interrupts, 1);
+        assert_eq!(ppu.read(0xFF41) & 0x04, 0);
+    }
+
+    #[test]
+    fn stat_interrupt_only_fires_on_a_rising_edge() {
+        let (vram, oam) = (vram(), [0; 0xA0]);
+        let mut ppu = ppu();
+        let mut interrupts = Interrupts::new();
+
+        // HBlank and OAM scan sources: the line stays high from HBlank into
+        // the OAM scan of the next line
+        ppu.write(0xFF41, 0x28);
+        run(&mut ppu, &vram, &oam, &mut interrupts, 1);
+        assert!(requested(&interrupts, Interrupt::LcdStat));
+        interrupts.acknowledge(Interrupt::LcdStat);
+
+        run(&mut ppu, &vram, &oam, &mut interrupts, 62);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        assert!(requested(&interrupts, Interrupt::LcdStat));
+        interrupts.acknowledge(Interrupt::LcdStat);
+
+        run(&mut ppu, &vram, &oam, &mut interrupts, 51);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+        assert!(!requested(&interrupts, Interrupt::LcdStat));
+
+        // Drawing drops the line, so the next HBlank raises it again
+        run(&mut ppu, &vram, &oam, &mut interrupts, 63);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        assert!(requested(&interrupts, Interrupt::LcdStat));
+    }
+
+    #[test]
+    fn window_line_only_advances_on_lines_showing_the_window() {
+        let (mut vram, oam) = (vram(), [0; 0xA0]);
+        let mut ppu = ppu();
+        let mut interrupts = Interrupts::new();
+
+        // Window from 0x9C00 covering the screen, made of a tile with only
+        // its second row drawn
+        for tile in vram[0x1C00..0x2000].iter_mut() {
+            *tile = 3;
+        }
+
+        vram[48 + 2] = 0xFF;
+        vram[48 + 3] = 0xFF;
+
+        ppu.write(0xFF4B, 7);
+        ppu.write(0xFF40, 0xF1);
+        run(&mut ppu, &vram, &oam, &mut interrupts, LINE_CYCLES);
+
+        ppu.write(0xFF40, 0xD1);
+        run(&mut ppu, &vram, &oam, &mut interrupts, 2 * LINE_CYCLES);
+
+        ppu.write(0xFF40, 0xF1);
+        run(&mut ppu, &vram, &oam, &mut interrupts, LINE_CYCLES);
+
+        // Line 3 draws the second window row, not the fourth
+        let frame = ppu.frame();
+        assert_eq!(frame[0], 0);
+        assert_eq!(frame[SCREEN_WIDTH], 0);
+        assert_eq!(frame[3 * SCREEN_WIDTH], 3);
+        assert_eq!(frame[4 * SCREEN_WIDTH - 1], 3);
+    }
+
+    #[test]
+    fn sprite_with_lower_x_wins_and_ties_go_to_lower_oam_index() {
+        let (vram, mut oam) = (vram(), [0; 0xA0]);
+        let mut ppu = ppu();
+        let mut interrupts = Interrupts::new();
+
+        // OBP0 draws color 3 as black and OBP1 as light gray
+        ppu.write(0xFF48, 0xC0);
+        ppu.write(0xFF49, 0x40);
+
+        sprite(&mut oam, 0, 20, 0x10);
+        sprite(&mut oam, 1, 16, 0x00);
+        sprite(&mut oam, 2, 40, 0x10);
+        sprite(&mut oam, 3, 40, 0x00);
+        run(&mut ppu, &vram, &oam, &mut interrupts, LINE_CYCLES);
+
+        let frame = ppu.frame();
+        assert_eq!(frame[8], 3);
+        assert_eq!(frame[12], 3);
+        assert_eq!(frame[16], 1);
+        assert_eq!(frame[32], 1);
+    }
+
+    #[test]
+    fn only_ten_sprites_are_drawn_per_line() {
+        let (vram, mut oam) = (vram(), [0; 0xA0]);
+        let mut ppu = ppu();
+        let mut interrupts = Interrupts::new();
+        ppu.write(0xFF48, 0xC0);
+
+        for index in 0..11 {
+            sprite(&mut oam, index, 8 + index as u8 * 8, 0x00);
+        }
+
+        run(&mut ppu, &vram, &oam, &mut interrupts, LINE_CYCLES);
+
+        let frame = ppu.frame();
+        assert_eq!(frame[72], 3);
+        assert_eq!(frame[80], 0);
+    }
+
+    #[test]
+    fn background_priority_hides_sprites_except_over_color_0() {
+        let (mut vram, mut oam) = (vram(), [0; 0xA0]);
+        let mut ppu = ppu();
+        let mut interrupts = Interrupts::new();
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF48, 0xE4);
+
+        // The first and twelfth background tiles are color 1, the rest
+        // color 0
+        vram[0x1800] = 2;
+        vram[0x1800 + 11] = 2;
+
+        // Behind the background across the edge of the first tile, and in
+        // front of it over the twelfth
+        sprite(&mut oam, 0, 12, 0x80);
+        sprite(&mut oam, 1, 96, 0x00);
+        run(&mut ppu, &vram, &oam, &mut interrupts, LINE_CYCLES);
+
+        let frame = ppu.frame();
+        assert_eq!(frame[4], 1);
+        assert_eq!(frame[8], 3);
+        assert_eq!(frame[88], 3);
+    }
+}
@@ -0,0 +1,96 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// Why a ROM image could not be loaded or failed a header check
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    // Shorter than the 0x150 bytes up to the end of the header
+    TooSmall,
+    BadLogo,
+    BadHeaderChecksum,
+    BadGlobalChecksum,
+    // Cartridge type byte at 0x147 that names no known controller
+    UnknownMapper(u8),
+    // The file is not as large as the ROM size byte at 0x148 says
+    SizeMismatch
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::Io(ref err) => write!(f, "could not read the ROM: {}", err),
+            RomError::TooSmall => write!(f, "the file is too small to hold a cartridge header"),
+            RomError::BadLogo => write!(f, "the Nintendo logo in the header is wrong"),
+            RomError::BadHeaderChecksum => write!(f, "the header checksum does not match"),
+            RomError::BadGlobalChecksum => write!(f, "the global checksum does not match"),
+            RomError::UnknownMapper(code) => write!(f, "unknown cartridge type 0x{:02X}", code),
+            RomError::SizeMismatch => write!(f, "the ROM size in the header does not match the file")
+        }
+    }
+}
+
+impl Error for RomError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            RomError::Io(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(err: io::Error) -> Self {
+        RomError::Io(err)
+    }
+}
+
+// Why a GBS music file could not be loaded or played
+#[derive(Debug)]
+pub enum GbsError {
+    Io(io::Error),
+    // Shorter than the 0x70 byte header
+    TooSmall,
+    // Does not start with "GBS"
+    BadMagic,
+    UnsupportedVersion(u8),
+    // The data would not start in the ROM area after the RST vectors
+    BadLoadAddress(u16),
+    // The routine at this address hit an illegal opcode
+    Locked(u16),
+    // The routine at this address halted with no interrupt enabled
+    Halted(u16),
+    // The routine at this address did not return within CALL_LIMIT
+    Timeout(u16)
+}
+
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GbsError::Io(ref err) => write!(f, "could not read the GBS file: {}", err),
+            GbsError::TooSmall => write!(f, "the file is too small to hold a GBS header"),
+            GbsError::BadMagic => write!(f, "the file is not a GBS file"),
+            GbsError::UnsupportedVersion(version) => write!(f, "unsupported GBS version {}", version),
+            GbsError::BadLoadAddress(address) => write!(f, "invalid GBS load address 0x{:04X}", address),
+            GbsError::Locked(address) => write!(f, "the routine at 0x{:04X} ran into an illegal opcode", address),
+            GbsError::Halted(address) => write!(f, "the routine at 0x{:04X} halted with no interrupt enabled", address),
+            GbsError::Timeout(address) => write!(f, "the routine at 0x{:04X} never returned", address)
+        }
+    }
+}
+
+impl Error for GbsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            GbsError::Io(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for GbsError {
+    fn from(err: io::Error) -> Self {
+        GbsError::Io(err)
+    }
+}
@@ -0,0 +1,403 @@
+use std::fs;
+
+use cpu::{Cpu, Mmu};
+use ppu::Renderer;
+
+use super::Mapper;
+use super::error::GbsError;
+use super::mapper::{StateError, StateReader, StateWriter};
+
+const HEADER_SIZE : usize = 0x70;
+
+// Routines are called with this address pushed as their return address and
+// run until PC gets there. Nothing can execute from the unusable area, so it
+// never shows up otherwise.
+const RETURN_ADDRESS : u16 = 0xFEA0;
+
+// M-cycles a single init or play call may take before it is given up on
+const CALL_LIMIT : u64 = 1048576;
+
+const VBLANK_PERIOD : u64 = 17556;
+
+// M-cycles per TIMA increment for each TAC clock select
+static TIMER_PERIODS : [u64; 4] = [256, 4, 16, 64];
+
+// GBS music file: a sound driver ripped out of a game, with the addresses
+// of its init and play routines
+pub struct Gbs {
+    pub song_count : u8,
+    // 1 based, like the songs shown to the user
+    pub first_song : u8,
+    pub load_address : u16,
+    pub init_address : u16,
+    pub play_address : u16,
+    pub stack_pointer : u16,
+    pub timer_modulo : u8,
+    pub timer_control : u8,
+    pub title : String,
+    pub author : String,
+    pub copyright : String,
+    data : Vec<u8>
+}
+
+impl Gbs<> {
+    pub fn new_from_file(path: &str) -> Result<Self, GbsError> {
+        let bytes = fs::read(path)?;
+
+        if bytes.len() < HEADER_SIZE {
+            return Err(GbsError::TooSmall);
+        }
+
+        if &bytes[0..3] != b"GBS" {
+            return Err(GbsError::BadMagic);
+        }
+
+        if bytes[0x03] != 1 {
+            return Err(GbsError::UnsupportedVersion(bytes[0x03]));
+        }
+
+        let word = |offset: usize| bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8;
+
+        let gbs = Gbs {
+            song_count: bytes[0x04],
+            first_song: bytes[0x05],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: bytes[0x0E],
+            timer_control: bytes[0x0F],
+            title: text(&bytes[0x10..0x30]),
+            author: text(&bytes[0x30..0x50]),
+            copyright: text(&bytes[0x50..0x70]),
+            data: bytes[HEADER_SIZE..].to_vec()
+        };
+
+        if gbs.load_address < 0x0400 || gbs.load_address >= 0x8000 {
+            return Err(GbsError::BadLoadAddress(gbs.load_address));
+        }
+
+        Ok(gbs)
+    }
+
+    // M-cycles between two calls of the play routine: the timer overflow
+    // rate when TAC enables the timer, the frame rate otherwise
+    pub fn play_period(&self) -> u64 {
+        if self.timer_control & 0x04 != 0 {
+            let period = TIMER_PERIODS[(self.timer_control & 0x03) as usize];
+            period * (256 - self.timer_modulo as u64)
+        } else {
+            VBLANK_PERIOD
+        }
+    }
+
+    // ROM image with the data at its load address, split in 16 KiB banks
+    fn image(&self) -> Vec<u8> {
+        let end = self.load_address as usize + self.data.len();
+        let size = ((end + 0x3FFF) & !0x3FFF).max(0x8000);
+
+        let mut image = vec![0; size];
+        image[self.load_address as usize..end].copy_from_slice(&self.data);
+
+        // RST instructions jump to their vector relative to the load
+        // address, interrupts simply return
+        for vector in 0..8 {
+            let target = self.load_address + vector * 8;
+            let at = vector as usize * 8;
+
+            image[at] = 0xC3;
+            image[at + 1] = target as u8;
+            image[at + 2] = (target >> 8) as u8;
+        }
+
+        for vector in 0..5 {
+            image[0x40 + vector * 8] = 0xD9;
+        }
+
+        image
+    }
+
+    // Machine with the driver loaded and the given song (0 based) set up by
+    // the init routine, plus the player that calls the play routine on it
+    pub fn start(&self, song: u8) -> Result<(Cpu, GbsPlayer), GbsError> {
+        // Nothing is displayed, so the fastest renderer will do
+        let mapper = GbsMapper::new(self.image());
+        let mut cpu = Cpu::new(Mmu::with_mapper(Box::new(mapper), Renderer::Scanline));
+
+        cpu.mmu.write_byte(0xFF06, self.timer_modulo);
+        cpu.mmu.write_byte(0xFF07, self.timer_control & 0x07);
+
+        // Sound on, every channel to both sides at full volume
+        cpu.mmu.write_byte(0xFF26, 0x80);
+        cpu.mmu.write_byte(0xFF25, 0xFF);
+        cpu.mmu.write_byte(0xFF24, 0x77);
+
+        cpu.registers.sp.write(self.stack_pointer);
+        cpu.registers.set_a(song);
+        call(&mut cpu, self.init_address)?;
+
+        let player = GbsPlayer {
+            play_address: self.play_address,
+            period: self.play_period(),
+            until_play: 0
+        };
+
+        Ok((cpu, player))
+    }
+}
+
+// Minimal memory map for the driver: 16 KiB ROM banks selected through
+// 0x2000 - 0x3FFF like on an MBC1, and 8 KiB of RAM that is always enabled
+struct GbsMapper {
+    rom : Vec<u8>,
+    ram : Vec<u8>,
+    bank : usize
+}
+
+impl GbsMapper<> {
+    fn new(image: Vec<u8>) -> Self {
+        GbsMapper {
+            rom: image,
+            ram: vec![0; 0x2000],
+            bank: 1
+        }
+    }
+}
+
+impl Mapper for GbsMapper {
+    fn read_rom(&self, address: u16) -> u8 {
+        let index = if address < 0x4000 {
+            address as usize
+        } else {
+            self.bank * 0x4000 + (address - 0x4000) as usize
+        };
+
+        match self.rom.get(index) {
+            Some(&value) => value,
+            None => 0xFF
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        if (0x2000..0x4000).contains(&address) {
+            self.bank = if value == 0 { 1 } else { value as usize };
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        self.ram[(address - 0xA000) as usize]
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        self.ram[(address - 0xA000) as usize] = value;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(b"GBS ");
+        state.put_block(&self.ram);
+        state.put_u8(self.bank as u8);
+        state.finish()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, b"GBS ")?;
+        let ram = state.block(self.ram.len())?;
+        let bank = state.u8()?;
+        state.finish()?;
+
+        self.ram.copy_from_slice(ram);
+        self.bank = bank as usize;
+
+        Ok(())
+    }
+}
+
+pub struct GbsPlayer {
+    play_address : u16,
+    period : u64,
+    until_play : u64
+}
+
+impl GbsPlayer<> {
+    // Runs the play routine when it is due and idles the machine for one
+    // M-cycle otherwise. Returns the M-cycles that went by.
+    pub fn step(&mut self, cpu: &mut Cpu) -> Result<u32, GbsError> {
+        if self.until_play == 0 {
+            let cycles = call(cpu, self.play_address)?;
+            self.until_play = self.period.saturating_sub(cycles);
+            return Ok(cycles as u32);
+        }
+
+        cpu.mmu.tick(1);
+        self.until_play -= 1;
+        Ok(1)
+    }
+}
+
+// Calls the routine at address and runs it until it returns. A CPU that
+// can never run again is reported right away instead of idling until the
+// limit.
+fn call(cpu: &mut Cpu, address: u16) -> Result<u64, GbsError> {
+    let sp = cpu.registers.sp.push(2);
+    cpu.mmu.write_word(sp, RETURN_ADDRESS);
+    cpu.registers.pc.write(address);
+
+    let mut cycles = 0;
+
+    while cpu.registers.pc.read() != RETURN_ADDRESS {
+        if cpu.is_locked() {
+            return Err(GbsError::Locked(address));
+        }
+
+        if cpu.is_halted() && cpu.mmu.interrupts.read_enable() & 0x1F == 0 {
+            return Err(GbsError::Halted(address));
+        }
+
+        if cycles >= CALL_LIMIT {
+            return Err(GbsError::Timeout(address));
+        }
+
+        cycles += cpu.step() as u64;
+    }
+
+    Ok(cycles)
+}
+
+// NUL padded header string
+fn text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use rom::error::GbsError;
+
+    use super::{Gbs, HEADER_SIZE};
+
+    // Driver at 0x0400 whose init routine is the given code
+    fn driver(code: &[u8]) -> Gbs {
+        Gbs {
+            song_count: 1,
+            first_song: 1,
+            load_address: 0x0400,
+            init_address: 0x0400,
+            play_address: 0x0400,
+            stack_pointer: 0xFFFE,
+            timer_modulo: 0,
+            timer_control: 0,
+            title: String::new(),
+            author: String::new(),
+            copyright: String::new(),
+            data: code.to_vec()
+        }
+    }
+
+    fn started(code: &[u8]) -> Result<(), GbsError> {
+        driver(code).start(0).map(|_| ())
+    }
+
+    // Header of a driver loaded at 0x0400 followed by a single RET
+    fn header() -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_SIZE + 1];
+        bytes[0..4].copy_from_slice(b"GBS\x01");
+        bytes[0x04] = 2;
+        bytes[0x05] = 1;
+        bytes[0x07] = 0x04;
+        bytes[0x09] = 0x04;
+        bytes[0x0B] = 0x04;
+        bytes[0x0C] = 0xFE;
+        bytes[0x0D] = 0xFF;
+        bytes[0x10..0x15].copy_from_slice(b"Title");
+        bytes[HEADER_SIZE] = 0xC9;
+        bytes
+    }
+
+    fn load(name: &str, bytes: &[u8]) -> Result<Gbs, GbsError> {
+        let path = env::temp_dir().join(format!("gba-sim-{}-{}.gbs", name, process::id()));
+        fs::write(&path, bytes).unwrap();
+
+        let gbs = Gbs::new_from_file(&path.to_string_lossy());
+        fs::remove_file(&path).unwrap();
+        gbs
+    }
+
+    #[test]
+    fn header_fields_are_read() {
+        let gbs = load("valid", &header()).unwrap();
+
+        assert_eq!(gbs.song_count, 2);
+        assert_eq!(gbs.load_address, 0x0400);
+        assert_eq!(gbs.stack_pointer, 0xFFFE);
+        assert_eq!(gbs.title, "Title");
+    }
+
+    #[test]
+    fn broken_files_are_refused() {
+        let missing = env::temp_dir().join("gba-sim-missing.gbs");
+        match Gbs::new_from_file(&missing.to_string_lossy()) {
+            Err(GbsError::Io(_)) => {}
+            _ => panic!("a missing file should fail to open")
+        }
+
+        match load("small", &header()[..0x20]) {
+            Err(GbsError::TooSmall) => {}
+            _ => panic!("a truncated header should be refused")
+        }
+
+        let mut bytes = header();
+        bytes[0] = b'N';
+        match load("magic", &bytes) {
+            Err(GbsError::BadMagic) => {}
+            _ => panic!("a file without the GBS magic should be refused")
+        }
+
+        let mut bytes = header();
+        bytes[0x03] = 2;
+        match load("version", &bytes) {
+            Err(GbsError::UnsupportedVersion(2)) => {}
+            _ => panic!("version 2 should be refused")
+        }
+
+        let mut bytes = header();
+        bytes[0x07] = 0x80;
+        match load("address", &bytes) {
+            Err(GbsError::BadLoadAddress(0x8000)) => {}
+            _ => panic!("a load address outside of the ROM should be refused")
+        }
+    }
+
+    #[test]
+    fn returning_routines_are_played() {
+        // LD A, 1; RET
+        let (mut cpu, mut player) = driver(&[0x3E, 0x01, 0xC9]).start(0).unwrap();
+
+        assert_eq!(cpu.registers.a(), 1);
+        assert!(player.step(&mut cpu).unwrap() > 0);
+    }
+
+    #[test]
+    fn stuck_routines_are_reported() {
+        // DI; HALT with nothing in IE
+        match started(&[0xF3, 0x76]) {
+            Err(GbsError::Halted(0x0400)) => {}
+            _ => panic!("halting for good should be reported")
+        }
+
+        // Illegal opcode
+        match started(&[0xD3]) {
+            Err(GbsError::Locked(0x0400)) => {}
+            _ => panic!("an illegal opcode should be reported")
+        }
+
+        // JR to itself
+        match started(&[0x18, 0xFE]) {
+            Err(GbsError::Timeout(0x0400)) => {}
+            _ => panic!("an endless loop should be reported")
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use super::{Mapper, StateError, StateReader, StateWriter, read, write};
+
+mod source;
+
+pub use self::source::{ImageSource, NoiseSource, PngSource, IMAGE_WIDTH, IMAGE_HEIGHT};
+
+// Sensor registers, banked in at 0xA000 - 0xA07F (mirrored every 0x80
+// bytes) by a RAM bank with bit 4 set
+const REGISTER_COUNT : usize = 0x36;
+const SHOOT : usize = 0x00;
+const GAIN_AND_EDGE : usize = 0x01;
+const EXPOSURE_HIGH : usize = 0x02;
+const EXPOSURE_LOW : usize = 0x03;
+const RATIO_AND_INVERT : usize = 0x04;
+// 4x4 matrix of three thresholds each, one per shade of the output
+const DITHER_MATRIX : usize = 0x06;
+
+// Only the capture bits of the first register can be read back
+const SHOOT_MASK : u8 = 0x07;
+const SHOOT_START : u8 = 0x01;
+
+// Register 0x01: bits 0 - 4 set the gain, bits 5 - 6 the directions edges
+// are enhanced in and bit 7 turns off the exclusive edge mode
+const GAIN_MASK : u8 = 0x1F;
+const EDGE_HORIZONTAL : u8 = 0x20;
+const EDGE_VERTICAL : u8 = 0x40;
+const EDGE_EXCLUSIVE_OFF : u8 = 0x80;
+
+// Register 0x04: bit 3 inverts the image, bits 4 - 6 pick the edge ratio
+const INVERT : u8 = 0x08;
+static EDGE_RATIOS : [f32; 8] = [0.5, 0.75, 1.0, 1.25, 2.0, 3.0, 4.0, 5.0];
+
+// Exposure time at which the image source's brightness comes out as is,
+// in units of 16 us
+const NEUTRAL_EXPOSURE : f32 = 4096.0;
+
+// Gain code 0 is about 14 dB and every step adds 1.5 dB
+const GAIN_STEP_DB : f32 = 1.5;
+
+// A capture takes about 32448 M-cycles plus 16 per exposure unit, and 512
+// more unless the exclusive edge mode is off
+const CAPTURE_CYCLES : u32 = 32448;
+const EXCLUSIVE_EDGE_CYCLES : u32 = 512;
+const CYCLES_PER_EXPOSURE : u32 = 16;
+
+// Finished captures land in RAM bank 0 as 16x14 tiles in the usual 2 bits
+// per pixel format
+const IMAGE_OFFSET : usize = 0x0100;
+
+// Nintendo's MAC-GBD as used by the Game Boy Camera: up to 1 MiB of ROM,
+// 128 KiB of battery backed RAM and a Mitsubishi M64282FP image sensor.
+// The sensor's picture comes from an ImageSource, random noise unless the
+// frontend sets one. The output offset and reference voltage in registers
+// 0x04 - 0x05 are not emulated.
+pub struct PocketCamera {
+    rom : Vec<u8>,
+    ram : Vec<u8>,
+    ram_enabled : bool,
+    rom_bank : u8,
+    // 0x00 - 0x0F select a RAM bank, 0x10 and up the sensor registers
+    ram_bank : u8,
+    registers : [u8; REGISTER_COUNT],
+    // M-cycles left until the capture in progress is written to RAM
+    capture_cycles : u32,
+    source : Box<dyn ImageSource>
+}
+
+impl PocketCamera<> {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+        PocketCamera {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            registers: [0; REGISTER_COUNT],
+            capture_cycles: 0,
+            source: Box::new(NoiseSource::new())
+        }
+    }
+
+    fn ram_index(&self, address: u16) -> usize {
+        let index = (self.ram_bank as usize & 0x0F) * 0x2000 + (address - 0xA000) as usize;
+        index % self.ram.len()
+    }
+
+    fn registers_mapped(&self) -> bool {
+        self.ram_bank & 0x10 != 0
+    }
+
+    fn exposure(&self) -> u16 {
+        (self.registers[EXPOSURE_HIGH] as u16) << 8 | self.registers[EXPOSURE_LOW] as u16
+    }
+
+    fn write_sensor(&mut self, register: usize, value: u8) {
+        if register >= REGISTER_COUNT {
+            return;
+        }
+
+        if register != SHOOT {
+            self.registers[register] = value;
+            return;
+        }
+
+        // A capture in progress can be cancelled but not restarted
+        let capturing = self.registers[SHOOT] & SHOOT_START != 0;
+        self.registers[SHOOT] = value & SHOOT_MASK;
+
+        if value & SHOOT_START == 0 {
+            self.capture_cycles = 0;
+        } else if !capturing {
+            let mut cycles = CAPTURE_CYCLES + self.exposure() as u32 * CYCLES_PER_EXPOSURE;
+
+            if self.registers[GAIN_AND_EDGE] & EDGE_EXCLUSIVE_OFF == 0 {
+                cycles += EXCLUSIVE_EDGE_CYCLES;
+            }
+
+            self.capture_cycles = cycles;
+        }
+    }
+
+    // Runs the picture through the sensor settings and writes it to RAM
+    fn finish_capture(&mut self) {
+        self.registers[SHOOT] &= !SHOOT_START;
+
+        if self.ram.len() < IMAGE_OFFSET + IMAGE_WIDTH * IMAGE_HEIGHT / 4 {
+            return;
+        }
+
+        let mut image = self.source.capture();
+        image.resize(IMAGE_WIDTH * IMAGE_HEIGHT, 0);
+
+        let sensed = self.sense(&image);
+
+        for y in 0..IMAGE_HEIGHT {
+            for x in 0..IMAGE_WIDTH {
+                let shade = self.dither(x, y, self.enhance(&sensed, x, y));
+
+                let tile = (y / 8) * (IMAGE_WIDTH / 8) + x / 8;
+                let index = IMAGE_OFFSET + tile * 16 + (y % 8) * 2;
+                let bit = 0x80 >> (x % 8);
+
+                for plane in 0..2 {
+                    if shade >> plane & 0x01 != 0 {
+                        self.ram[index + plane] |= bit;
+                    } else {
+                        self.ram[index + plane] &= !bit;
+                    }
+                }
+            }
+        }
+    }
+
+    // Light collected by each cell, scaled by exposure time and gain
+    fn sense(&self, image: &[u8]) -> Vec<f32> {
+        let gain_code = (self.registers[GAIN_AND_EDGE] & GAIN_MASK) as f32;
+        let gain = 10f32.powf(gain_code * GAIN_STEP_DB / 20.0);
+        let scale = self.exposure() as f32 / NEUTRAL_EXPOSURE * gain;
+
+        image.iter().map(|&pixel| pixel as f32 * scale).collect()
+    }
+
+    // Adds the difference to the neighbours in the enabled directions,
+    // which sharpens edges. The border reuses the edge pixels.
+    fn enhance(&self, sensed: &[f32], x: usize, y: usize) -> f32 {
+        let pixel = |x: usize, y: usize| sensed[y * IMAGE_WIDTH + x];
+        let value = pixel(x, y);
+
+        let flags = self.registers[GAIN_AND_EDGE];
+        let ratio = EDGE_RATIOS[(self.registers[RATIO_AND_INVERT] >> 4) as usize & 0x07];
+        let mut edge = 0.0;
+
+        if flags & EDGE_HORIZONTAL != 0 {
+            let left = pixel(x.saturating_sub(1), y);
+            let right = pixel((x + 1).min(IMAGE_WIDTH - 1), y);
+            edge += 2.0 * value - left - right;
+        }
+
+        if flags & EDGE_VERTICAL != 0 {
+            let up = pixel(x, y.saturating_sub(1));
+            let down = pixel(x, (y + 1).min(IMAGE_HEIGHT - 1));
+            edge += 2.0 * value - up - down;
+        }
+
+        value + edge * ratio
+    }
+
+    // Compares the value with the matrix thresholds for its position and
+    // returns the shade, 0 for white to 3 for black
+    fn dither(&self, x: usize, y: usize, value: f32) -> u8 {
+        let mut value = value.clamp(0.0, 255.0) as u8;
+
+        if self.registers[RATIO_AND_INVERT] & INVERT != 0 {
+            value = 255 - value;
+        }
+
+        let thresholds = DITHER_MATRIX + ((y & 3) * 4 + (x & 3)) * 3;
+
+        if value < self.registers[thresholds] {
+            3
+        } else if value < self.registers[thresholds + 1] {
+            2
+        } else if value < self.registers[thresholds + 2] {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+impl Mapper for PocketCamera {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 { 0 } else { self.rom_bank as usize };
+        let banks = (self.rom.len() / 0x4000).max(1);
+
+        read(&self.rom, (bank % banks) * 0x4000 + (address & 0x3FFF) as usize)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = value & 0x3F,
+            0x4000..=0x5FFF => self.ram_bank = value & 0x1F,
+            _ => {}
+        }
+    }
+
+    // The RAM can be read while it is disabled, only writes are blocked
+    fn read_ram(&self, address: u16) -> u8 {
+        if self.registers_mapped() {
+            let register = (address & 0x7F) as usize;
+            return if register == SHOOT { self.registers[SHOOT] } else { 0x00 };
+        }
+
+        if self.ram.is_empty() {
+            return 0xFF;
+        }
+
+        read(&self.ram, self.ram_index(address))
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if self.registers_mapped() {
+            self.write_sensor((address & 0x7F) as usize, value);
+            return;
+        }
+
+        if !self.ram_enabled || self.ram.is_empty() {
+            return;
+        }
+
+        let index = self.ram_index(address);
+        write(&mut self.ram, index, value);
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.capture_cycles == 0 {
+            return;
+        }
+
+        if cycles < self.capture_cycles {
+            self.capture_cycles -= cycles;
+        } else {
+            self.capture_cycles = 0;
+            self.finish_capture();
+        }
+    }
+
+    fn battery(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
+
+    fn set_image_source(&mut self, source: Box<dyn ImageSource>) {
+        self.source = source;
+    }
+
+    // The image source is part of the frontend and not saved
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(b"CAM ");
+        state.put_block(&self.ram);
+        state.put_bool(self.ram_enabled);
+        state.put_u8(self.rom_bank);
+        state.put_u8(self.ram_bank);
+        state.put_block(&self.registers);
+        state.put_u32(self.capture_cycles);
+        state.finish()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, b"CAM ")?;
+        let ram = state.block(self.ram.len())?;
+        let ram_enabled = state.bool()?;
+        let rom_bank = state.u8()?;
+        let ram_bank = state.u8()?;
+        let registers = state.block(REGISTER_COUNT)?;
+        let capture_cycles = state.u32()?;
+        state.finish()?;
+
+        self.ram.copy_from_slice(ram);
+        self.ram_enabled = ram_enabled;
+        self.rom_bank = rom_bank;
+        self.ram_bank = ram_bank;
+        self.registers.copy_from_slice(registers);
+        self.capture_cycles = capture_cycles;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::BufWriter;
+
+    use png;
+
+    use super::{Mapper, PocketCamera, PngSource, IMAGE_WIDTH, IMAGE_HEIGHT};
+
+    // Left half black, right half white, at twice the sensor size
+    fn write_png(path: &str) {
+        let (width, height) = (IMAGE_WIDTH * 2, IMAGE_HEIGHT * 2);
+        let mut data = Vec::with_capacity(width * height * 3);
+
+        for _ in 0..height {
+            for x in 0..width {
+                let value = if x < width / 2 { 0x00 } else { 0xFF };
+                data.extend_from_slice(&[value, value, value]);
+            }
+        }
+
+        let file = BufWriter::new(File::create(path).unwrap());
+        let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&data).unwrap();
+    }
+
+    #[test]
+    fn png_capture_lands_in_ram_as_tiles() {
+        let path = env::temp_dir().join("gba-sim-camera-test.png").to_string_lossy().into_owned();
+        write_png(&path);
+
+        let source = PngSource::new_from_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        let mut camera = PocketCamera::new(vec![0; 0x8000], 0x20000);
+        camera.set_image_source(Box::new(source.unwrap()));
+
+        camera.write_register(0x0000, 0x0A);
+        camera.write_register(0x4000, 0x10);
+
+        // No edge enhancement, no gain and the neutral exposure of 0x1000,
+        // so the image reaches the dither matrix unchanged
+        camera.write_ram(0xA001, 0x80);
+        camera.write_ram(0xA002, 0x10);
+        camera.write_ram(0xA003, 0x00);
+
+        for cell in 0..16 {
+            camera.write_ram(0xA006 + cell * 3, 0x40);
+            camera.write_ram(0xA007 + cell * 3, 0x80);
+            camera.write_ram(0xA008 + cell * 3, 0xC0);
+        }
+
+        camera.write_ram(0xA000, 0x01);
+
+        let cycles = 32448 + 0x1000 * 16;
+        camera.tick(cycles - 1);
+        assert_eq!(camera.read_ram(0xA000), 0x01);
+        camera.tick(1);
+        assert_eq!(camera.read_ram(0xA000), 0x00);
+
+        camera.write_register(0x4000, 0x00);
+
+        // 16x14 tiles of 16 bytes; black reads as shade 3 in both planes
+        for tile in 0..IMAGE_WIDTH / 8 * IMAGE_HEIGHT / 8 {
+            let expected = if tile % 16 < 8 { 0xFF } else { 0x00 };
+
+            for offset in 0..16 {
+                let address = 0xA100 + (tile * 16 + offset) as u16;
+                assert_eq!(camera.read_ram(address), expected, "tile {} byte {}", tile, offset);
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use png;
+
+// Size of the picture the cartridge keeps, after the sensor's unused rows
+// are cut off
+pub const IMAGE_WIDTH : usize = 128;
+pub const IMAGE_HEIGHT : usize = 112;
+
+// Whatever the camera is pointed at. Called once per capture.
+pub trait ImageSource {
+    // Brightness of IMAGE_WIDTH x IMAGE_HEIGHT pixels row by row, 0 for
+    // black to 255 for white
+    fn capture(&mut self) -> Vec<u8>;
+}
+
+// Random grey noise, what the camera shows before a frontend gives it
+// anything to look at
+pub struct NoiseSource {
+    state : u32
+}
+
+impl NoiseSource<> {
+    pub fn new() -> Self {
+        NoiseSource {
+            state: 0x2545F491
+        }
+    }
+}
+
+impl ImageSource for NoiseSource {
+    fn capture(&mut self) -> Vec<u8> {
+        let mut image = Vec::with_capacity(IMAGE_WIDTH * IMAGE_HEIGHT);
+
+        for _ in 0..IMAGE_WIDTH * IMAGE_HEIGHT {
+            // xorshift32
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 17;
+            self.state ^= self.state << 5;
+
+            image.push(0x60 + (self.state >> 26) as u8);
+        }
+
+        image
+    }
+}
+
+// A still image loaded from a PNG file, turned to grey and scaled to the
+// sensor size. Every capture returns the same picture.
+pub struct PngSource {
+    image : Vec<u8>
+}
+
+impl PngSource<> {
+    pub fn new_from_file(path: &str) -> Result<Self, String> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) => return Err(format!("could not open {}: {}", path, err))
+        };
+
+        let mut decoder = png::Decoder::new(BufReader::new(file));
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+
+        let mut reader = match decoder.read_info() {
+            Ok(reader) => reader,
+            Err(err) => return Err(format!("{} is not a valid PNG: {}", path, err))
+        };
+
+        let mut data = vec![0; reader.output_buffer_size().unwrap_or(0)];
+
+        let info = match reader.next_frame(&mut data) {
+            Ok(info) => info,
+            Err(err) => return Err(format!("could not decode {}: {}", path, err))
+        };
+
+        let channels = info.color_type.samples();
+        let width = info.width as usize;
+        let height = info.height as usize;
+
+        let luma : Vec<u8> = data[..height * info.line_size].chunks(info.line_size).flat_map(|line| {
+            line[..width * channels].chunks(channels).map(|pixel| match channels {
+                1 | 2 => pixel[0],
+                _ => ((pixel[0] as u32 * 299 + pixel[1] as u32 * 587 + pixel[2] as u32 * 114) / 1000) as u8
+            })
+        }).collect();
+
+        Ok(PngSource {
+            image: scale(&luma, width, height)
+        })
+    }
+}
+
+impl ImageSource for PngSource {
+    fn capture(&mut self) -> Vec<u8> {
+        self.image.clone()
+    }
+}
+
+// Averages the pixels that fall into each sensor pixel, stretching the
+// image to fill the sensor
+fn scale(image: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut scaled = Vec::with_capacity(IMAGE_WIDTH * IMAGE_HEIGHT);
+
+    if width == 0 || height == 0 {
+        scaled.resize(IMAGE_WIDTH * IMAGE_HEIGHT, 0);
+        return scaled;
+    }
+
+    for y in 0..IMAGE_HEIGHT {
+        let top = y * height / IMAGE_HEIGHT;
+        let bottom = ((y + 1) * height / IMAGE_HEIGHT).max(top + 1);
+
+        for x in 0..IMAGE_WIDTH {
+            let left = x * width / IMAGE_WIDTH;
+            let right = ((x + 1) * width / IMAGE_WIDTH).max(left + 1);
+
+            let mut sum = 0;
+
+            for row in top..bottom {
+                for column in left..right {
+                    sum += image[row * width + column] as usize;
+                }
+            }
+
+            scaled.push((sum / ((bottom - top) * (right - left))) as u8);
+        }
+    }
+
+    scaled
+}
@@ -0,0 +1,160 @@
+use super::{Mapper, StateError, StateReader, StateWriter, read, write};
+
+// Hudson HuC-1: MBC1-like banking of up to 1 MiB of ROM and 32 KiB of RAM,
+// plus an infrared LED and receiver that take the place of the RAM when
+// selected
+pub struct Huc1 {
+    rom : Vec<u8>,
+    ram : Vec<u8>,
+    battery : bool,
+    // Writing 0x0E to 0x0000 - 0x1FFF switches 0xA000 - 0xBFFF to the IR
+    // port, anything else back to RAM. RAM cannot be disabled.
+    ir_mode : bool,
+    ir_led : bool,
+    rom_bank : u8,
+    ram_bank : u8
+}
+
+impl Huc1<> {
+    pub fn new(rom: Vec<u8>, ram_size: usize, battery: bool) -> Self {
+        Huc1 {
+            rom,
+            ram: vec![0; ram_size],
+            battery,
+            ir_mode: false,
+            ir_led: false,
+            rom_bank: 1,
+            ram_bank: 0
+        }
+    }
+
+    fn ram_index(&self, address: u16) -> usize {
+        let index = self.ram_bank as usize * 0x2000 + (address - 0xA000) as usize;
+        index % self.ram.len()
+    }
+}
+
+impl Mapper for Huc1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 { 0 } else { self.rom_bank as usize };
+        let banks = (self.rom.len() / 0x4000).max(1);
+
+        read(&self.rom, (bank % banks) * 0x4000 + (address & 0x3FFF) as usize)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ir_mode = value & 0x0F == 0x0E,
+            0x2000..=0x3FFF => {
+                self.rom_bank = value & 0x3F;
+
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        // No second cartridge is sending, so the receiver never sees light
+        if self.ir_mode {
+            return 0xC0;
+        }
+
+        if self.ram.is_empty() {
+            return 0xFF;
+        }
+
+        read(&self.ram, self.ram_index(address))
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if self.ir_mode {
+            self.ir_led = value & 0x01 != 0;
+            return;
+        }
+
+        if self.ram.is_empty() {
+            return;
+        }
+
+        let index = self.ram_index(address);
+        write(&mut self.ram, index, value);
+    }
+
+    fn battery(&self) -> Vec<u8> {
+        if self.battery { self.ram.clone() } else { Vec::new() }
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(b"HUC1");
+        state.put_block(&self.ram);
+        state.put_bool(self.ir_mode);
+        state.put_bool(self.ir_led);
+        state.put_u8(self.rom_bank);
+        state.put_u8(self.ram_bank);
+        state.finish()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, b"HUC1")?;
+        let ram = state.block(self.ram.len())?;
+        let ir_mode = state.bool()?;
+        let ir_led = state.bool()?;
+        let rom_bank = state.u8()?;
+        let ram_bank = state.u8()?;
+        state.finish()?;
+
+        self.ram.copy_from_slice(ram);
+        self.ir_mode = ir_mode;
+        self.ir_led = ir_led;
+        self.rom_bank = rom_bank;
+        self.ram_bank = ram_bank;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rom::mapper::{Mapper, rom};
+
+    use super::Huc1;
+
+    #[test]
+    fn ir_mode_takes_over_the_ram_window() {
+        let mut huc = Huc1::new(rom(4), 0x8000, true);
+
+        huc.write_register(0x4000, 0x02);
+        huc.write_ram(0xA000, 0x42);
+        assert_eq!(huc.read_ram(0xA000), 0x42);
+        assert_eq!(huc.battery()[0x4000], 0x42);
+
+        // The sensor reads as dark and writes go to the LED
+        huc.write_register(0x0000, 0x0E);
+        assert_eq!(huc.read_ram(0xA000), 0xC0);
+        huc.write_ram(0xA000, 0x01);
+        assert!(huc.ir_led);
+
+        huc.write_register(0x0000, 0x00);
+        assert_eq!(huc.read_ram(0xA000), 0x42);
+    }
+
+    #[test]
+    fn rom_bank_zero_selects_bank_one() {
+        let mut huc = Huc1::new(rom(8), 0, false);
+
+        huc.write_register(0x2000, 0x05);
+        assert_eq!(huc.read_rom(0x4000), 5);
+
+        huc.write_register(0x2000, 0x00);
+        assert_eq!(huc.read_rom(0x4000), 1);
+    }
+}
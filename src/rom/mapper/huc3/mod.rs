@@ -0,0 +1,417 @@
+use super::{Mapper, StateError, StateReader, StateWriter, read, write, now, CYCLES_PER_SECOND};
+
+// Minutes of the day and day counter, then a 64-bit UNIX timestamp, appended
+// to the RAM in save files
+const RTC_TRAILER_SIZE : usize = 12;
+
+const MINUTES_PER_DAY : u16 = 1440;
+const CYCLES_PER_MINUTE : u32 = CYCLES_PER_SECOND * 60;
+
+// Hudson HuC-3: up to 2 MiB of ROM and 32 KiB of RAM, with a clock, an
+// alarm speaker and an infrared port behind a command interface. The value
+// written to 0x0000 - 0x1FFF picks what 0xA000 - 0xBFFF talks to.
+pub struct Huc3 {
+    rom : Vec<u8>,
+    ram : Vec<u8>,
+    battery : bool,
+    mode : u8,
+    rom_bank : u8,
+    ram_bank : u8,
+    rtc : Rtc,
+    // Nibble of the clock memory the next command reads or writes: 0 - 2
+    // hold the minute of the day, 3 - 6 the day counter and the rest the
+    // alarm and other settings
+    access_index : u8,
+    memory : [u8; 0x100],
+    response : u8,
+    // Set by the extended command that asks whether the clock is ready
+    status_query : bool,
+    ir_led : bool
+}
+
+impl Huc3<> {
+    pub fn new(rom: Vec<u8>, ram_size: usize, battery: bool) -> Self {
+        Huc3 {
+            rom,
+            ram: vec![0; ram_size],
+            battery,
+            mode: 0,
+            rom_bank: 1,
+            ram_bank: 0,
+            rtc: Rtc::new(),
+            access_index: 0,
+            memory: [0; 0x100],
+            response: 0,
+            status_query: false,
+            ir_led: false
+        }
+    }
+
+    fn ram_index(&self, address: u16) -> usize {
+        let index = self.ram_bank as usize * 0x2000 + (address - 0xA000) as usize;
+        index % self.ram.len()
+    }
+
+    // Commands are written as a byte: the command in the upper nibble and
+    // its argument in the lower one
+    fn command(&mut self, value: u8) {
+        let argument = value & 0x0F;
+
+        match value >> 4 {
+            // Read the nibble at the access index and move on
+            0x1 => {
+                self.response = self.read_memory(self.access_index);
+                self.access_index = self.access_index.wrapping_add(1);
+            }
+            // Write the nibble at the access index; 0x3 also moves on
+            0x2 | 0x3 => {
+                let index = self.access_index;
+                self.write_memory(index, argument);
+
+                if value >> 4 == 0x3 {
+                    self.access_index = self.access_index.wrapping_add(1);
+                }
+            }
+            0x4 => self.access_index = (self.access_index & 0xF0) | argument,
+            0x5 => self.access_index = (self.access_index & 0x0F) | argument << 4,
+            // Extended commands. 0x2 asks for the clock status; the others
+            // play tones on the speaker, which is not emulated.
+            0x6 => self.status_query = argument == 0x2,
+            _ => {}
+        }
+    }
+
+    fn read_memory(&self, index: u8) -> u8 {
+        match index {
+            0..=2 => (self.rtc.minutes >> (index * 4)) as u8 & 0x0F,
+            3..=6 => (self.rtc.days >> ((index - 3) * 4)) as u8 & 0x0F,
+            _ => self.memory[index as usize]
+        }
+    }
+
+    fn write_memory(&mut self, index: u8, value: u8) {
+        match index {
+            0..=2 => {
+                let shift = index * 4;
+                self.rtc.minutes = (self.rtc.minutes & !(0x0F << shift)) | (value as u16) << shift;
+
+                // Twelve bits reach past the end of the day
+                self.rtc.minutes %= MINUTES_PER_DAY;
+            }
+            3..=6 => {
+                let shift = (index - 3) * 4;
+                self.rtc.days = (self.rtc.days & !(0x0F << shift)) | (value as u16) << shift;
+            }
+            _ => self.memory[index as usize] = value
+        }
+    }
+}
+
+impl Mapper for Huc3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 { 0 } else { self.rom_bank as usize };
+        let banks = (self.rom.len() / 0x4000).max(1);
+
+        read(&self.rom, (bank % banks) * 0x4000 + (address & 0x3FFF) as usize)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.mode = value & 0x0F,
+            0x2000..=0x3FFF => self.rom_bank = value & 0x7F,
+            0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        match self.mode {
+            // 0x0 maps the RAM read only, 0xA read and write
+            0x0 | 0xA if !self.ram.is_empty() => read(&self.ram, self.ram_index(address)),
+            0xC => if self.status_query { 0x01 } else { self.response },
+            // The clock is always ready to take a command
+            0xD => 0x01,
+            // No second cartridge is sending, so the receiver never sees
+            // light
+            0xE => 0xC0,
+            _ => 0xFF
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        match self.mode {
+            0xA if !self.ram.is_empty() => {
+                let index = self.ram_index(address);
+                write(&mut self.ram, index, value);
+            }
+            0xB => self.command(value),
+            0xE => self.ir_led = value & 0x01 != 0,
+            _ => {}
+        }
+    }
+
+    fn battery(&self) -> Vec<u8> {
+        if !self.battery {
+            return Vec::new();
+        }
+
+        let mut data = self.ram.clone();
+        data.push(self.rtc.minutes as u8);
+        data.push((self.rtc.minutes >> 8) as u8);
+        data.push(self.rtc.days as u8);
+        data.push((self.rtc.days >> 8) as u8);
+
+        let timestamp = now();
+
+        for i in 0..8 {
+            data.push((timestamp >> (i * 8)) as u8);
+        }
+
+        data
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+        self.ram[..size].copy_from_slice(&data[..size]);
+
+        let trailer = &data[size..];
+
+        if trailer.len() != RTC_TRAILER_SIZE {
+            return;
+        }
+
+        self.rtc.minutes = (trailer[0] as u16 | (trailer[1] as u16) << 8) % MINUTES_PER_DAY;
+        self.rtc.days = trailer[2] as u16 | (trailer[3] as u16) << 8;
+
+        let mut timestamp = 0;
+
+        for i in 0..8 {
+            timestamp |= (trailer[4 + i] as u64) << (i * 8);
+        }
+
+        // Time kept running while the game was not played. Seconds short of
+        // a full minute carry on in the cycle counter.
+        let elapsed = now().saturating_sub(timestamp);
+        self.rtc.cycles = (elapsed % 60) as u32 * CYCLES_PER_SECOND;
+        self.rtc.advance(elapsed / 60);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(b"HUC3");
+        state.put_block(&self.ram);
+        state.put_u8(self.mode);
+        state.put_u8(self.rom_bank);
+        state.put_u8(self.ram_bank);
+        state.put_u16(self.rtc.minutes);
+        state.put_u16(self.rtc.days);
+        state.put_u32(self.rtc.cycles);
+        state.put_u8(self.access_index);
+        state.put_block(&self.memory);
+        state.put_u8(self.response);
+        state.put_bool(self.status_query);
+        state.put_bool(self.ir_led);
+        state.finish()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, b"HUC3")?;
+        let ram = state.block(self.ram.len())?;
+        let mode = state.u8()?;
+        let rom_bank = state.u8()?;
+        let ram_bank = state.u8()?;
+        let minutes = state.u16()?;
+        let days = state.u16()?;
+        let cycles = state.u32()?;
+        let access_index = state.u8()?;
+        let memory = state.block(self.memory.len())?;
+        let response = state.u8()?;
+        let status_query = state.bool()?;
+        let ir_led = state.bool()?;
+        state.finish()?;
+
+        self.ram.copy_from_slice(ram);
+        self.mode = mode;
+        self.rom_bank = rom_bank;
+        self.ram_bank = ram_bank;
+        self.rtc.minutes = minutes % MINUTES_PER_DAY;
+        self.rtc.days = days;
+        self.rtc.cycles = cycles % CYCLES_PER_MINUTE;
+        self.access_index = access_index;
+        self.memory.copy_from_slice(memory);
+        self.response = response;
+        self.status_query = status_query;
+        self.ir_led = ir_led;
+
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.rtc.tick(cycles);
+    }
+}
+
+// The HuC-3 clock only counts whole minutes and days
+struct Rtc {
+    minutes : u16,
+    days : u16,
+    // M-cycles counted towards the next minute
+    cycles : u32
+}
+
+impl Rtc<> {
+    fn new() -> Self {
+        Rtc {
+            minutes: 0,
+            days: 0,
+            cycles: 0
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let cycles = self.cycles as u64 + cycles as u64;
+        self.cycles = (cycles % CYCLES_PER_MINUTE as u64) as u32;
+        self.advance(cycles / CYCLES_PER_MINUTE as u64);
+    }
+
+    fn advance(&mut self, elapsed: u64) {
+        let minutes = self.minutes as u64 + elapsed;
+        self.minutes = (minutes % MINUTES_PER_DAY as u64) as u16;
+        self.days = self.days.wrapping_add((minutes / MINUTES_PER_DAY as u64) as u16);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rom::mapper::{Mapper, now, rom};
+
+    use super::{Huc3, RTC_TRAILER_SIZE, MINUTES_PER_DAY, CYCLES_PER_MINUTE};
+
+    fn command(huc: &mut Huc3, value: u8) -> u8 {
+        huc.write_register(0x0000, 0x0B);
+        huc.write_ram(0xA000, value);
+        huc.write_register(0x0000, 0x0C);
+        huc.read_ram(0xA000)
+    }
+
+    #[test]
+    fn ram_is_only_writable_in_mode_a() {
+        let mut huc = Huc3::new(rom(4), 0x8000, true);
+
+        huc.write_register(0x0000, 0x00);
+        huc.write_ram(0xA000, 0x42);
+        assert_eq!(huc.read_ram(0xA000), 0x00);
+
+        huc.write_register(0x0000, 0x0A);
+        huc.write_ram(0xA000, 0x42);
+        huc.write_register(0x0000, 0x00);
+        assert_eq!(huc.read_ram(0xA000), 0x42);
+    }
+
+    #[test]
+    fn clock_is_read_and_written_a_nibble_at_a_time() {
+        let mut huc = Huc3::new(rom(4), 0, false);
+
+        // Access index 0, then write the three minute nibbles of 0x123
+        command(&mut huc, 0x40);
+        command(&mut huc, 0x50);
+        command(&mut huc, 0x33);
+        command(&mut huc, 0x32);
+        command(&mut huc, 0x31);
+
+        command(&mut huc, 0x40);
+        assert_eq!(command(&mut huc, 0x10), 0x03);
+        assert_eq!(command(&mut huc, 0x10), 0x02);
+        assert_eq!(command(&mut huc, 0x10), 0x01);
+    }
+
+    #[test]
+    fn minutes_past_the_end_of_the_day_wrap() {
+        let mut huc = Huc3::new(rom(4), 0, false);
+
+        // 0xFFF minutes
+        command(&mut huc, 0x40);
+        command(&mut huc, 0x50);
+        command(&mut huc, 0x3F);
+        command(&mut huc, 0x3F);
+        command(&mut huc, 0x3F);
+
+        assert!(huc.rtc.minutes < MINUTES_PER_DAY);
+    }
+
+    #[test]
+    fn clock_counts_emulated_minutes_into_days() {
+        let mut huc = Huc3::new(rom(4), 0, false);
+        huc.rtc.minutes = MINUTES_PER_DAY - 2;
+
+        huc.tick(CYCLES_PER_MINUTE - 1);
+        assert_eq!((huc.rtc.minutes, huc.rtc.days), (MINUTES_PER_DAY - 2, 0));
+
+        huc.tick(1);
+        assert_eq!((huc.rtc.minutes, huc.rtc.days), (MINUTES_PER_DAY - 1, 0));
+
+        huc.tick(CYCLES_PER_MINUTE);
+        assert_eq!((huc.rtc.minutes, huc.rtc.days), (0, 1));
+    }
+
+    #[test]
+    fn loading_catches_up_with_the_time_away() {
+        let mut data = vec![0; 0x2000];
+        data.extend_from_slice(&[0x10, 0x00, 0x02, 0x00]);
+
+        // Saved a day, an hour and a half minute ago
+        let timestamp = now() - (24 * 3600 + 3600 + 30);
+
+        for i in 0..8 {
+            data.push((timestamp >> (i * 8)) as u8);
+        }
+
+        let mut huc = Huc3::new(rom(4), 0x2000, true);
+        huc.load_battery(&data);
+        assert_eq!((huc.rtc.minutes, huc.rtc.days), (0x10 + 60, 3));
+
+        // The half minute is already counted
+        huc.tick(CYCLES_PER_MINUTE / 2);
+        assert_eq!(huc.rtc.minutes, 0x10 + 61);
+    }
+
+    #[test]
+    fn clock_status_reads_as_ready() {
+        let mut huc = Huc3::new(rom(4), 0, false);
+
+        assert_eq!(command(&mut huc, 0x62), 0x01);
+        huc.write_register(0x0000, 0x0D);
+        assert_eq!(huc.read_ram(0xA000), 0x01);
+    }
+
+    #[test]
+    fn battery_carries_the_clock_after_the_ram() {
+        let mut huc = Huc3::new(rom(4), 0x2000, true);
+        huc.write_register(0x0000, 0x0A);
+        huc.write_ram(0xA000, 0x42);
+
+        // Minute 0x123 of day 0x0045
+        command(&mut huc, 0x40);
+        command(&mut huc, 0x50);
+
+        for &nibble in [0x3, 0x2, 0x1, 0x5, 0x4, 0x0, 0x0].iter() {
+            command(&mut huc, 0x30 | nibble);
+        }
+
+        let battery = huc.battery();
+        assert_eq!(battery.len(), 0x2000 + RTC_TRAILER_SIZE);
+        assert_eq!(&battery[0x2000..0x2004], &[0x23, 0x01, 0x45, 0x00]);
+
+        let mut loaded = Huc3::new(rom(4), 0x2000, true);
+        loaded.load_battery(&battery);
+        assert_eq!(loaded.rtc.minutes, 0x123);
+        assert_eq!(loaded.rtc.days, 0x45);
+        assert_eq!(loaded.battery()[..0x2004], battery[..0x2004]);
+
+        // A trailer of the wrong size is ignored, the RAM still loads
+        let mut loaded = Huc3::new(rom(4), 0x2000, true);
+        loaded.load_battery(&battery[..battery.len() - 1]);
+        loaded.write_register(0x0000, 0x0A);
+        assert_eq!(loaded.read_ram(0xA000), 0x42);
+        assert_eq!((loaded.rtc.minutes, loaded.rtc.days), (0, 0));
+    }
+}
@@ -0,0 +1,267 @@
+use rom;
+
+use super::{Mapper, StateError, StateReader, StateWriter, read, write};
+
+const MULTICART_GAME_SIZE : usize = 0x40000;
+
+// MBC1: up to 2 MiB of ROM and 32 KiB of RAM. A 5-bit register picks the
+// ROM bank at 0x4000 and a 2-bit register either extends it or, in mode 1,
+// also banks 0x0000 and the RAM.
+pub struct Mbc1 {
+    rom : Vec<u8>,
+    ram : Vec<u8>,
+    battery : bool,
+    ram_enabled : bool,
+    bank1 : u8,
+    bank2 : u8,
+    mode : u8,
+    // MBC1M multicarts wire the upper register one bit lower, so each game
+    // sees 256 KiB
+    multicart : bool
+}
+
+impl Mbc1<> {
+    pub fn new(rom: Vec<u8>, ram_size: usize, battery: bool) -> Self {
+        let multicart = is_multicart(&rom);
+
+        Mbc1 {
+            rom,
+            ram: vec![0; ram_size],
+            battery,
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            mode: 0,
+            multicart
+        }
+    }
+
+    fn bank2_shift(&self) -> u8 {
+        if self.multicart { 4 } else { 5 }
+    }
+
+    fn rom_bank(&self, address: u16) -> usize {
+        let bank = if address < 0x4000 {
+            if self.mode == 1 { self.bank2 << self.bank2_shift() } else { 0 }
+        } else {
+            let low = if self.multicart { self.bank1 & 0x0F } else { self.bank1 };
+            self.bank2 << self.bank2_shift() | low
+        };
+
+        // Unconnected upper bank lines wrap around smaller ROMs
+        let banks = (self.rom.len() / 0x4000).max(1);
+        bank as usize % banks
+    }
+
+    fn ram_index(&self, address: u16) -> usize {
+        let bank = if self.mode == 1 { self.bank2 as usize } else { 0 };
+        let index = bank * 0x2000 + (address - 0xA000) as usize;
+
+        // 2 KiB chips are mirrored across the 8 KiB window
+        index % self.ram.len()
+    }
+}
+
+impl Mapper for Mbc1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let index = self.rom_bank(address) * 0x4000 + (address & 0x3FFF) as usize;
+        read(&self.rom, index)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // Bank 0 cannot be selected here; the check looks at all five
+                // bits, so 0x20, 0x40 and 0x60 turn into 0x21, 0x41 and 0x61
+                self.bank1 = value & 0x1F;
+
+                if self.bank1 == 0 {
+                    self.bank1 = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.bank2 = value & 0x03,
+            _ => self.mode = value & 0x01
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xFF;
+        }
+
+        read(&self.ram, self.ram_index(address))
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return;
+        }
+
+        let index = self.ram_index(address);
+        write(&mut self.ram, index, value);
+    }
+
+    fn battery(&self) -> Vec<u8> {
+        if self.battery { self.ram.clone() } else { Vec::new() }
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(b"MBC1");
+        state.put_block(&self.ram);
+        state.put_bool(self.ram_enabled);
+        state.put_u8(self.bank1);
+        state.put_u8(self.bank2);
+        state.put_u8(self.mode);
+        state.finish()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, b"MBC1")?;
+        let ram = state.block(self.ram.len())?;
+        let ram_enabled = state.bool()?;
+        let bank1 = state.u8()?;
+        let bank2 = state.u8()?;
+        let mode = state.u8()?;
+        state.finish()?;
+
+        self.ram.copy_from_slice(ram);
+        self.ram_enabled = ram_enabled;
+        self.bank1 = bank1;
+        self.bank2 = bank2;
+        self.mode = mode;
+
+        Ok(())
+    }
+}
+
+// MBC1M carts are 1 MiB compilations of 256 KiB games, each starting with
+// its own header. Plain MBC1 games of that size only have the one at 0x104.
+fn is_multicart(rom: &[u8]) -> bool {
+    if rom.len() != 4 * MULTICART_GAME_SIZE {
+        return false;
+    }
+
+    let logos = (0..4)
+        .filter(|&game| rom::has_logo(rom, game * MULTICART_GAME_SIZE + 0x104))
+        .count();
+
+    logos > 1
+}
+
+#[cfg(test)]
+mod tests {
+    use rom::NINTENDO_LOGO;
+    use rom::mapper::{Mapper, rom};
+
+    use super::{Mbc1, MULTICART_GAME_SIZE};
+
+    // Gives the game starting at offset a header logo
+    fn add_logo(rom: &mut [u8], offset: usize) {
+        rom[offset + 0x104..offset + 0x134].copy_from_slice(&NINTENDO_LOGO);
+    }
+
+    #[test]
+    fn bank_zero_selects_bank_one() {
+        let mut mbc = Mbc1::new(rom(4), 0, false);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+
+        mbc.write_register(0x2000, 3);
+        assert_eq!(mbc.read_rom(0x4000), 3);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+
+        mbc.write_register(0x2000, 0);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+    }
+
+    #[test]
+    fn upper_bits_extend_the_rom_bank() {
+        let mut mbc = Mbc1::new(rom(128), 0, false);
+
+        // Only the five lower bits are checked for zero
+        mbc.write_register(0x4000, 1);
+        mbc.write_register(0x2000, 0);
+        assert_eq!(mbc.read_rom(0x4000), 0x21);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+
+        // Mode 1 also applies them to 0x0000 - 0x3FFF
+        mbc.write_register(0x6000, 1);
+        assert_eq!(mbc.read_rom(0x0000), 0x20);
+    }
+
+    #[test]
+    fn small_roms_wrap_around() {
+        let mut mbc = Mbc1::new(rom(4), 0, false);
+
+        mbc.write_register(0x2000, 6);
+        assert_eq!(mbc.read_rom(0x4000), 2);
+    }
+
+    #[test]
+    fn ram_is_banked_in_mode_1_only() {
+        let mut mbc = Mbc1::new(rom(4), 0x8000, true);
+
+        // Disabled RAM reads as open bus and ignores writes
+        mbc.write_ram(0xA000, 1);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_register(0x4000, 2);
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.battery()[0], 0x42);
+
+        mbc.write_register(0x6000, 1);
+        mbc.write_ram(0xA000, 0x43);
+        assert_eq!(mbc.battery()[0x4000], 0x43);
+        assert_eq!(mbc.read_ram(0xA000), 0x43);
+
+        mbc.write_register(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn small_ram_is_mirrored() {
+        let mut mbc = Mbc1::new(rom(4), 0x800, false);
+        mbc.write_register(0x0000, 0x0A);
+
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.read_ram(0xA800), 0x42);
+        assert!(mbc.battery().is_empty());
+    }
+
+    #[test]
+    fn multicarts_bank_each_game_on_its_own() {
+        let mut data = rom(64);
+
+        for game in 0..4 {
+            add_logo(&mut data, game * MULTICART_GAME_SIZE);
+        }
+
+        let mut mbc = Mbc1::new(data, 0, false);
+
+        // The upper register selects the game, only four bits are left
+        // for the bank within it
+        mbc.write_register(0x4000, 1);
+        mbc.write_register(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(0x4000), 0x12);
+
+        mbc.write_register(0x6000, 1);
+        assert_eq!(mbc.read_rom(0x0000), 0x10);
+    }
+
+    #[test]
+    fn one_logo_is_not_a_multicart() {
+        let mut data = rom(64);
+        add_logo(&mut data, 0);
+
+        let mut mbc = Mbc1::new(data, 0, false);
+        mbc.write_register(0x4000, 1);
+        mbc.write_register(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(0x4000), 0x32);
+    }
+}
@@ -0,0 +1,151 @@
+use super::{Mapper, StateError, StateReader, StateWriter, read};
+
+const RAM_SIZE : usize = 512;
+
+// MBC2: up to 256 KiB of ROM and 512 4-bit cells of RAM inside the
+// controller itself. Both registers live at 0x0000 - 0x3FFF, told apart by
+// bit 8 of the address.
+pub struct Mbc2 {
+    rom : Vec<u8>,
+    ram : [u8; RAM_SIZE],
+    battery : bool,
+    ram_enabled : bool,
+    rom_bank : u8
+}
+
+impl Mbc2<> {
+    pub fn new(rom: Vec<u8>, battery: bool) -> Self {
+        Mbc2 {
+            rom,
+            ram: [0; RAM_SIZE],
+            battery,
+            ram_enabled: false,
+            rom_bank: 1
+        }
+    }
+}
+
+impl Mapper for Mbc2 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 { 0 } else { self.rom_bank as usize };
+        let banks = (self.rom.len() / 0x4000).max(1);
+
+        read(&self.rom, (bank % banks) * 0x4000 + (address & 0x3FFF) as usize)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        if address >= 0x4000 {
+            return;
+        }
+
+        if address & 0x0100 == 0 {
+            self.ram_enabled = value & 0x0F == 0x0A;
+        } else {
+            self.rom_bank = value & 0x0F;
+
+            if self.rom_bank == 0 {
+                self.rom_bank = 1;
+            }
+        }
+    }
+
+    // Only nine address lines reach the RAM, so it repeats through the
+    // whole window. The missing upper nibble reads as set.
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+
+        0xF0 | self.ram[address as usize % RAM_SIZE]
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if self.ram_enabled {
+            self.ram[address as usize % RAM_SIZE] = value & 0x0F;
+        }
+    }
+
+    // One byte per cell, as other emulators store it
+    fn battery(&self) -> Vec<u8> {
+        if self.battery { self.ram.to_vec() } else { Vec::new() }
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        for (cell, &value) in self.ram.iter_mut().zip(data.iter()) {
+            *cell = value & 0x0F;
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(b"MBC2");
+        state.put_block(&self.ram);
+        state.put_bool(self.ram_enabled);
+        state.put_u8(self.rom_bank);
+        state.finish()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, b"MBC2")?;
+        let ram = state.block(RAM_SIZE)?;
+        let ram_enabled = state.bool()?;
+        let rom_bank = state.u8()?;
+        state.finish()?;
+
+        self.ram.copy_from_slice(ram);
+        self.ram_enabled = ram_enabled;
+        self.rom_bank = rom_bank;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rom::mapper::{Mapper, rom};
+
+    use super::{Mbc2, RAM_SIZE};
+
+    #[test]
+    fn address_bit_8_picks_the_register() {
+        let mut mbc = Mbc2::new(rom(16), false);
+
+        // Bit 8 clear: RAM enable, even at 0x2000 and up
+        mbc.write_register(0x2000, 0x0A);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_ram(0xA000, 0x05);
+        assert_eq!(mbc.read_ram(0xA000), 0xF5);
+
+        // Bit 8 set: ROM bank, even below 0x2000
+        mbc.write_register(0x0100, 0x0F);
+        assert_eq!(mbc.read_rom(0x4000), 0x0F);
+
+        mbc.write_register(0x0100, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+    }
+
+    #[test]
+    fn ram_keeps_four_bits_and_repeats() {
+        let mut mbc = Mbc2::new(rom(2), true);
+        mbc.write_register(0x0000, 0x0A);
+
+        mbc.write_ram(0xA001, 0xAB);
+        assert_eq!(mbc.read_ram(0xA001), 0xFB);
+        assert_eq!(mbc.read_ram(0xA001 + RAM_SIZE as u16), 0xFB);
+        assert_eq!(mbc.read_ram(0xBE01), 0xFB);
+
+        let battery = mbc.battery();
+        assert_eq!(battery.len(), RAM_SIZE);
+        assert_eq!(battery[1], 0x0B);
+
+        mbc.write_register(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xA001), 0xFF);
+    }
+
+    #[test]
+    fn loaded_saves_lose_the_upper_nibble() {
+        let mut mbc = Mbc2::new(rom(2), true);
+        mbc.load_battery(&[0xFF; RAM_SIZE]);
+
+        assert!(mbc.battery().iter().all(|&cell| cell == 0x0F));
+    }
+}
@@ -0,0 +1,493 @@
+use super::{Mapper, StateError, StateReader, StateWriter, read, write, now, CYCLES_PER_SECOND};
+
+// Size of the clock state other emulators append to the save file: live and
+// latched registers as ten 32-bit words, then a 64-bit UNIX timestamp.
+// Older files have a 32-bit timestamp instead.
+const RTC_TRAILER_SIZE : usize = 48;
+const RTC_TRAILER_SIZE_32 : usize = 44;
+
+// Day counter high register: bit 0 is bit 8 of the day, bit 6 stops the
+// clock and bit 7 records that the counter overflowed
+const DAY_HIGH : usize = 4;
+const HALT : u8 = 0x40;
+const DAY_CARRY : u8 = 0x80;
+
+// Bits that exist in the seconds, minutes, hours, day low and day high
+// registers
+static RTC_MASKS : [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+
+// MBC3: up to 2 MiB of ROM, 32 KiB of RAM and an optional real time clock
+// whose registers are banked in place of the RAM
+pub struct Mbc3 {
+    rom : Vec<u8>,
+    ram : Vec<u8>,
+    battery : bool,
+    rtc : Option<Rtc>,
+    ram_enabled : bool,
+    rom_bank : u8,
+    // 0x00 - 0x07 select a RAM bank, 0x08 - 0x0C a clock register
+    ram_bank : u8,
+    // Latching happens on a 0x00 write followed by a 0x01 write
+    latch_armed : bool
+}
+
+impl Mbc3<> {
+    pub fn new(rom: Vec<u8>, ram_size: usize, timer: bool, battery: bool) -> Self {
+        Mbc3 {
+            rom,
+            ram: vec![0; ram_size],
+            battery,
+            rtc: if timer { Some(Rtc::new()) } else { None },
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            latch_armed: false
+        }
+    }
+
+    fn ram_index(&self, address: u16) -> usize {
+        let index = (self.ram_bank as usize & 0x07) * 0x2000 + (address - 0xA000) as usize;
+        index % self.ram.len()
+    }
+}
+
+impl Mapper for Mbc3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 { 0 } else { self.rom_bank as usize };
+        let banks = (self.rom.len() / 0x4000).max(1);
+
+        read(&self.rom, (bank % banks) * 0x4000 + (address & 0x3FFF) as usize)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = value & 0x7F;
+
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            _ => {
+                if self.latch_armed && value == 0x01 {
+                    if let Some(ref mut rtc) = self.rtc {
+                        rtc.latch();
+                    }
+                }
+
+                self.latch_armed = value == 0x00;
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+
+        match self.ram_bank {
+            0x00..=0x07 if !self.ram.is_empty() => read(&self.ram, self.ram_index(address)),
+            0x08..=0x0C => match self.rtc {
+                Some(ref rtc) => rtc.read(self.ram_bank as usize - 0x08),
+                None => 0xFF
+            },
+            _ => 0xFF
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+
+        match self.ram_bank {
+            0x00..=0x07 if !self.ram.is_empty() => {
+                let index = self.ram_index(address);
+                write(&mut self.ram, index, value);
+            }
+            0x08..=0x0C => {
+                if let Some(ref mut rtc) = self.rtc {
+                    rtc.write(self.ram_bank as usize - 0x08, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn battery(&self) -> Vec<u8> {
+        if !self.battery {
+            return Vec::new();
+        }
+
+        let mut data = self.ram.clone();
+
+        if let Some(ref rtc) = self.rtc {
+            data.extend_from_slice(&rtc.trailer());
+        }
+
+        data
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+        self.ram[..size].copy_from_slice(&data[..size]);
+
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.load_trailer(&data[size..]);
+        }
+    }
+
+    // Unlike the save file, a state holds the clock exactly as it was, down
+    // to the cycles of the current second
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(b"MBC3");
+        state.put_block(&self.ram);
+        state.put_bool(self.ram_enabled);
+        state.put_u8(self.rom_bank);
+        state.put_u8(self.ram_bank);
+        state.put_bool(self.latch_armed);
+
+        if let Some(ref rtc) = self.rtc {
+            state.put_block(&rtc.live);
+            state.put_block(&rtc.latched);
+            state.put_u32(rtc.cycles);
+        }
+
+        state.finish()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, b"MBC3")?;
+        let ram = state.block(self.ram.len())?;
+        let ram_enabled = state.bool()?;
+        let rom_bank = state.u8()?;
+        let ram_bank = state.u8()?;
+        let latch_armed = state.bool()?;
+
+        let clock = match self.rtc {
+            Some(_) => Some((state.block(5)?, state.block(5)?, state.u32()?)),
+            None => None
+        };
+
+        state.finish()?;
+
+        self.ram.copy_from_slice(ram);
+        self.ram_enabled = ram_enabled;
+        self.rom_bank = rom_bank;
+        self.ram_bank = ram_bank;
+        self.latch_armed = latch_armed;
+
+        if let (Some(ref mut rtc), Some((live, latched, cycles))) = (self.rtc.as_mut(), clock) {
+            for i in 0..5 {
+                rtc.live[i] = live[i] & RTC_MASKS[i];
+                rtc.latched[i] = latched[i] & RTC_MASKS[i];
+            }
+
+            rtc.cycles = cycles % CYCLES_PER_SECOND;
+        }
+
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+}
+
+struct Rtc {
+    // Seconds, minutes, hours, day low and day high
+    live : [u8; 5],
+    latched : [u8; 5],
+    // M-cycles counted towards the next second
+    cycles : u32
+}
+
+impl Rtc<> {
+    fn new() -> Self {
+        Rtc {
+            live: [0; 5],
+            latched: [0; 5],
+            cycles: 0
+        }
+    }
+
+    fn read(&self, register: usize) -> u8 {
+        self.latched[register] | !RTC_MASKS[register]
+    }
+
+    fn write(&mut self, register: usize, value: u8) {
+        // Writing the seconds also restarts the second in progress
+        if register == 0 {
+            self.cycles = 0;
+        }
+
+        self.live[register] = value & RTC_MASKS[register];
+        self.latched[register] = self.live[register];
+    }
+
+    fn latch(&mut self) {
+        self.latched = self.live;
+    }
+
+    fn halted(&self) -> bool {
+        self.live[DAY_HIGH] & HALT != 0
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.halted() {
+            return;
+        }
+
+        let cycles = self.cycles as u64 + cycles as u64;
+        self.cycles = (cycles % CYCLES_PER_SECOND as u64) as u32;
+        self.advance(cycles / CYCLES_PER_SECOND as u64);
+    }
+
+    fn advance(&mut self, elapsed: u64) {
+        let seconds = self.live[0] as u64 + elapsed;
+        let minutes = self.live[1] as u64 + seconds / 60;
+        let hours = self.live[2] as u64 + minutes / 60;
+        let mut days = self.day() as u64 + hours / 24;
+
+        let mut high = self.live[DAY_HIGH] & (HALT | DAY_CARRY);
+
+        if days > 0x1FF {
+            high |= DAY_CARRY;
+            days &= 0x1FF;
+        }
+
+        self.live[0] = (seconds % 60) as u8;
+        self.live[1] = (minutes % 60) as u8;
+        self.live[2] = (hours % 24) as u8;
+        self.live[3] = days as u8;
+        self.live[DAY_HIGH] = high | (days >> 8) as u8;
+    }
+
+    fn day(&self) -> u16 {
+        (self.live[DAY_HIGH] as u16 & 0x01) << 8 | self.live[3] as u16
+    }
+
+    // Registers as of now, so loading the file can tell how long it was
+    // away
+    fn trailer(&self) -> [u8; RTC_TRAILER_SIZE] {
+        let mut trailer = [0; RTC_TRAILER_SIZE];
+        let registers = self.live.iter().chain(self.latched.iter());
+
+        for (i, &register) in registers.enumerate() {
+            trailer[i * 4] = register;
+        }
+
+        let timestamp = now();
+
+        for i in 0..8 {
+            trailer[40 + i] = (timestamp >> (i * 8)) as u8;
+        }
+
+        trailer
+    }
+
+    // A save without a clock trailer leaves the clock as it is
+    fn load_trailer(&mut self, trailer: &[u8]) {
+        if trailer.len() != RTC_TRAILER_SIZE && trailer.len() != RTC_TRAILER_SIZE_32 {
+            return;
+        }
+
+        for i in 0..5 {
+            self.live[i] = trailer[i * 4] & RTC_MASKS[i];
+            self.latched[i] = trailer[20 + i * 4] & RTC_MASKS[i];
+        }
+
+        let mut timestamp = 0;
+
+        for i in 0..trailer.len() - 40 {
+            timestamp |= (trailer[40 + i] as u64) << (i * 8);
+        }
+
+        // Time kept running while the game was not played
+        if !self.halted() {
+            self.advance(now().saturating_sub(timestamp));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rom::mapper::{Mapper, now, rom, CYCLES_PER_SECOND};
+
+    use super::{Mbc3, Rtc, RTC_TRAILER_SIZE, DAY_HIGH, HALT, DAY_CARRY};
+
+    // Clock of an MBC3 with 8 KiB of RAM, ready to be read and written
+    fn mbc() -> Mbc3 {
+        let mut mbc = Mbc3::new(rom(4), 0x2000, true, true);
+        mbc.write_register(0x0000, 0x0A);
+        mbc
+    }
+
+    fn trailer(live: [u8; 5], latched: [u8; 5], timestamp: u64, size: usize) -> Vec<u8> {
+        let mut trailer = vec![0; size];
+
+        for i in 0..5 {
+            trailer[i * 4] = live[i];
+            trailer[20 + i * 4] = latched[i];
+        }
+
+        for i in 0..size - 40 {
+            trailer[40 + i] = (timestamp >> (i * 8)) as u8;
+        }
+
+        trailer
+    }
+
+    #[test]
+    fn rom_bank_takes_seven_bits() {
+        let mut mbc = Mbc3::new(rom(128), 0, false, false);
+
+        mbc.write_register(0x2000, 0x7F);
+        assert_eq!(mbc.read_rom(0x4000), 0x7F);
+
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x01);
+    }
+
+    #[test]
+    fn ram_and_clock_share_the_window() {
+        let mut mbc = Mbc3::new(rom(4), 0x8000, true, true);
+        mbc.write_register(0x0000, 0x0A);
+
+        mbc.write_register(0x4000, 0x03);
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.battery()[0x6000], 0x42);
+
+        // Minutes register, which only has six bits
+        mbc.write_register(0x4000, 0x09);
+        mbc.write_ram(0xA000, 0xFB);
+        assert_eq!(mbc.read_ram(0xA000), 0xFB);
+        assert_eq!(mbc.battery()[0x6000], 0x42);
+        assert_eq!(mbc.battery()[0x8000 + 4], 0x3B);
+    }
+
+    #[test]
+    fn reads_see_the_latched_clock() {
+        let mut mbc = mbc();
+        mbc.write_register(0x4000, 0x08);
+        mbc.write_ram(0xA000, 10);
+
+        mbc.rtc.as_mut().unwrap().live[0] = 20;
+        assert_eq!(mbc.read_ram(0xA000), 10 | 0xC0);
+
+        // Only a 0x00 write followed by 0x01 latches
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 10 | 0xC0);
+
+        mbc.write_register(0x6000, 0x00);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 20 | 0xC0);
+    }
+
+    #[test]
+    fn clock_counts_emulated_seconds() {
+        let mut mbc = mbc();
+        mbc.write_register(0x4000, 0x08);
+
+        mbc.tick(CYCLES_PER_SECOND - 1);
+        mbc.write_register(0x6000, 0x00);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 0xC0);
+
+        mbc.tick(1);
+        mbc.write_register(0x6000, 0x00);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 1 | 0xC0);
+
+        // Writing the seconds starts a new second
+        mbc.tick(CYCLES_PER_SECOND - 1);
+        mbc.write_ram(0xA000, 30);
+        mbc.tick(1);
+        mbc.write_register(0x6000, 0x00);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 30 | 0xC0);
+    }
+
+    #[test]
+    fn clock_carries_into_the_day_counter() {
+        let mut rtc = Rtc::new();
+        rtc.live = [59, 59, 23, 0xFF, 0x00];
+
+        rtc.advance(1);
+        assert_eq!(rtc.live, [0, 0, 0, 0x00, 0x01]);
+
+        rtc.live = [59, 59, 23, 0xFF, 0x01];
+        rtc.advance(1);
+        assert_eq!(rtc.live, [0, 0, 0, 0x00, DAY_CARRY]);
+    }
+
+    #[test]
+    fn halted_clock_does_not_advance() {
+        let mut rtc = Rtc::new();
+        rtc.live[DAY_HIGH] = HALT;
+
+        rtc.tick(CYCLES_PER_SECOND * 1000);
+        assert_eq!(rtc.live, [0, 0, 0, 0, HALT]);
+        assert_eq!(rtc.cycles, 0);
+    }
+
+    #[test]
+    fn battery_ends_with_a_48_byte_trailer() {
+        let mut mbc = mbc();
+        mbc.write_register(0x4000, 0x0A);
+        mbc.write_ram(0xA000, 5);
+
+        let data = mbc.battery();
+        assert_eq!(data.len(), 0x2000 + RTC_TRAILER_SIZE);
+
+        let trailer = &data[0x2000..];
+        assert_eq!(trailer[8], 5);
+        assert_eq!(trailer[28], 5);
+
+        let mut timestamp = 0;
+        for i in 0..8 {
+            timestamp |= (trailer[40 + i] as u64) << (i * 8);
+        }
+        assert!(now() - timestamp <= 1);
+    }
+
+    #[test]
+    fn loading_catches_up_with_the_time_away() {
+        // Saved a day, an hour and a minute ago with the clock at zero
+        let away = 24 * 3600 + 3600 + 60;
+
+        for &size in [48, 44].iter() {
+            let mut data = vec![0; 0x2000];
+            data.extend(trailer([0; 5], [1, 2, 3, 4, 0], now() - away, size));
+
+            let mut mbc = mbc();
+            mbc.load_battery(&data);
+
+            let rtc = mbc.rtc.as_ref().unwrap();
+            assert_eq!(&rtc.live[1..], &[1, 1, 1, 0]);
+            assert_eq!(rtc.latched, [1, 2, 3, 4, 0]);
+        }
+
+        // A halted clock stays where it was
+        let mut data = vec![0; 0x2000];
+        data.extend(trailer([0, 0, 0, 0, HALT], [0; 5], now() - away, 48));
+
+        let mut mbc = mbc();
+        mbc.load_battery(&data);
+        assert_eq!(mbc.rtc.as_ref().unwrap().live, [0, 0, 0, 0, HALT]);
+    }
+
+    #[test]
+    fn missing_trailer_keeps_the_clock() {
+        let mut mbc = mbc();
+        mbc.write_register(0x4000, 0x0A);
+        mbc.write_ram(0xA000, 7);
+
+        mbc.load_battery(&[0; 0x2000]);
+        assert_eq!(mbc.read_ram(0xA000) & 0x1F, 7);
+    }
+}
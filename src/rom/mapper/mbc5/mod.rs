@@ -0,0 +1,215 @@
+use super::{Mapper, StateError, StateReader, StateWriter, read, write};
+
+// MBC5: up to 8 MiB of ROM through a 9-bit bank number and 128 KiB of RAM
+// in 16 banks. Rumble carts take bit 3 of the RAM bank register for the
+// motor, leaving them 8 banks.
+pub struct Mbc5 {
+    rom : Vec<u8>,
+    ram : Vec<u8>,
+    battery : bool,
+    rumble : bool,
+    ram_enabled : bool,
+    // Unlike older controllers, bank 0 can be mapped at 0x4000 too
+    rom_bank : u16,
+    ram_bank : u8,
+    motor : bool,
+    rumble_callback : Option<Box<dyn FnMut(bool)>>
+}
+
+impl Mbc5<> {
+    pub fn new(rom: Vec<u8>, ram_size: usize, battery: bool, rumble: bool) -> Self {
+        Mbc5 {
+            rom,
+            ram: vec![0; ram_size],
+            battery,
+            rumble,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            motor: false,
+            rumble_callback: None
+        }
+    }
+
+    fn ram_index(&self, address: u16) -> usize {
+        let index = self.ram_bank as usize * 0x2000 + (address - 0xA000) as usize;
+        index % self.ram.len()
+    }
+}
+
+impl Mapper for Mbc5 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 { 0 } else { self.rom_bank as usize };
+        let banks = (self.rom.len() / 0x4000).max(1);
+
+        read(&self.rom, (bank % banks) * 0x4000 + (address & 0x3FFF) as usize)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            // Only the exact value enables RAM on MBC5
+            0x0000..=0x1FFF => self.ram_enabled = value == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | (value as u16 & 0x01) << 8,
+            0x4000..=0x5FFF => {
+                if self.rumble {
+                    self.ram_bank = value & 0x07;
+
+                    let motor = value & 0x08 != 0;
+
+                    if motor != self.motor {
+                        self.motor = motor;
+
+                        if let Some(ref mut callback) = self.rumble_callback {
+                            callback(motor);
+                        }
+                    }
+                } else {
+                    self.ram_bank = value & 0x0F;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xFF;
+        }
+
+        read(&self.ram, self.ram_index(address))
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return;
+        }
+
+        let index = self.ram_index(address);
+        write(&mut self.ram, index, value);
+    }
+
+    fn battery(&self) -> Vec<u8> {
+        if self.battery { self.ram.clone() } else { Vec::new() }
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
+
+    fn on_rumble(&mut self, callback: Box<dyn FnMut(bool)>) {
+        self.rumble_callback = Some(callback);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(b"MBC5");
+        state.put_block(&self.ram);
+        state.put_bool(self.ram_enabled);
+        state.put_u16(self.rom_bank);
+        state.put_u8(self.ram_bank);
+        state.put_bool(self.motor);
+        state.finish()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, b"MBC5")?;
+        let ram = state.block(self.ram.len())?;
+        let ram_enabled = state.bool()?;
+        let rom_bank = state.u16()?;
+        let ram_bank = state.u8()?;
+        let motor = state.bool()?;
+        state.finish()?;
+
+        self.ram.copy_from_slice(ram);
+        self.ram_enabled = ram_enabled;
+        self.rom_bank = rom_bank & 0x1FF;
+        self.ram_bank = ram_bank;
+
+        if motor != self.motor {
+            self.motor = motor;
+
+            if let Some(ref mut callback) = self.rumble_callback {
+                callback(motor);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use rom::mapper::{Mapper, rom};
+
+    use super::Mbc5;
+
+    #[test]
+    fn rom_bank_takes_nine_bits() {
+        // 512 banks; the low byte of each is its number modulo 256
+        let mut mbc = Mbc5::new(rom(512), 0, false, false);
+
+        mbc.write_register(0x2000, 0x05);
+        mbc.write_register(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x05);
+        assert_eq!(mbc.rom_bank, 0x105);
+
+        mbc.write_register(0x2000, 0xFF);
+        assert_eq!(mbc.rom_bank, 0x1FF);
+
+        mbc.write_register(0x3000, 0x00);
+        assert_eq!(mbc.rom_bank, 0x0FF);
+    }
+
+    #[test]
+    fn bank_zero_can_be_mapped_high() {
+        let mut mbc = Mbc5::new(rom(4), 0, false, false);
+
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0);
+    }
+
+    #[test]
+    fn only_0x0a_enables_ram() {
+        let mut mbc = Mbc5::new(rom(4), 0x2000, false, false);
+
+        mbc.write_register(0x0000, 0x1A);
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.read_ram(0xA000), 0x42);
+    }
+
+    #[test]
+    fn sixteen_ram_banks() {
+        let mut mbc = Mbc5::new(rom(4), 0x20000, true, false);
+        mbc.write_register(0x0000, 0x0A);
+
+        mbc.write_register(0x4000, 0x0F);
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.battery()[0x1E000], 0x42);
+    }
+
+    #[test]
+    fn rumble_takes_bit_3_for_the_motor() {
+        let mut mbc = Mbc5::new(rom(4), 0x10000, true, true);
+        mbc.write_register(0x0000, 0x0A);
+
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let seen = calls.clone();
+        mbc.on_rumble(Box::new(move |motor| seen.borrow_mut().push(motor)));
+
+        mbc.write_register(0x4000, 0x0F);
+        mbc.write_register(0x4000, 0x0B);
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.battery()[0x6000], 0x42);
+
+        mbc.write_register(0x4000, 0x03);
+        assert_eq!(*calls.borrow(), [true, false]);
+    }
+}
@@ -0,0 +1,280 @@
+use rom;
+
+use super::{Mapper, StateError, StateReader, StateWriter, read, write};
+
+// MMM01 multicart controller. It starts out unmapped, showing the menu in
+// the last 32 KiB of the ROM. The menu sets up the upper bank bits and
+// masks for the chosen game and then maps it by setting bit 6 of the RAM
+// enable register, which locks everything but the MBC1-style registers the
+// game itself uses.
+pub struct Mmm01 {
+    rom : Vec<u8>,
+    ram : Vec<u8>,
+    battery : bool,
+    mapped : bool,
+    ram_enabled : bool,
+    // Bits 0 - 4 of the ROM bank, then 5 - 6 and 7 - 8, which only the menu
+    // can set
+    rom_bank_low : u8,
+    rom_bank_mid : u8,
+    rom_bank_high : u8,
+    // Bits of rom_bank_low (shifted down by one) and ram_bank_low that keep
+    // the value the menu gave them once mapped
+    rom_bank_mask : u8,
+    ram_bank_mask : u8,
+    ram_bank_low : u8,
+    ram_bank_high : u8,
+    mode : u8,
+    mode_locked : bool
+}
+
+impl Mmm01<> {
+    pub fn new(rom: Vec<u8>, ram_size: usize, battery: bool) -> Self {
+        Mmm01 {
+            rom,
+            ram: vec![0; ram_size],
+            battery,
+            mapped: false,
+            ram_enabled: false,
+            rom_bank_low: 0,
+            rom_bank_mid: 0,
+            rom_bank_high: 0,
+            rom_bank_mask: 0,
+            ram_bank_mask: 0,
+            ram_bank_low: 0,
+            ram_bank_high: 0,
+            mode: 0,
+            mode_locked: false
+        }
+    }
+
+    fn rom_bank(&self, address: u16) -> usize {
+        let banks = (self.rom.len() / 0x4000).max(2);
+
+        if !self.mapped {
+            let bank = if address < 0x4000 { banks - 2 } else { banks - 1 };
+            return bank;
+        }
+
+        let upper = (self.rom_bank_mid as usize) << 5 | (self.rom_bank_high as usize) << 7;
+        let fixed = self.rom_bank_mask << 1;
+
+        let low = if address < 0x4000 {
+            // The game's bank 0 is wherever the masked bits point
+            self.rom_bank_low & fixed
+        } else if self.rom_bank_low & !fixed & 0x1F == 0 {
+            // Bank 0 cannot be selected here, like on MBC1
+            self.rom_bank_low | 0x01
+        } else {
+            self.rom_bank_low
+        };
+
+        (upper | low as usize) % banks
+    }
+
+    fn ram_index(&self, address: u16) -> usize {
+        // As on MBC1, the low RAM bank bits only count in mode 1
+        let low = if self.mode == 1 { self.ram_bank_low } else { self.ram_bank_low & self.ram_bank_mask };
+        let bank = (self.ram_bank_high as usize) << 2 | low as usize;
+        let index = bank * 0x2000 + (address - 0xA000) as usize;
+
+        index % self.ram.len()
+    }
+}
+
+impl Mapper for Mmm01 {
+    fn read_rom(&self, address: u16) -> u8 {
+        read(&self.rom, self.rom_bank(address) * 0x4000 + (address & 0x3FFF) as usize)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = value & 0x0F == 0x0A;
+
+                if !self.mapped {
+                    self.ram_bank_mask = (value >> 4) & 0x03;
+                    self.mapped = value & 0x40 != 0;
+                }
+            }
+            0x2000..=0x3FFF => {
+                let fixed = if self.mapped { self.rom_bank_mask << 1 } else { 0 };
+                self.rom_bank_low = (self.rom_bank_low & fixed) | (value & 0x1F & !fixed);
+
+                if !self.mapped {
+                    self.rom_bank_mid = (value >> 5) & 0x03;
+                }
+            }
+            0x4000..=0x5FFF => {
+                let fixed = if self.mapped { self.ram_bank_mask } else { 0 };
+                self.ram_bank_low = (self.ram_bank_low & fixed) | (value & 0x03 & !fixed);
+
+                if !self.mapped {
+                    self.ram_bank_high = (value >> 2) & 0x03;
+                    self.rom_bank_high = (value >> 4) & 0x03;
+                    self.mode_locked = value & 0x40 != 0;
+                }
+            }
+            _ => {
+                if !self.mode_locked {
+                    self.mode = value & 0x01;
+                }
+
+                if !self.mapped {
+                    self.rom_bank_mask = (value >> 2) & 0x0F;
+                }
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xFF;
+        }
+
+        read(&self.ram, self.ram_index(address))
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return;
+        }
+
+        let index = self.ram_index(address);
+        write(&mut self.ram, index, value);
+    }
+
+    fn battery(&self) -> Vec<u8> {
+        if self.battery { self.ram.clone() } else { Vec::new() }
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(b"MMM1");
+        state.put_block(&self.ram);
+        state.put_bool(self.mapped);
+        state.put_bool(self.ram_enabled);
+        state.put_u8(self.rom_bank_low);
+        state.put_u8(self.rom_bank_mid);
+        state.put_u8(self.rom_bank_high);
+        state.put_u8(self.rom_bank_mask);
+        state.put_u8(self.ram_bank_mask);
+        state.put_u8(self.ram_bank_low);
+        state.put_u8(self.ram_bank_high);
+        state.put_u8(self.mode);
+        state.put_bool(self.mode_locked);
+        state.finish()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, b"MMM1")?;
+        let ram = state.block(self.ram.len())?;
+        let mapped = state.bool()?;
+        let ram_enabled = state.bool()?;
+        let rom_bank_low = state.u8()?;
+        let rom_bank_mid = state.u8()?;
+        let rom_bank_high = state.u8()?;
+        let rom_bank_mask = state.u8()?;
+        let ram_bank_mask = state.u8()?;
+        let ram_bank_low = state.u8()?;
+        let ram_bank_high = state.u8()?;
+        let mode = state.u8()?;
+        let mode_locked = state.bool()?;
+        state.finish()?;
+
+        self.ram.copy_from_slice(ram);
+        self.mapped = mapped;
+        self.ram_enabled = ram_enabled;
+        self.rom_bank_low = rom_bank_low;
+        self.rom_bank_mid = rom_bank_mid;
+        self.rom_bank_high = rom_bank_high;
+        self.rom_bank_mask = rom_bank_mask;
+        self.ram_bank_mask = ram_bank_mask;
+        self.ram_bank_low = ram_bank_low;
+        self.ram_bank_high = ram_bank_high;
+        self.mode = mode;
+        self.mode_locked = mode_locked;
+
+        Ok(())
+    }
+}
+
+// Header of the menu in the last 32 KiB, the one the console boots from.
+// The header at the start of the ROM belongs to the first game, so MMM01
+// carts have to be recognised by this one. Other carts can hold anything
+// there, so the logo has to be in place as well as the cartridge type.
+pub fn menu_header(rom: &[u8]) -> Option<&[u8]> {
+    if rom.len() < 0x8000 {
+        return None;
+    }
+
+    let menu = rom.len() - 0x8000;
+
+    if !rom::has_logo(rom, menu + 0x104) {
+        return None;
+    }
+
+    let header = &rom[menu + 0x100..menu + 0x150];
+
+    match header[0x47] {
+        0x0B..=0x0D => Some(header),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rom::NINTENDO_LOGO;
+    use rom::mapper::{Mapper, rom};
+
+    use super::{Mmm01, menu_header};
+
+    #[test]
+    fn menu_is_mapped_at_start() {
+        let mmm = Mmm01::new(rom(16), 0, false);
+
+        assert_eq!(mmm.read_rom(0x0000), 14);
+        assert_eq!(mmm.read_rom(0x4000), 15);
+    }
+
+    #[test]
+    fn mapped_game_keeps_the_masked_bits() {
+        let mut mmm = Mmm01::new(rom(16), 0, false);
+
+        // Game at bank 4 with bits 2 - 3 of the bank fixed, then map it
+        mmm.write_register(0x2000, 0x04);
+        mmm.write_register(0x6000, 0x18);
+        mmm.write_register(0x0000, 0x40);
+
+        assert_eq!(mmm.read_rom(0x0000), 4);
+        assert_eq!(mmm.read_rom(0x4000), 5);
+
+        mmm.write_register(0x2000, 0x02);
+        assert_eq!(mmm.read_rom(0x4000), 6);
+
+        mmm.write_register(0x2000, 0x0F);
+        assert_eq!(mmm.read_rom(0x4000), 7);
+
+        // There is no way back to the menu
+        mmm.write_register(0x0000, 0x00);
+        assert_eq!(mmm.read_rom(0x0000), 4);
+    }
+
+    #[test]
+    fn menu_header_is_found_in_the_last_32_kib() {
+        let mut data = rom(16);
+        assert!(menu_header(&data).is_none());
+
+        let menu = data.len() - 0x8000;
+        data[menu + 0x147] = 0x0D;
+        assert!(menu_header(&data).is_none());
+
+        data[menu + 0x104..menu + 0x134].copy_from_slice(&NINTENDO_LOGO);
+        assert_eq!(menu_header(&data).unwrap()[0x47], 0x0D);
+        assert!(menu_header(&data[..0x4000]).is_none());
+    }
+}
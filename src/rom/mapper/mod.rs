@@ -0,0 +1,260 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod mmm01;
+mod huc1;
+mod huc3;
+mod tama5;
+mod camera;
+mod state;
+
+pub use self::mbc1::Mbc1;
+pub use self::mbc2::Mbc2;
+pub use self::mbc3::Mbc3;
+pub use self::mbc5::Mbc5;
+pub use self::mmm01::Mmm01;
+pub use self::huc1::Huc1;
+pub use self::huc3::Huc3;
+pub use self::tama5::Tama5;
+pub use self::camera::{PocketCamera, ImageSource, PngSource};
+pub use self::state::{StateError, StateReader, StateWriter};
+
+pub use self::mmm01::menu_header as mmm01_menu_header;
+
+// Memory bank controller of a cartridge. It owns the ROM and the external
+// RAM and sees every access to 0x0000 - 0x7FFF and 0xA000 - 0xBFFF, with
+// the full address.
+pub trait Mapper {
+    fn read_rom(&self, address: u16) -> u8;
+
+    // ROM cannot be written, these writes set the controller registers
+    fn write_register(&mut self, address: u16, value: u8);
+
+    fn read_ram(&self, address: u16) -> u8;
+
+    fn write_ram(&mut self, address: u16, value: u8);
+
+    // Battery backed state in the layout of a .sav file, empty when the
+    // cartridge has no battery
+    fn battery(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_battery(&mut self, _data: &[u8]) {}
+
+    // Everything but the ROM a save state needs to carry on exactly where
+    // the game was: RAM, bank registers, latches and clocks
+    #[allow(dead_code)]
+    fn save_state(&self) -> Vec<u8>;
+
+    #[allow(dead_code)]
+    fn load_state(&mut self, data: &[u8]) -> Result<(), StateError>;
+
+    // Called with the new motor state whenever a rumble cart turns its
+    // motor on or off
+    fn on_rumble(&mut self, _callback: Box<dyn FnMut(bool)>) {}
+
+    // Advances controllers that do work of their own, like the camera
+    // sensor or a real time clock, by the given number of M-cycles
+    fn tick(&mut self, _cycles: u32) {}
+
+    // Picture the Pocket Camera sensor sees, ignored by other cartridges
+    fn set_image_source(&mut self, _source: Box<dyn ImageSource>) {}
+}
+
+// Cartridge without a controller: 32 KiB of ROM and up to 8 KiB of RAM
+pub struct RomOnly {
+    rom : Vec<u8>,
+    ram : Vec<u8>,
+    battery : bool
+}
+
+impl RomOnly<> {
+    pub fn new(rom: Vec<u8>, ram_size: usize, battery: bool) -> Self {
+        RomOnly {
+            rom,
+            ram: vec![0; ram_size],
+            battery
+        }
+    }
+}
+
+impl Mapper for RomOnly {
+    fn read_rom(&self, address: u16) -> u8 {
+        read(&self.rom, address as usize)
+    }
+
+    fn write_register(&mut self, _address: u16, _value: u8) {}
+
+    fn read_ram(&self, address: u16) -> u8 {
+        read(&self.ram, (address - 0xA000) as usize)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        write(&mut self.ram, (address - 0xA000) as usize, value);
+    }
+
+    fn battery(&self) -> Vec<u8> {
+        if self.battery { self.ram.clone() } else { Vec::new() }
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(b"ROM ");
+        state.put_block(&self.ram);
+        state.finish()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, b"ROM ")?;
+        let ram = state.block(self.ram.len())?;
+        state.finish()?;
+
+        self.ram.copy_from_slice(ram);
+
+        Ok(())
+    }
+}
+
+// Reads past the end of a chip return an open bus
+fn read(memory: &[u8], index: usize) -> u8 {
+    match memory.get(index) {
+        Some(&value) => value,
+        None => 0xFF
+    }
+}
+
+fn write(memory: &mut [u8], index: usize, value: u8) {
+    if let Some(cell) = memory.get_mut(index) {
+        *cell = value;
+    }
+}
+
+// M-cycles in a second of emulated time, which the cartridge clocks count
+const CYCLES_PER_SECOND : u32 = 1048576;
+
+// Wall clock time in seconds. Cartridge clocks use it to catch up with the
+// time a save file spent on disk.
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0
+    }
+}
+
+// ROM of the given number of 16 KiB banks, each filled with its own number,
+// for the tests of every mapper
+#[cfg(test)]
+pub fn rom(banks: usize) -> Vec<u8> {
+    (0..banks * 0x4000).map(|index| (index / 0x4000) as u8).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mapper, RomOnly, Mbc1, Mbc2, Mbc3, Mbc5, Huc1, StateError, rom};
+
+    // Enables the RAM, switches to ROM bank 3 and RAM bank 1, then fills
+    // the first bytes of the RAM window
+    fn play(mapper: &mut dyn Mapper) {
+        mapper.write_register(0x0000, 0x0A);
+        mapper.write_register(0x2100, 3);
+        mapper.write_register(0x4000, 1);
+
+        for offset in 0..0x20 {
+            mapper.write_ram(0xA000 + offset, offset as u8 ^ 0x5A);
+        }
+    }
+
+    fn seen(mapper: &dyn Mapper) -> (u8, Vec<u8>) {
+        let ram = (0..0x20).map(|offset| mapper.read_ram(0xA000 + offset)).collect();
+        (mapper.read_rom(0x4000), ram)
+    }
+
+    fn round_trip<M: Mapper, F: Fn() -> M>(build: F, bank: u8) {
+        let mut played = build();
+        let mut fresh = build();
+
+        play(&mut played);
+        let state = played.save_state();
+
+        fresh.load_state(&state).unwrap();
+
+        assert_eq!(seen(&fresh), seen(&played));
+        assert_eq!(seen(&fresh).0, bank);
+        assert_eq!(fresh.save_state(), state);
+    }
+
+    #[test]
+    fn state_round_trips_through_a_fresh_mapper() {
+        round_trip(|| RomOnly::new(rom(8), 0x2000, false), 1);
+        round_trip(|| Mbc1::new(rom(8), 0x8000, true), 3);
+        round_trip(|| Mbc2::new(rom(8), true), 3);
+        round_trip(|| Mbc5::new(rom(8), 0x8000, true, false), 3);
+        round_trip(|| Huc1::new(rom(8), 0x8000, true), 3);
+    }
+
+    #[test]
+    fn state_keeps_the_clock_of_mbc3() {
+        let mut played = Mbc3::new(rom(8), 0x8000, true, true);
+        play(&mut played);
+
+        // Latch the clock and select its seconds register
+        played.write_register(0x6000, 0);
+        played.write_register(0x6000, 1);
+        played.write_register(0x4000, 0x08);
+        played.write_ram(0xA000, 42);
+
+        let mut fresh = Mbc3::new(rom(8), 0x8000, true, true);
+        fresh.load_state(&played.save_state()).unwrap();
+
+        assert_eq!(fresh.read_rom(0x4000), 3);
+        assert_eq!(fresh.read_ram(0xA000), played.read_ram(0xA000));
+
+        fresh.write_register(0x4000, 1);
+        played.write_register(0x4000, 1);
+        assert_eq!(seen(&fresh), seen(&played));
+    }
+
+    #[test]
+    fn state_of_another_mapper_is_refused() {
+        let state = Mbc1::new(rom(8), 0x8000, true).save_state();
+        let mut mapper = Mbc5::new(rom(8), 0x8000, true, false);
+
+        assert_eq!(mapper.load_state(&state), Err(StateError::WrongMapper));
+    }
+
+    #[test]
+    fn state_with_another_ram_size_is_refused() {
+        let state = Mbc1::new(rom(8), 0x2000, true).save_state();
+        let mut mapper = Mbc1::new(rom(8), 0x8000, true);
+
+        assert_eq!(mapper.load_state(&state), Err(StateError::SizeMismatch));
+    }
+
+    #[test]
+    fn truncated_or_padded_state_is_refused() {
+        let mut played = Mbc1::new(rom(8), 0x8000, true);
+        play(&mut played);
+        let state = played.save_state();
+
+        let mut mapper = Mbc1::new(rom(8), 0x8000, true);
+        let before = mapper.save_state();
+
+        assert_eq!(mapper.load_state(&state[..state.len() - 1]), Err(StateError::BadLength));
+        assert_eq!(mapper.load_state(&state[..2]), Err(StateError::BadLength));
+
+        let mut padded = state.clone();
+        padded.push(0);
+        assert_eq!(mapper.load_state(&padded), Err(StateError::BadLength));
+
+        // A failed load leaves everything as it was
+        assert_eq!(mapper.save_state(), before);
+    }
+}
@@ -0,0 +1,134 @@
+use std::error::Error;
+use std::fmt;
+
+// Why a save state could not be restored. The mapper is left untouched.
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    // The state was saved by another kind of controller
+    WrongMapper,
+    // RAM or another block is not the size this cartridge has
+    SizeMismatch,
+    // The data ends early or goes on past the last field
+    BadLength
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::WrongMapper => write!(f, "the state belongs to another cartridge type"),
+            StateError::SizeMismatch => write!(f, "the state does not match the cartridge's memory sizes"),
+            StateError::BadLength => write!(f, "the state is truncated or has trailing data")
+        }
+    }
+}
+
+impl Error for StateError {}
+
+// A save state is a four byte tag naming the controller, then its fields in
+// a fixed order. Numbers are little-endian and blocks carry their length.
+pub struct StateWriter {
+    data : Vec<u8>
+}
+
+impl StateWriter<> {
+    pub fn new(tag: &[u8; 4]) -> Self {
+        StateWriter {
+            data: tag.to_vec()
+        }
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn put_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn put_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_block(&mut self, block: &[u8]) {
+        self.put_u32(block.len() as u32);
+        self.data.extend_from_slice(block);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+// Reads the fields back in the order they were written
+pub struct StateReader<'a> {
+    data : &'a [u8],
+    position : usize
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8], tag: &[u8; 4]) -> Result<Self, StateError> {
+        if data.len() < tag.len() {
+            return Err(StateError::BadLength);
+        }
+
+        if &data[..tag.len()] != tag {
+            return Err(StateError::WrongMapper);
+        }
+
+        Ok(StateReader {
+            data,
+            position: tag.len()
+        })
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.position < length {
+            return Err(StateError::BadLength);
+        }
+
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // A block that has to be exactly as long as the one it replaces
+    pub fn block(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.u32()? as usize != length {
+            return Err(StateError::SizeMismatch);
+        }
+
+        self.take(length)
+    }
+
+    // Checks that every byte was used
+    pub fn finish(&self) -> Result<(), StateError> {
+        if self.position != self.data.len() {
+            return Err(StateError::BadLength);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,480 @@
+use super::{Mapper, StateError, StateReader, StateWriter, read, now, CYCLES_PER_SECOND};
+
+// Registers reached by writing their number to 0xA001 and then a nibble to
+// 0xA000, or reading 0xA000
+const BANK_LO : usize = 0x0;
+const BANK_HI : usize = 0x1;
+const WRITE_LO : usize = 0x4;
+const WRITE_HI : usize = 0x5;
+const ADDR_HI : usize = 0x6;
+const ADDR_LO : usize = 0x7;
+const REGISTER_COUNT : usize = 0x8;
+const READ_LO : u8 = 0xC;
+const READ_HI : u8 = 0xD;
+
+// Commands in bits 1 - 3 of ADDR_HI, run by the write to ADDR_LO
+const COMMAND_MEMORY_WRITE : u8 = 0x0;
+const COMMAND_MEMORY_READ : u8 = 0x1;
+const COMMAND_CLOCK : u8 = 0x2;
+const COMMAND_CLOCK_PAGE : u8 = 0x4;
+
+// Clock commands, selected by the 5-bit address
+const CLOCK_STOP : u8 = 0x00;
+const CLOCK_START : u8 = 0x01;
+const CLOCK_MINUTES_WRITE : u8 = 0x04;
+const CLOCK_HOURS_WRITE : u8 = 0x05;
+const CLOCK_MINUTES_READ : u8 = 0x06;
+const CLOCK_HOURS_READ : u8 = 0x07;
+
+const MEMORY_SIZE : usize = 0x20;
+const PAGE_SIZE : usize = 13;
+
+// Bandai TAMA5, as used by Tamagotchi 3. Besides the ROM bank everything
+// goes through a nibble-wide register interface at 0xA000 - 0xA001: 32
+// bytes of battery backed memory and a TAMA6 clock.
+pub struct Tama5 {
+    rom : Vec<u8>,
+    register : u8,
+    registers : [u8; REGISTER_COUNT],
+    memory : [u8; MEMORY_SIZE],
+    rtc : Rtc
+}
+
+impl Tama5<> {
+    pub fn new(rom: Vec<u8>) -> Self {
+        Tama5 {
+            rom,
+            register: 0,
+            registers: [0; REGISTER_COUNT],
+            memory: [0; MEMORY_SIZE],
+            rtc: Rtc::new()
+        }
+    }
+
+    fn address(&self) -> u8 {
+        (self.registers[ADDR_HI] << 4) & 0x10 | self.registers[ADDR_LO]
+    }
+
+    fn command(&self) -> u8 {
+        self.registers[ADDR_HI] >> 1
+    }
+
+    fn data(&self) -> u8 {
+        self.registers[WRITE_HI] << 4 | self.registers[WRITE_LO]
+    }
+
+    fn run_command(&mut self) {
+        let address = self.address();
+        let data = self.data();
+
+        match self.command() {
+            COMMAND_MEMORY_WRITE => self.memory[address as usize] = data,
+            COMMAND_CLOCK => match address {
+                CLOCK_STOP => self.rtc.set_running(false),
+                CLOCK_START => self.rtc.set_running(true),
+                CLOCK_MINUTES_WRITE => {
+                    self.rtc.write_page(2, data & 0x0F);
+                    self.rtc.write_page(3, data >> 4);
+                }
+                CLOCK_HOURS_WRITE => {
+                    self.rtc.write_page(4, data & 0x0F);
+                    self.rtc.write_page(5, data >> 4);
+                }
+                _ => {}
+            },
+            // WRITE_LO picks the page register and ADDR_LO 0 writes WRITE_HI
+            // into it
+            COMMAND_CLOCK_PAGE if self.registers[ADDR_LO] == 0 => {
+                let register = self.registers[WRITE_LO] as usize;
+                let value = self.registers[WRITE_HI];
+                self.rtc.write_page(register, value);
+            }
+            _ => {}
+        }
+    }
+
+    // Byte the read registers return the nibbles of
+    fn result(&self) -> u8 {
+        match self.command() {
+            COMMAND_MEMORY_READ => self.memory[self.address() as usize],
+            COMMAND_CLOCK => match self.address() {
+                CLOCK_MINUTES_READ => self.rtc.read_page(3) << 4 | self.rtc.read_page(2),
+                CLOCK_HOURS_READ => self.rtc.read_page(5) << 4 | self.rtc.read_page(4),
+                _ => 0
+            },
+            COMMAND_CLOCK_PAGE => self.rtc.read_page(self.registers[WRITE_LO] as usize),
+            _ => 0
+        }
+    }
+}
+
+impl Mapper for Tama5 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 {
+            0
+        } else {
+            (self.registers[BANK_HI] << 4 | self.registers[BANK_LO]) as usize & 0x1F
+        };
+
+        let banks = (self.rom.len() / 0x4000).max(1);
+        read(&self.rom, (bank % banks) * 0x4000 + (address & 0x3FFF) as usize)
+    }
+
+    // All registers are in the 0xA000 window
+    fn write_register(&mut self, _address: u16, _value: u8) {}
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if address & 0x01 != 0 {
+            return 0xFF;
+        }
+
+        match self.register {
+            READ_LO => 0xF0 | self.result() & 0x0F,
+            READ_HI => 0xF0 | self.result() >> 4,
+            // Anything else reads as the chip being ready
+            _ => 0xF1
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if address & 0x01 != 0 {
+            self.register = value & 0x0F;
+            return;
+        }
+
+        let register = self.register as usize;
+
+        if register >= REGISTER_COUNT {
+            return;
+        }
+
+        self.registers[register] = value & 0x0F;
+
+        if register == ADDR_LO {
+            self.run_command();
+        }
+    }
+
+    // Memory, the clock page, the seconds of the day as a 32-bit word and a
+    // 64-bit UNIX timestamp of when it was written
+    fn battery(&self) -> Vec<u8> {
+        let mut data = self.memory.to_vec();
+        data.extend_from_slice(&self.rtc.page);
+
+        for i in 0..4 {
+            data.push((self.rtc.seconds >> (i * 8)) as u8);
+        }
+
+        let timestamp = now();
+
+        for i in 0..8 {
+            data.push((timestamp >> (i * 8)) as u8);
+        }
+
+        data
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        if data.len() < MEMORY_SIZE {
+            return;
+        }
+
+        self.memory.copy_from_slice(&data[..MEMORY_SIZE]);
+
+        let clock = &data[MEMORY_SIZE..];
+
+        if clock.len() != PAGE_SIZE + 12 {
+            return;
+        }
+
+        let mut seconds = 0;
+        let mut timestamp = 0;
+
+        for i in 0..4 {
+            seconds |= (clock[PAGE_SIZE + i] as u32) << (i * 8);
+        }
+
+        for i in 0..8 {
+            timestamp |= (clock[PAGE_SIZE + 4 + i] as u64) << (i * 8);
+        }
+
+        self.rtc.page.copy_from_slice(&clock[..PAGE_SIZE]);
+        self.rtc.seconds = seconds % 86400;
+
+        // Time kept running while the game was not played
+        if self.rtc.running {
+            self.rtc.advance(now().saturating_sub(timestamp));
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(b"TAM5");
+        state.put_u8(self.register);
+        state.put_block(&self.registers);
+        state.put_block(&self.memory);
+        state.put_block(&self.rtc.page);
+        state.put_u32(self.rtc.seconds);
+        state.put_bool(self.rtc.running);
+        state.put_u32(self.rtc.cycles);
+        state.finish()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, b"TAM5")?;
+        let register = state.u8()?;
+        let registers = state.block(REGISTER_COUNT)?;
+        let memory = state.block(MEMORY_SIZE)?;
+        let page = state.block(PAGE_SIZE)?;
+        let seconds = state.u32()?;
+        let running = state.bool()?;
+        let cycles = state.u32()?;
+        state.finish()?;
+
+        self.register = register & 0x0F;
+        self.registers.copy_from_slice(registers);
+        self.memory.copy_from_slice(memory);
+        self.rtc.page.copy_from_slice(page);
+        self.rtc.seconds = seconds % 86400;
+        self.rtc.running = running;
+        self.rtc.cycles = cycles % CYCLES_PER_SECOND;
+
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.rtc.tick(cycles);
+    }
+}
+
+// TAMA6 clock. Its page holds BCD nibbles: seconds, minutes and hours (two
+// each), the weekday, and the day, month and year (two each). Time of day
+// comes from a running seconds counter; the calendar nibbles are kept as
+// written and do not roll over.
+struct Rtc {
+    page : [u8; PAGE_SIZE],
+    seconds : u32,
+    running : bool,
+    // M-cycles counted towards the next second
+    cycles : u32
+}
+
+impl Rtc<> {
+    fn new() -> Self {
+        Rtc {
+            page: [0; PAGE_SIZE],
+            seconds: 0,
+            running: true,
+            cycles: 0
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if !self.running {
+            return;
+        }
+
+        let cycles = self.cycles as u64 + cycles as u64;
+        self.cycles = (cycles % CYCLES_PER_SECOND as u64) as u32;
+        self.advance(cycles / CYCLES_PER_SECOND as u64);
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        self.seconds = ((self.seconds as u64 + seconds) % 86400) as u32;
+    }
+
+    fn set_running(&mut self, running: bool) {
+        self.running = running;
+    }
+
+    fn read_page(&self, register: usize) -> u8 {
+        let time = [self.seconds % 60, self.seconds / 60 % 60, self.seconds / 3600];
+
+        match register {
+            0..=5 => {
+                let value = time[register / 2];
+                (if register & 1 == 0 { value % 10 } else { value / 10 }) as u8
+            }
+            _ if register < PAGE_SIZE => self.page[register],
+            _ => 0
+        }
+    }
+
+    fn write_page(&mut self, register: usize, value: u8) {
+        let value = value & 0x0F;
+
+        if register >= 6 {
+            if register < PAGE_SIZE {
+                self.page[register] = value;
+            }
+
+            return;
+        }
+
+        let mut time = [self.seconds % 60, self.seconds / 60 % 60, self.seconds / 3600];
+        let field = &mut time[register / 2];
+
+        *field = if register & 1 == 0 {
+            *field / 10 * 10 + value as u32
+        } else {
+            value as u32 * 10 + *field % 10
+        };
+
+        self.seconds = (time[2] * 3600 + time[1] * 60 + time[0]) % 86400;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rom::mapper::{Mapper, now, rom, CYCLES_PER_SECOND};
+
+    use super::{Tama5, BANK_LO, BANK_HI, WRITE_LO, WRITE_HI, ADDR_HI, ADDR_LO, READ_LO, READ_HI,
+                COMMAND_MEMORY_WRITE, COMMAND_MEMORY_READ, COMMAND_CLOCK, COMMAND_CLOCK_PAGE,
+                CLOCK_STOP, CLOCK_START, CLOCK_MINUTES_WRITE, CLOCK_HOURS_WRITE, CLOCK_MINUTES_READ,
+                CLOCK_HOURS_READ, MEMORY_SIZE, PAGE_SIZE};
+
+    fn set(tama: &mut Tama5, register: usize, value: u8) {
+        tama.write_ram(0xA001, register as u8);
+        tama.write_ram(0xA000, value);
+    }
+
+    fn get(tama: &mut Tama5, register: u8) -> u8 {
+        tama.write_ram(0xA001, register);
+        tama.read_ram(0xA000)
+    }
+
+    // Runs a command on the given 5-bit address with the data byte
+    fn run(tama: &mut Tama5, command: u8, address: u8, data: u8) {
+        set(tama, WRITE_LO, data & 0x0F);
+        set(tama, WRITE_HI, data >> 4);
+        set(tama, ADDR_HI, command << 1 | address >> 4);
+        set(tama, ADDR_LO, address & 0x0F);
+    }
+
+    // Byte a read command leaves in the read registers
+    fn result(tama: &mut Tama5, command: u8, address: u8) -> u8 {
+        run(tama, command, address, 0);
+        (get(tama, READ_HI) & 0x0F) << 4 | get(tama, READ_LO) & 0x0F
+    }
+
+    #[test]
+    fn rom_bank_is_set_a_nibble_at_a_time() {
+        let mut tama = Tama5::new(rom(32));
+
+        set(&mut tama, BANK_LO, 0x03);
+        set(&mut tama, BANK_HI, 0x01);
+        assert_eq!(tama.read_rom(0x4000), 0x13);
+        assert_eq!(tama.read_rom(0x0000), 0);
+    }
+
+    #[test]
+    fn memory_round_trips_through_the_registers() {
+        let mut tama = Tama5::new(rom(2));
+
+        // Write 0x5A to address 0x13
+        set(&mut tama, WRITE_LO, 0x0A);
+        set(&mut tama, WRITE_HI, 0x05);
+        set(&mut tama, ADDR_HI, COMMAND_MEMORY_WRITE << 1 | 0x01);
+        set(&mut tama, ADDR_LO, 0x03);
+        assert_eq!(tama.battery()[0x13], 0x5A);
+
+        set(&mut tama, ADDR_HI, COMMAND_MEMORY_READ << 1 | 0x01);
+        set(&mut tama, ADDR_LO, 0x03);
+        assert_eq!(get(&mut tama, READ_LO), 0xFA);
+        assert_eq!(get(&mut tama, READ_HI), 0xF5);
+
+        // Other registers read as ready
+        assert_eq!(get(&mut tama, 0x0A), 0xF1);
+    }
+
+    #[test]
+    fn clock_time_is_written_and_read_in_bcd() {
+        let mut tama = Tama5::new(rom(2));
+
+        run(&mut tama, COMMAND_CLOCK, CLOCK_MINUTES_WRITE, 0x42);
+        run(&mut tama, COMMAND_CLOCK, CLOCK_HOURS_WRITE, 0x13);
+
+        assert_eq!(result(&mut tama, COMMAND_CLOCK, CLOCK_MINUTES_READ), 0x42);
+        assert_eq!(result(&mut tama, COMMAND_CLOCK, CLOCK_HOURS_READ), 0x13);
+        assert_eq!(tama.rtc.seconds / 60, 13 * 60 + 42);
+    }
+
+    #[test]
+    fn stopped_clock_does_not_count() {
+        let mut tama = Tama5::new(rom(2));
+        run(&mut tama, COMMAND_CLOCK, CLOCK_MINUTES_WRITE, 0x10);
+
+        run(&mut tama, COMMAND_CLOCK, CLOCK_STOP, 0);
+        assert!(!tama.rtc.running);
+        tama.tick(CYCLES_PER_SECOND * 3600);
+        assert_eq!(result(&mut tama, COMMAND_CLOCK, CLOCK_MINUTES_READ), 0x10);
+
+        run(&mut tama, COMMAND_CLOCK, CLOCK_START, 0);
+        assert!(tama.rtc.running);
+        tama.tick(CYCLES_PER_SECOND * 120 - 1);
+        assert_eq!(result(&mut tama, COMMAND_CLOCK, CLOCK_MINUTES_READ), 0x11);
+        tama.tick(1);
+        assert_eq!(result(&mut tama, COMMAND_CLOCK, CLOCK_MINUTES_READ), 0x12);
+    }
+
+    #[test]
+    fn clock_page_is_reached_a_nibble_at_a_time() {
+        let mut tama = Tama5::new(rom(2));
+
+        // WRITE_LO picks the page register and WRITE_HI holds the value
+        run(&mut tama, COMMAND_CLOCK_PAGE, 0, 0x7 << 4 | 0x9);
+        run(&mut tama, COMMAND_CLOCK_PAGE, 0, 0x5 << 4 | 0x3);
+        assert_eq!(tama.rtc.page[9], 0x7);
+
+        // Reads take the register from WRITE_LO without running a command
+        set(&mut tama, WRITE_LO, 0x9);
+        assert_eq!(get(&mut tama, READ_LO), 0xF7);
+
+        // Time of day nibbles go to the seconds counter
+        assert_eq!(result(&mut tama, COMMAND_CLOCK, CLOCK_MINUTES_READ), 0x50);
+    }
+
+    #[test]
+    fn battery_carries_memory_and_clock() {
+        let mut tama = Tama5::new(rom(2));
+        run(&mut tama, COMMAND_MEMORY_WRITE, 0x1F, 0xA5);
+        run(&mut tama, COMMAND_CLOCK, CLOCK_HOURS_WRITE, 0x21);
+        run(&mut tama, COMMAND_CLOCK_PAGE, 0, 0x4 << 4 | 0xC);
+
+        let battery = tama.battery();
+        assert_eq!(battery.len(), MEMORY_SIZE + PAGE_SIZE + 4 + 8);
+        assert_eq!(battery[0x1F], 0xA5);
+        assert_eq!(battery[MEMORY_SIZE + 0xC], 0x4);
+
+        let mut loaded = Tama5::new(rom(2));
+        loaded.load_battery(&battery);
+        assert_eq!(loaded.memory[0x1F], 0xA5);
+        assert_eq!(loaded.rtc.page[0xC], 0x4);
+        assert_eq!(result(&mut loaded, COMMAND_CLOCK, CLOCK_HOURS_READ), 0x21);
+        assert_eq!(loaded.battery()[..MEMORY_SIZE + PAGE_SIZE], battery[..MEMORY_SIZE + PAGE_SIZE]);
+
+        // A clock of the wrong size is ignored, the memory still loads
+        let mut loaded = Tama5::new(rom(2));
+        loaded.load_battery(&battery[..battery.len() - 1]);
+        assert_eq!(loaded.memory[0x1F], 0xA5);
+        assert_eq!(loaded.rtc.page[0xC], 0);
+        assert_eq!(result(&mut loaded, COMMAND_CLOCK, CLOCK_HOURS_READ), 0x00);
+    }
+
+    #[test]
+    fn loading_catches_up_with_the_time_away() {
+        let mut tama = Tama5::new(rom(2));
+        run(&mut tama, COMMAND_CLOCK, CLOCK_HOURS_WRITE, 0x21);
+
+        // Saved two hours ago
+        let mut battery = tama.battery();
+        let timestamp = now() - 2 * 3600;
+
+        for i in 0..8 {
+            battery[MEMORY_SIZE + PAGE_SIZE + 4 + i] = (timestamp >> (i * 8)) as u8;
+        }
+
+        let mut loaded = Tama5::new(rom(2));
+        loaded.load_battery(&battery);
+        assert_eq!(result(&mut loaded, COMMAND_CLOCK, CLOCK_HOURS_READ), 0x23);
+    }
+}
@@ -1,11 +1,60 @@
-use std::error::Error;
-use std::path::Path;
 use std::fs::File;
 use std::io::Read;
-use std::fs;
-use std::io::prelude::*;
 use std::fmt;
 
+mod error;
+mod gbs;
+mod licensee;
+mod mapper;
+
+pub use self::error::RomError;
+pub use self::licensee::LicenseCode;
+
+pub use self::gbs::Gbs;
+pub use self::mapper::{Mapper, PngSource};
+
+static NINTENDO_LOGO : [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E
+];
+
+// Checksum of 0x134 - 0x14C stored at 0x14D. The boot ROM locks up when
+// it does not match.
+pub fn header_checksum(data: &[u8]) -> u8 {
+    data[0x134..0x14D].iter().fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1))
+}
+
+// Sum of every byte in the ROM except the two at 0x14E - 0x14F it is stored
+// in. Nothing on the console checks it.
+pub fn global_checksum(data: &[u8]) -> u16 {
+    data.iter().enumerate()
+        .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+        .fold(0u16, |sum, (_, &byte)| sum.wrapping_add(byte as u16))
+}
+
+pub fn fix_header_checksum(data: &mut [u8]) {
+    data[0x14D] = header_checksum(data);
+}
+
+// Has to come after everything else, the header checksum included
+pub fn fix_global_checksum(data: &mut [u8]) {
+    let checksum = global_checksum(data);
+
+    data[0x14E] = (checksum >> 8) as u8;
+    data[0x14F] = checksum as u8;
+}
+
+// Whether the Nintendo logo is found at offset, as it is at 0x104 in every
+// cartridge header
+pub fn has_logo(data: &[u8], offset: usize) -> bool {
+    match data.get(offset..offset + NINTENDO_LOGO.len()) {
+        Some(bytes) => bytes == &NINTENDO_LOGO[..],
+        None => false
+    }
+}
+
+#[derive(PartialEq)]
 struct Nintendo {
     pub texels : [u8; 48]
 }
@@ -16,19 +65,33 @@ impl fmt::Debug for Nintendo {
     }
 }
 
-#[derive(Debug)]
+// Title as stored, without the zeros padding it. Titles are not always
+// ASCII, so the bytes are kept to be written back unchanged.
+#[derive(PartialEq)]
+pub struct Title {
+    pub bytes : Vec<u8>
+}
+
+impl fmt::Debug for Title {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(&self.bytes))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ConType {
+    // Uses CGB functions but also runs on a DMG
     Color = 0x80,
-    NColor = 0x81
+    ColorOnly = 0xC0
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum GBSGB_Indicator {
     GB = 0x00,
     SGB = 0x03
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ROMType {
     ROM_Only = 0x0,
     ROM_MBC1 = 0x1,
@@ -58,7 +121,41 @@ pub enum ROMType {
     Hudson_HuC_1 = 0xFF
 }
 
-#[derive(Debug)]
+impl ROMType<> {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(ROMType::ROM_Only),
+            1 => Some(ROMType::ROM_MBC1),
+            2 => Some(ROMType::ROM_MBC1_RAM),
+            3 => Some(ROMType::ROM_MBC1_RAM_BATT),
+            5 => Some(ROMType::ROM_MBC2),
+            6 => Some(ROMType::ROM_MBC2_BATTERY),
+            8 => Some(ROMType::ROM_RAM),
+            9 => Some(ROMType::ROM_RAM_BATTERY),
+            0xB => Some(ROMType::ROM_MMM01),
+            0xC => Some(ROMType::ROM_MMM01_SRAM),
+            0xD => Some(ROMType::ROM_MMM01_SRAM_BATT),
+            0xF => Some(ROMType::ROM_MBC3_TIMER_BATT),
+            0x10 => Some(ROMType::ROM_MBC3_TIMER_RAM_BATT),
+            0x11 => Some(ROMType::ROM_MBC3),
+            0x12 => Some(ROMType::ROM_MBC3_RAM),
+            0x13 => Some(ROMType::ROM_MBC3_RAM_BATT),
+            0x19 => Some(ROMType::ROM_MBC5),
+            0x1A => Some(ROMType::ROM_MBC5_RAM),
+            0x1B => Some(ROMType::ROM_MBC5_RAM_BATT),
+            0x1C => Some(ROMType::ROM_MBC5_RUMBLE),
+            0x1D => Some(ROMType::ROM_MBC5_RUMBLE_SRAM),
+            0x1E => Some(ROMType::ROM_MBC5_RUMBLE_SRAM_BATT),
+            0x1F => Some(ROMType::Pocket_Camera),
+            0xFD => Some(ROMType::Bandai_TAMA5),
+            0xFE => Some(ROMType::Hudson_HuC_3),
+            0xFF => Some(ROMType::Hudson_HuC_1),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ROMSize {
     Sz256Kbit = 0x0,
     Sz512Kbit = 0x1,
@@ -67,179 +164,528 @@ pub enum ROMSize {
     Sz4Mbit = 0x4,
     Sz8Mbit = 0x5,
     Sz16Mbit = 0x6,
+    Sz32Mbit = 0x7,
+    Sz64Mbit = 0x8,
     Sz9Mbit = 0x52,
     Sz10Mbit = 0x53,
     Sz12Mbit = 0x54
 }
 
-#[derive(Debug)]
+impl ROMSize<> {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(ROMSize::Sz256Kbit),
+            1 => Some(ROMSize::Sz512Kbit),
+            2 => Some(ROMSize::Sz1Mbit),
+            3 => Some(ROMSize::Sz2Mbit),
+            4 => Some(ROMSize::Sz4Mbit),
+            5 => Some(ROMSize::Sz8Mbit),
+            6 => Some(ROMSize::Sz16Mbit),
+            7 => Some(ROMSize::Sz32Mbit),
+            8 => Some(ROMSize::Sz64Mbit),
+            0x52 => Some(ROMSize::Sz9Mbit),
+            0x53 => Some(ROMSize::Sz10Mbit),
+            0x54 => Some(ROMSize::Sz12Mbit),
+            _ => None
+        }
+    }
+
+    // Smallest power of two size that holds length bytes
+    pub fn for_length(length: usize) -> Option<Self> {
+        (0..9).filter_map(ROMSize::from_code).find(|size| size.bytes() >= length)
+    }
+
+    pub fn bytes(&self) -> usize {
+        match *self {
+            ROMSize::Sz256Kbit => 0x8000,
+            ROMSize::Sz512Kbit => 0x10000,
+            ROMSize::Sz1Mbit => 0x20000,
+            ROMSize::Sz2Mbit => 0x40000,
+            ROMSize::Sz4Mbit => 0x80000,
+            ROMSize::Sz8Mbit => 0x100000,
+            ROMSize::Sz16Mbit => 0x200000,
+            ROMSize::Sz32Mbit => 0x400000,
+            ROMSize::Sz64Mbit => 0x800000,
+            // 72, 80 and 96 banks
+            ROMSize::Sz9Mbit => 0x120000,
+            ROMSize::Sz10Mbit => 0x140000,
+            ROMSize::Sz12Mbit => 0x180000
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RAMSize {
     None = 0x0,
     Sz16kBit = 0x1,
     Sz64kBit = 0x2,
     Sz256kBit = 0x3,
-    Sz1MBit = 0x4
+    Sz1MBit = 0x4,
+    Sz512kBit = 0x5
+}
+
+impl RAMSize<> {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(RAMSize::None),
+            1 => Some(RAMSize::Sz16kBit),
+            2 => Some(RAMSize::Sz64kBit),
+            3 => Some(RAMSize::Sz256kBit),
+            4 => Some(RAMSize::Sz1MBit),
+            5 => Some(RAMSize::Sz512kBit),
+            _ => None
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        match *self {
+            RAMSize::None => 0,
+            RAMSize::Sz16kBit => 0x800,
+            RAMSize::Sz64kBit => 0x2000,
+            RAMSize::Sz256kBit => 0x8000,
+            RAMSize::Sz1MBit => 0x20000,
+            RAMSize::Sz512kBit => 0x10000
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DestinationCode {
     Japanese,
     NonJapanese,
 }
 
-#[derive(Debug)]
-pub enum LicenseCode {
-    Recheck = 0x33,
-    Accolade = 0x79,
-    Konami = 0xA4
-}
-
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Header {
     nintendo : Nintendo,
-    pub title : String,
+    pub title : Title,
+    // Four characters at 0x13F - 0x142 that shorten the title of CGB games
+    pub manufacturer : Option<String>,
     pub con_type : Option<ConType>,
-    pub rom_type : Option<ROMType>,
+    pub rom_type : ROMType,
     pub rom_size : Option<ROMSize>,
     pub ram_size : Option<RAMSize>,
     pub dest_code : Option<DestinationCode>,
-    pub lcode : Option<LicenseCode>,
+    pub sgb : Option<GBSGB_Indicator>,
+    // Licensee as stored: the old one byte code, which is 0x33 when the two
+    // characters of the new code are used instead
+    pub old_licensee : u8,
+    pub new_licensee : [u8; 2],
     pub mask_rom_vers_number : u8,
     pub compl_check : u8,
     pub checksum : [u8; 2]
 }
 
 impl Header<> {
-    fn get_nintendo_texels(&self) -> &[u8; 48] {
-        static TEXELS : Nintendo =  Nintendo {
-            texels: [
-                0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
-                0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
-                0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E
-            ]
-        };
+    pub fn parse(data: &[u8]) -> Result<Self, RomError> {
+        if data.len() < 0x150 {
+            return Err(RomError::TooSmall);
+        }
 
-        return &TEXELS.texels;
+        // The CGB flag takes the place of the last title character
+        let cgb = data[0x143] & 0x80 != 0;
+
+        Ok(Header {
+            checksum: [ data[0x14E], data[0x14F] ],
+            compl_check: data[0x14D],
+            con_type: match data[0x143] {
+                0x80 => Some(ConType::Color),
+                0xC0 => Some(ConType::ColorOnly),
+                _ => None
+            },
+            dest_code: match data[0x14A] {
+                0 => Some(DestinationCode::Japanese),
+                1 => Some(DestinationCode::NonJapanese),
+                _ => None
+            },
+            sgb: match data[0x146] {
+                0x00 => Some(GBSGB_Indicator::GB),
+                0x03 => Some(GBSGB_Indicator::SGB),
+                _ => None
+            },
+            old_licensee: data[0x14B],
+            new_licensee: [ data[0x144], data[0x145] ],
+            mask_rom_vers_number: data[0x14C],
+            nintendo: {
+                let mut texel_data = [0; 48];
+                texel_data.clone_from_slice(&data[0x104..0x134]);
+                Nintendo { texels: texel_data }
+            },
+            ram_size: RAMSize::from_code(data[0x149]),
+            rom_size: ROMSize::from_code(data[0x148]),
+            title: {
+                let field = if cgb { &data[0x134..0x13F] } else { &data[0x134..0x144] };
+                let length = field.iter().rposition(|&byte| byte != 0).map_or(0, |last| last + 1);
+                Title { bytes: field[..length].to_vec() }
+            },
+            manufacturer: if cgb { Some(text(&data[0x13F..0x143])) } else { None },
+            rom_type: match ROMType::from_code(data[0x147]) {
+                Some(rom_type) => rom_type,
+                None => return Err(RomError::UnknownMapper(data[0x147]))
+            }
+        })
     }
 
-    pub fn validate(&self) -> Result<(), &'static str> {
-        let texels = self.get_nintendo_texels();
+    // Stores the header back into a ROM image. Fields that could not be
+    // decoded leave the bytes that are there alone.
+    pub fn write(&self, data: &mut [u8]) -> Result<(), RomError> {
+        if data.len() < 0x150 {
+            return Err(RomError::TooSmall);
+        }
+
+        data[0x104..0x134].copy_from_slice(&self.nintendo.texels);
 
-        for i in 0..texels.len() {
-            if self.nintendo.texels[i] != texels[i] {
-                return Err("Invalid ROM header");
+        if let Some(con_type) = self.con_type {
+            data[0x143] = con_type as u8;
+        }
+
+        if data[0x143] & 0x80 != 0 {
+            write_text(&mut data[0x134..0x13F], &self.title.bytes);
+
+            if let Some(ref manufacturer) = self.manufacturer {
+                write_text(&mut data[0x13F..0x143], manufacturer.as_bytes());
             }
+        } else {
+            write_text(&mut data[0x134..0x144], &self.title.bytes);
+        }
+
+        data[0x144..0x146].copy_from_slice(&self.new_licensee);
+
+        if let Some(sgb) = self.sgb {
+            data[0x146] = sgb as u8;
+        }
+
+        data[0x147] = self.rom_type as u8;
+
+        if let Some(rom_size) = self.rom_size {
+            data[0x148] = rom_size as u8;
+        }
+
+        if let Some(ram_size) = self.ram_size {
+            data[0x149] = ram_size as u8;
+        }
+
+        if let Some(dest_code) = self.dest_code {
+            data[0x14A] = dest_code as u8;
+        }
+
+        data[0x14B] = self.old_licensee;
+        data[0x14C] = self.mask_rom_vers_number;
+        data[0x14D] = self.compl_check;
+        data[0x14E..0x150].copy_from_slice(&self.checksum);
+
+        Ok(())
+    }
+
+    pub fn licensee(&self) -> LicenseCode {
+        match self.old_licensee {
+            0x33 => LicenseCode::New(self.new_licensee),
+            code => LicenseCode::Old(code)
+        }
+    }
+
+    pub fn restore_logo(&mut self) {
+        self.nintendo.texels = NINTENDO_LOGO;
+    }
+
+    fn get_nintendo_texels(&self) -> &[u8; 48] {
+        &NINTENDO_LOGO
+    }
+
+    pub fn validate(&self) -> Result<(), RomError> {
+        if self.nintendo.texels[..] != self.get_nintendo_texels()[..] {
+            return Err(RomError::BadLogo);
         }
 
         Ok(())
     }
+
+    // The global checksum is stored big endian
+    pub fn global_checksum(&self) -> u16 {
+        (self.checksum[0] as u16) << 8 | self.checksum[1] as u16
+    }
+}
+
+// Header strings are ASCII padded with zeros
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim_end_matches('\0').to_string()
+}
+
+fn write_text(field: &mut [u8], text: &[u8]) {
+    let length = text.len().min(field.len());
+
+    for byte in field.iter_mut() {
+        *byte = 0;
+    }
+
+    field[..length].copy_from_slice(&text[..length]);
 }
 
-#[derive(Debug)]
 pub struct Cartridge {
-    pub header: Header
+    pub header: Header,
+    pub rom: Vec<u8>
 }
 
-impl Cartridge<> {
-    pub fn new_from_file(path: &str) -> Result<Self, &'static str> {
-        println!("Loading ROM {}", path);
+impl fmt::Debug for Cartridge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cartridge {{ header: {:?}, rom: [{} bytes] }}", self.header, self.rom.len())
+    }
+}
 
-        let mut file = match File::open(path) {
-            Ok(res) => res,
-            Err(err) => {
-                println!("{:?}", err);
-                return Err("Error on load ROM");
-            }
+impl Cartridge<> {
+    // Bank controller named by the header, owning the ROM from now on
+    pub fn into_mapper(self) -> Box<dyn Mapper> {
+        let ram_size = match self.header.ram_size {
+            Some(ref size) => size.bytes(),
+            None => 0
         };
 
-        let meta = file.metadata().unwrap();
+        let mmm01 = mapper::mmm01_menu_header(&self.rom).map(|header| {
+            let ram_size = RAMSize::from_code(header[0x49]).map_or(0, |size| size.bytes());
+            (ram_size, header[0x47] == 0x0D)
+        });
+
+        if let Some((ram_size, battery)) = mmm01 {
+            return Box::new(mapper::Mmm01::new(self.rom, ram_size, battery));
+        }
+
+        match self.header.rom_type {
+            ROMType::ROM_MBC1 |
+            ROMType::ROM_MBC1_RAM => Box::new(mapper::Mbc1::new(self.rom, ram_size, false)),
+            ROMType::ROM_MBC1_RAM_BATT => Box::new(mapper::Mbc1::new(self.rom, ram_size, true)),
+            ROMType::ROM_MBC2 => Box::new(mapper::Mbc2::new(self.rom, false)),
+            ROMType::ROM_MBC2_BATTERY => Box::new(mapper::Mbc2::new(self.rom, true)),
+            ROMType::ROM_MBC3_TIMER_BATT => Box::new(mapper::Mbc3::new(self.rom, ram_size, true, true)),
+            ROMType::ROM_MBC3_TIMER_RAM_BATT => Box::new(mapper::Mbc3::new(self.rom, ram_size, true, true)),
+            ROMType::ROM_MBC3 |
+            ROMType::ROM_MBC3_RAM => Box::new(mapper::Mbc3::new(self.rom, ram_size, false, false)),
+            ROMType::ROM_MBC3_RAM_BATT => Box::new(mapper::Mbc3::new(self.rom, ram_size, false, true)),
+            ROMType::ROM_MBC5 |
+            ROMType::ROM_MBC5_RAM => Box::new(mapper::Mbc5::new(self.rom, ram_size, false, false)),
+            ROMType::ROM_MBC5_RAM_BATT => Box::new(mapper::Mbc5::new(self.rom, ram_size, true, false)),
+            ROMType::ROM_MBC5_RUMBLE |
+            ROMType::ROM_MBC5_RUMBLE_SRAM => Box::new(mapper::Mbc5::new(self.rom, ram_size, false, true)),
+            ROMType::ROM_MBC5_RUMBLE_SRAM_BATT => Box::new(mapper::Mbc5::new(self.rom, ram_size, true, true)),
+            ROMType::ROM_MMM01 |
+            ROMType::ROM_MMM01_SRAM => Box::new(mapper::Mmm01::new(self.rom, ram_size, false)),
+            ROMType::ROM_MMM01_SRAM_BATT => Box::new(mapper::Mmm01::new(self.rom, ram_size, true)),
+            ROMType::Hudson_HuC_1 => Box::new(mapper::Huc1::new(self.rom, ram_size, true)),
+            ROMType::Hudson_HuC_3 => Box::new(mapper::Huc3::new(self.rom, ram_size, true)),
+            ROMType::Bandai_TAMA5 => Box::new(mapper::Tama5::new(self.rom)),
+            ROMType::Pocket_Camera => Box::new(mapper::PocketCamera::new(self.rom, ram_size)),
+            ROMType::ROM_RAM_BATTERY => Box::new(mapper::RomOnly::new(self.rom, ram_size, true)),
+            _ => Box::new(mapper::RomOnly::new(self.rom, ram_size, false))
+        }
+    }
+
+    pub fn verify_header_checksum(&self) -> Result<(), RomError> {
+        if header_checksum(&self.rom) != self.header.compl_check {
+            return Err(RomError::BadHeaderChecksum);
+        }
+
+        Ok(())
+    }
+
+    pub fn verify_global_checksum(&self) -> Result<(), RomError> {
+        if global_checksum(&self.rom) != self.header.global_checksum() {
+            return Err(RomError::BadGlobalChecksum);
+        }
+
+        Ok(())
+    }
+
+    pub fn new_from_file(path: &str) -> Result<Self, RomError> {
+        println!("Loading ROM {}", path);
+
+        let mut file = File::open(path)?;
 
         let mut data = Vec::new();
-        data.resize(meta.len() as usize, 0u8);
- 
-        file.read(&mut data).unwrap();
+        file.read_to_end(&mut data)?;
 
-        Ok(Self {
-            header: Header {
-                checksum: [ data[0x14E], data[0x14F] ],
-                compl_check: data[0x14D],
-                con_type: match data[0x143] {
-                    0x80 => Some(ConType::Color),
-                    _ => None
-                },
-                dest_code: match data[0x14A] {
-                    0 => Some(DestinationCode::Japanese),
-                    1 => Some(DestinationCode::NonJapanese),
-                    _ => None
-                },
-                lcode: match data[0x14B] {
-                    0x33 => match data[0x144] as u16 | (data[0x145] as u16).wrapping_shl(8) {
-                                0x79 => Some(LicenseCode::Accolade),
-                                0xA4 => Some(LicenseCode::Konami),
-                                _ => None
-                            },
-                    0x79 => Some(LicenseCode::Accolade),
-                    0xA4 => Some(LicenseCode::Konami),
-                    _ => None
-                },
-                mask_rom_vers_number: data[0x14C],
-                nintendo: {
-                    let mut texel_data = [0; 48];
-                    texel_data[..47].clone_from_slice(&data[0x104..0x133]);
-                    Nintendo { texels: texel_data }
-                },
-                ram_size: match data[0x149] {
-                    0 => Some(RAMSize::None),
-                    1 => Some(RAMSize::Sz16kBit),
-                    2 => Some(RAMSize::Sz64kBit),
-                    3 => Some(RAMSize::Sz256kBit),
-                    4 => Some(RAMSize::Sz1MBit),
-                    _ => None
-                },
-                rom_size: match data[0x148] {
-                    0 => Some(ROMSize::Sz256Kbit),
-                    1 => Some(ROMSize::Sz512Kbit),
-                    2 => Some(ROMSize::Sz1Mbit),
-                    3 => Some(ROMSize::Sz2Mbit),
-                    4 => Some(ROMSize::Sz4Mbit),
-                    5 => Some(ROMSize::Sz8Mbit),
-                    6 => Some(ROMSize::Sz16Mbit),
-                    0x52 => Some(ROMSize::Sz9Mbit),
-                    0x53 => Some(ROMSize::Sz10Mbit),
-                    0x54 => Some(ROMSize::Sz12Mbit),
-                    _ => None
-                },
-                title: String::from_utf8(data[0x134..0x142].iter().cloned().collect()).unwrap(),
-                rom_type: match data[0x147] {
-                    0 => Some(ROMType::ROM_Only),
-                    1 => Some(ROMType::ROM_MBC1),
-                    2 => Some(ROMType::ROM_MBC1_RAM),
-                    3 => Some(ROMType::ROM_MBC1_RAM_BATT),
-                    5 => Some(ROMType::ROM_MBC2),
-                    6 => Some(ROMType::ROM_MBC2_BATTERY),
-                    8 => Some(ROMType::ROM_RAM),
-                    9 => Some(ROMType::ROM_RAM_BATTERY),
-                    0xB => Some(ROMType::ROM_MMM01),
-                    0xC => Some(ROMType::ROM_MMM01_SRAM),
-                    0xD => Some(ROMType::ROM_MMM01_SRAM_BATT),
-                    0xF => Some(ROMType::ROM_MBC3_TIMER_BATT),
-                    0x10 => Some(ROMType::ROM_MBC3_TIMER_RAM_BATT),
-                    0x11 => Some(ROMType::ROM_MBC3),
-                    0x12 => Some(ROMType::ROM_MBC3_RAM),
-                    0x13 => Some(ROMType::ROM_MBC3_RAM_BATT),
-                    0x19 => Some(ROMType::ROM_MBC5),
-                    0x1A => Some(ROMType::ROM_MBC5_RAM),
-                    0x1B => Some(ROMType::ROM_MBC5_RAM_BATT),
-                    0x1C => Some(ROMType::ROM_MBC5_RUMBLE),
-                    0x1D => Some(ROMType::ROM_MBC5_RUMBLE_SRAM),
-                    0x1E => Some(ROMType::ROM_MBC5_RUMBLE_SRAM_BATT),
-                    0x1F => Some(ROMType::Pocket_Camera),
-                    0xFD => Some(ROMType::Bandai_TAMA5),
-                    0xFE => Some(ROMType::Hudson_HuC_3),
-                    0xFF => Some(ROMType::Hudson_HuC_1),
-                    _ => None
-                }
+        Cartridge::new(data)
+    }
+
+    // Takes over a ROM image and reads its header
+    pub fn new(data: Vec<u8>) -> Result<Self, RomError> {
+        let header = Header::parse(&data)?;
+
+        // MMM01 carts are checked against the menu's header, the one at
+        // 0x100 only describes the first game
+        let rom_size = match mapper::mmm01_menu_header(&data) {
+            Some(menu) => ROMSize::from_code(menu[0x48]),
+            None => ROMSize::from_code(data[0x148])
+        };
+
+        if let Some(size) = rom_size {
+            if size.bytes() != data.len() {
+                return Err(RomError::SizeMismatch);
             }
+        }
+
+        Ok(Self {
+            header,
+            rom: data
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Header, Title, Cartridge, RomError, ConType, ROMType, ROMSize, RAMSize, DestinationCode, GBSGB_Indicator,
+                NINTENDO_LOGO, header_checksum, global_checksum, fix_header_checksum, fix_global_checksum};
+
+    // 32 KiB ROM with a valid logo and both checksums
+    fn image() -> Vec<u8> {
+        let mut data = vec![0; 0x8000];
+        data[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+        data[0x134..0x138].copy_from_slice(b"TEST");
+        data[0x4000] = 0x99;
+
+        fix_header_checksum(&mut data);
+        fix_global_checksum(&mut data);
+        data
+    }
+
+    #[test]
+    fn header_round_trips_through_write() {
+        let mut data = vec![0; 0x8000];
+        data[0x143] = 0x80;
+
+        let mut header = Header::parse(&data).unwrap();
+        header.restore_logo();
+        header.title = Title { bytes: b"POKEMON RED".to_vec() };
+        header.manufacturer = Some("APSE".to_string());
+        header.con_type = Some(ConType::ColorOnly);
+        header.rom_type = ROMType::ROM_MBC5_RAM_BATT;
+        header.rom_size = Some(ROMSize::Sz8Mbit);
+        header.ram_size = Some(RAMSize::Sz256kBit);
+        header.dest_code = Some(DestinationCode::NonJapanese);
+        header.sgb = Some(GBSGB_Indicator::SGB);
+        header.old_licensee = 0x33;
+        header.new_licensee = *b"01";
+        header.mask_rom_vers_number = 1;
+        header.compl_check = 0x12;
+        header.checksum = [0xAB, 0xCD];
+
+        header.write(&mut data).unwrap();
+        assert_eq!(&data[0x104..0x134], &NINTENDO_LOGO[..]);
+        assert_eq!(&data[0x134..0x144], b"POKEMON REDAPSE\xC0");
+        assert_eq!(Header::parse(&data).unwrap(), header);
+    }
+
+    #[test]
+    fn dmg_title_takes_sixteen_characters() {
+        let mut data = vec![0; 0x8000];
+
+        let mut header = Header::parse(&data).unwrap();
+        header.title = Title { bytes: b"SIXTEEN CHARS OK".to_vec() };
+        header.write(&mut data).unwrap();
+
+        let parsed = Header::parse(&data).unwrap();
+        assert_eq!(parsed.title.bytes, b"SIXTEEN CHARS OK");
+        assert_eq!(parsed.manufacturer, None);
+        assert_eq!(parsed, header);
+    }
+
+    #[test]
+    fn title_bytes_are_written_back_unchanged() {
+        let mut data = image();
+
+        // Shift JIS, a stray 0xFF and a zero inside the title
+        let title = [0x83, 0x7C, 0x83, 0x50, 0xFF, 0x00, 0x41, 0x20];
+        data[0x134..0x13C].copy_from_slice(&title);
+        let original = data.clone();
+
+        let header = Header::parse(&data).unwrap();
+        assert_eq!(header.title.bytes, &title[..]);
+
+        header.write(&mut data).unwrap();
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn header_checksum_covers_0x134_to_0x14c() {
+        let mut data = vec![0; 0x150];
+
+        // Every zero byte takes one off
+        assert_eq!(header_checksum(&data), 0xE7);
+
+        data[0x134] = 0x10;
+        assert_eq!(header_checksum(&data), 0xD7);
+
+        // Bytes outside the range do not count
+        data[0x133] = 0xFF;
+        data[0x14D] = 0xFF;
+        assert_eq!(header_checksum(&data), 0xD7);
+    }
+
+    #[test]
+    fn global_checksum_skips_its_own_bytes() {
+        let mut data = vec![1; 0x8000];
+        assert_eq!(global_checksum(&data), 0x7FFE);
+
+        data[0x14E] = 0xFF;
+        data[0x14F] = 0xFF;
+        assert_eq!(global_checksum(&data), 0x7FFE);
+
+        fix_global_checksum(&mut data);
+        assert_eq!(&data[0x14E..0x150], &[0x7F, 0xFE]);
+    }
+
+    #[test]
+    fn fixed_image_passes_every_check() {
+        let cart = Cartridge::new(image()).unwrap();
+
+        assert!(cart.header.validate().is_ok());
+        assert!(cart.verify_header_checksum().is_ok());
+        assert!(cart.verify_global_checksum().is_ok());
+    }
+
+    #[test]
+    fn corruption_is_caught_by_the_right_check() {
+        let mut data = image();
+        data[0x4000] = 0x00;
+        let cart = Cartridge::new(data).unwrap();
+        assert!(cart.verify_header_checksum().is_ok());
+        match cart.verify_global_checksum() {
+            Err(RomError::BadGlobalChecksum) => {}
+            _ => panic!("a changed ROM byte should fail the global checksum")
+        }
+
+        let mut data = image();
+        data[0x134] = b'B';
+        let cart = Cartridge::new(data).unwrap();
+        match cart.verify_header_checksum() {
+            Err(RomError::BadHeaderChecksum) => {}
+            _ => panic!("a changed title should fail the header checksum")
+        }
+
+        let mut data = image();
+        data[0x133] ^= 0x01;
+        let cart = Cartridge::new(data).unwrap();
+        match cart.header.validate() {
+            Err(RomError::BadLogo) => {}
+            _ => panic!("the last byte of the logo should be checked too")
+        }
+    }
+
+    #[test]
+    fn mmm01_type_byte_alone_does_not_make_an_mmm01() {
+        // 64 KiB MBC5 game with 0x0D where an MMM01 menu would keep its
+        // cartridge type, but no logo there
+        let mut data : Vec<u8> = (0..0x10000).map(|index| (index / 0x4000) as u8).collect();
+        data[0x100..0x150].copy_from_slice(&[0; 0x50]);
+        data[0x147] = 0x19;
+        data[0x148] = 0x01;
+        data[0x8000 + 0x147] = 0x0D;
+
+        let cart = Cartridge::new(data).unwrap();
+        assert_eq!(cart.header.rom_type, ROMType::ROM_MBC5);
+
+        // Only MBC5 maps bank 0 at 0x4000, MMM01 would show the menu
+        let mut mapper = cart.into_mapper();
+        mapper.write_register(0x2000, 0x00);
+        assert_eq!(mapper.read_rom(0x4000), 0);
+        assert_eq!(mapper.read_rom(0x0000), 0);
+    }
+}
@@ -0,0 +1,241 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use rom::Mapper;
+
+// Keeps the battery backed RAM of a cartridge in a .sav file next to the
+// ROM, in the raw layout other emulators use. The file is loaded once at
+// start, then written every interval of emulated time and on exit, but
+// only when the contents changed.
+pub struct BatterySave {
+    path : String,
+    // M-cycles between writes, 0 to only write on exit
+    interval : u64,
+    elapsed : u64,
+    // Contents of the file as last read or written
+    saved : Vec<u8>
+}
+
+impl BatterySave<> {
+    pub fn new(path: &str, interval: u64) -> Self {
+        BatterySave {
+            path: path.to_string(),
+            interval,
+            elapsed: 0,
+            saved: Vec::new()
+        }
+    }
+
+    // game.gb becomes game.sav
+    pub fn path_for(rom: &str) -> String {
+        Path::new(rom).with_extension("sav").to_string_lossy().into_owned()
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // A missing file is not an error, the game just starts without a save
+    pub fn load(&mut self, mapper: &mut dyn Mapper) -> io::Result<()> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err)
+        };
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        mapper.load_battery(&data);
+        self.saved = mapper.battery();
+
+        Ok(())
+    }
+
+    pub fn tick(&mut self, cycles: u32, mapper: &dyn Mapper) -> io::Result<()> {
+        if self.interval == 0 {
+            return Ok(());
+        }
+
+        self.elapsed += cycles as u64;
+
+        if self.elapsed < self.interval {
+            return Ok(());
+        }
+
+        self.elapsed = 0;
+        self.flush(mapper)
+    }
+
+    // Writes a temporary file and renames it over the old one, so a crash
+    // halfway through cannot leave a truncated save behind
+    pub fn flush(&mut self, mapper: &dyn Mapper) -> io::Result<()> {
+        let data = mapper.battery();
+
+        if data.is_empty() || data == self.saved {
+            return Ok(());
+        }
+
+        let temporary = format!("{}.tmp", self.path);
+
+        {
+            let mut file = File::create(&temporary)?;
+            file.write_all(&data)?;
+            file.sync_all()?;
+        }
+
+        fs::rename(&temporary, &self.path)?;
+        self.saved = data;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Seek, SeekFrom};
+    use std::path::Path;
+    use std::process;
+
+    use rom::{Cartridge, Mapper};
+
+    use super::BatterySave;
+
+    const RAM_SIZE : usize = 0x800;
+
+    // ROM+RAM+BATTERY cartridge with 2 KiB of RAM, which is always enabled
+    fn cart() -> Box<dyn Mapper> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x09;
+        rom[0x149] = 0x01;
+
+        Cartridge::new(rom).unwrap().into_mapper()
+    }
+
+    // Empty directory of its own for each test
+    fn directory(name: &str) -> String {
+        let path = env::temp_dir().join(format!("gba-sim-save-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn path_replaces_the_extension() {
+        assert_eq!(BatterySave::path_for("roms/tetris.gb"), "roms/tetris.sav");
+        assert_eq!(BatterySave::path_for("roms.v2/zelda.gbc"), "roms.v2/zelda.sav");
+        assert_eq!(BatterySave::path_for("game"), "game.sav");
+    }
+
+    #[test]
+    fn load_fills_the_ram() {
+        let dir = directory("load");
+        let path = format!("{}/game.sav", dir);
+        fs::write(&path, [1, 2, 3, 4]).unwrap();
+
+        let mut cart = cart();
+        let mut save = BatterySave::new(&path, 0);
+        save.load(&mut *cart).unwrap();
+
+        let ram : Vec<u8> = (0xA000..0xA005).map(|address| cart.read_ram(address)).collect();
+        assert_eq!(ram, [1, 2, 3, 4, 0]);
+
+        // What was just loaded is not written back
+        fs::remove_file(&path).unwrap();
+        save.flush(&*cart).unwrap();
+        assert!(!Path::new(&path).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_file_starts_empty() {
+        let dir = directory("missing");
+        let path = format!("{}/game.sav", dir);
+
+        let mut cart = cart();
+        BatterySave::new(&path, 0).load(&mut *cart).unwrap();
+        assert_eq!(cart.battery(), vec![0; RAM_SIZE]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tick_writes_once_per_interval() {
+        let dir = directory("interval");
+        let path = format!("{}/game.sav", dir);
+
+        let mut cart = cart();
+        let mut save = BatterySave::new(&path, 100);
+        save.load(&mut *cart).unwrap();
+
+        cart.write_ram(0xA000, 0x42);
+        save.tick(99, &*cart).unwrap();
+        assert!(!Path::new(&path).exists());
+
+        save.tick(1, &*cart).unwrap();
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), RAM_SIZE);
+        assert_eq!(data[..2], [0x42, 0]);
+
+        // The count starts over after a write
+        cart.write_ram(0xA001, 0x43);
+        save.tick(99, &*cart).unwrap();
+        assert_eq!(fs::read(&path).unwrap()[..2], [0x42, 0]);
+
+        save.tick(1, &*cart).unwrap();
+        assert_eq!(fs::read(&path).unwrap()[..2], [0x42, 0x43]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn zero_interval_only_writes_on_flush() {
+        let dir = directory("exit");
+        let path = format!("{}/game.sav", dir);
+
+        let mut cart = cart();
+        let mut save = BatterySave::new(&path, 0);
+        cart.write_ram(0xA000, 7);
+
+        save.tick(u32::MAX, &*cart).unwrap();
+        assert!(!Path::new(&path).exists());
+
+        save.flush(&*cart).unwrap();
+        assert_eq!(fs::read(&path).unwrap()[0], 7);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flush_replaces_the_file_through_a_rename() {
+        let dir = directory("rename");
+        let path = format!("{}/game.sav", dir);
+        fs::write(&path, vec![1; RAM_SIZE]).unwrap();
+
+        let mut cart = cart();
+        let mut save = BatterySave::new(&path, 0);
+        save.load(&mut *cart).unwrap();
+
+        // A handle on the old file keeps seeing the old contents, which
+        // would not be the case if it was rewritten in place
+        let mut old = File::open(&path).unwrap();
+
+        cart.write_ram(0xA000, 2);
+        save.flush(&*cart).unwrap();
+
+        let mut contents = Vec::new();
+        old.seek(SeekFrom::Start(0)).unwrap();
+        old.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, vec![1; RAM_SIZE]);
+
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data[..2], [2, 1]);
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
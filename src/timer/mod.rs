@@ -0,0 +1,240 @@
+use cpu::{Interrupt, Interrupts};
+
+// Bit of the internal counter feeding TIMA for each TAC clock select
+static TAC_BITS : [u16; 4] = [9, 3, 5, 7];
+
+// DIV, TIMA, TMA and TAC. DIV is the upper byte of a 16-bit counter that
+// advances every T-cycle; TIMA counts falling edges of one of its bits.
+pub struct Timer {
+    counter : u16,
+    tima : u8,
+    tma : u8,
+    tac : u8,
+    // TIMA overflowed during the last M-cycle and still reads as zero
+    overflow : bool,
+    // TIMA was reloaded from TMA during the current M-cycle
+    reloading : bool
+}
+
+impl Timer<> {
+    pub fn new() -> Self {
+        Timer {
+            // Value left by the DMG boot ROM
+            counter: 0xABCC,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow: false,
+            reloading: false
+        }
+    }
+
+    // Advances the timer by one M-cycle
+    pub fn tick(&mut self, interrupts: &mut Interrupts) {
+        self.reloading = false;
+
+        // TMA is copied and the interrupt raised one M-cycle after the
+        // overflow, not immediately
+        if self.overflow {
+            self.overflow = false;
+            self.reloading = true;
+            self.tima = self.tma;
+            interrupts.request(Interrupt::Timer);
+        }
+
+        let signal = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+
+        if signal && !self.signal() {
+            self.increment();
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            _ => self.tac | 0xF8
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            // Resetting the counter is a falling edge if the selected bit
+            // was set, which bumps TIMA
+            0xFF04 => {
+                let signal = self.signal();
+                self.counter = 0;
+
+                if signal {
+                    self.increment();
+                }
+            }
+            0xFF05 => {
+                // Writes are dropped on the cycle TMA is loaded, and cancel
+                // the reload if they land in the cycle before it
+                if !self.reloading {
+                    self.tima = value;
+                    self.overflow = false;
+                }
+            }
+            0xFF06 => {
+                self.tma = value;
+
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
+            _ => {
+                // Disabling the timer or switching to a bit that is clear
+                // can also produce a falling edge
+                let signal = self.signal();
+                self.tac = value & 0x07;
+
+                if signal && !self.signal() {
+                    self.increment();
+                }
+            }
+        }
+    }
+
+    // Input of the falling edge detector: timer enable ANDed with the
+    // selected counter bit
+    fn signal(&self) -> bool {
+        let bit = TAC_BITS[(self.tac & 0x03) as usize];
+        self.tac & 0x04 != 0 && (self.counter >> bit) & 0x1 != 0
+    }
+
+    fn increment(&mut self) {
+        if self.tima == 0xFF {
+            self.tima = 0;
+            self.overflow = true;
+        } else {
+            self.tima += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::Interrupts;
+
+    use super::Timer;
+
+    // Timer counting every 4 M-cycles (TAC 0x05) from a reset counter
+    fn timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.write(0xFF04, 0);
+        timer.write(0xFF07, 0x05);
+        timer
+    }
+
+    fn run(timer: &mut Timer, interrupts: &mut Interrupts, cycles: u32) {
+        for _ in 0..cycles {
+            timer.tick(interrupts);
+        }
+    }
+
+    #[test]
+    fn div_counts_every_64_m_cycles() {
+        let mut timer = Timer::new();
+        let mut interrupts = Interrupts::new();
+        timer.write(0xFF04, 0x12);
+
+        run(&mut timer, &mut interrupts, 63);
+        assert_eq!(timer.read(0xFF04), 0);
+
+        run(&mut timer, &mut interrupts, 1);
+        assert_eq!(timer.read(0xFF04), 1);
+    }
+
+    #[test]
+    fn tima_counts_falling_edges_of_the_selected_bit() {
+        let mut timer = timer();
+        let mut interrupts = Interrupts::new();
+
+        run(&mut timer, &mut interrupts, 3);
+        assert_eq!(timer.read(0xFF05), 0);
+
+        run(&mut timer, &mut interrupts, 1);
+        assert_eq!(timer.read(0xFF05), 1);
+
+        run(&mut timer, &mut interrupts, 40);
+        assert_eq!(timer.read(0xFF05), 11);
+    }
+
+    #[test]
+    fn resetting_div_with_the_bit_set_bumps_tima() {
+        let mut timer = timer();
+        let mut interrupts = Interrupts::new();
+
+        // Bit 3 of the counter is set after 2 M-cycles
+        run(&mut timer, &mut interrupts, 2);
+        timer.write(0xFF04, 0);
+        assert_eq!(timer.read(0xFF05), 1);
+
+        // Not when it is clear
+        timer.write(0xFF04, 0);
+        assert_eq!(timer.read(0xFF05), 1);
+    }
+
+    #[test]
+    fn disabling_the_timer_with_the_bit_set_bumps_tima() {
+        let mut timer = timer();
+        let mut interrupts = Interrupts::new();
+
+        run(&mut timer, &mut interrupts, 2);
+        timer.write(0xFF07, 0x01);
+        assert_eq!(timer.read(0xFF05), 1);
+        assert_eq!(timer.read(0xFF07), 0xF9);
+    }
+
+    #[test]
+    fn overflow_reloads_tma_one_cycle_late() {
+        let mut timer = timer();
+        let mut interrupts = Interrupts::new();
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF06, 0x80);
+
+        run(&mut timer, &mut interrupts, 4);
+        assert_eq!(timer.read(0xFF05), 0x00);
+        assert_eq!(interrupts.read_flags() & 0x04, 0);
+
+        run(&mut timer, &mut interrupts, 1);
+        assert_eq!(timer.read(0xFF05), 0x80);
+        assert_eq!(interrupts.read_flags() & 0x04, 0x04);
+    }
+
+    #[test]
+    fn writing_tima_before_the_reload_cancels_it() {
+        let mut timer = timer();
+        let mut interrupts = Interrupts::new();
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF06, 0x80);
+
+        run(&mut timer, &mut interrupts, 4);
+        timer.write(0xFF05, 0x10);
+
+        run(&mut timer, &mut interrupts, 1);
+        assert_eq!(timer.read(0xFF05), 0x10);
+        assert_eq!(interrupts.read_flags() & 0x04, 0);
+    }
+
+    #[test]
+    fn writes_during_the_reload_cycle() {
+        let mut timer = timer();
+        let mut interrupts = Interrupts::new();
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF06, 0x80);
+
+        run(&mut timer, &mut interrupts, 5);
+
+        // TIMA is ignored, TMA goes straight through
+        timer.write(0xFF05, 0x10);
+        assert_eq!(timer.read(0xFF05), 0x80);
+
+        timer.write(0xFF06, 0x90);
+        assert_eq!(timer.read(0xFF05), 0x90);
+    }
+}
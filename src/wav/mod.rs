@@ -0,0 +1,152 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+const HEADER_SIZE : u32 = 44;
+
+// The RIFF size counts everything after its own field and must fit in 32
+// bits, which leaves a little less than 4 GiB for samples
+pub const MAX_DATA_SIZE : u32 = u32::MAX - (HEADER_SIZE - 8);
+
+// Streams 16-bit PCM samples into a RIFF WAVE file. The sizes in the header
+// are only known once all samples are in, so they are filled in by finish.
+pub struct WavWriter {
+    file : BufWriter<File>,
+    data_size : u32
+}
+
+impl WavWriter<> {
+    pub fn create(path: &str, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let block_align = channels * 2;
+
+        file.write_all(b"RIFF")?;
+        file.write_all(&le32(0))?;
+        file.write_all(b"WAVE")?;
+
+        file.write_all(b"fmt ")?;
+        file.write_all(&le32(16))?;
+        // Format 1 is uncompressed PCM
+        file.write_all(&le16(1))?;
+        file.write_all(&le16(channels))?;
+        file.write_all(&le32(sample_rate))?;
+        file.write_all(&le32(sample_rate * block_align as u32))?;
+        file.write_all(&le16(block_align))?;
+        file.write_all(&le16(16))?;
+
+        file.write_all(b"data")?;
+        file.write_all(&le32(0))?;
+
+        Ok(WavWriter {
+            file,
+            data_size: 0
+        })
+    }
+
+    // Samples of all channels interleaved
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let data_size = samples.len().checked_mul(2)
+            .and_then(|size| size.checked_add(self.data_size as usize))
+            .filter(|&size| size <= MAX_DATA_SIZE as usize);
+
+        let data_size = match data_size {
+            Some(size) => size as u32,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many samples for a WAV file"))
+        };
+
+        for &sample in samples.iter() {
+            self.file.write_all(&le16(sample as u16))?;
+        }
+
+        self.data_size = data_size;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&le32(HEADER_SIZE - 8 + self.data_size))?;
+
+        self.file.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.file.write_all(&le32(self.data_size))?;
+
+        self.file.flush()
+    }
+}
+
+fn le16(value: u16) -> [u8; 2] {
+    [value as u8, (value >> 8) as u8]
+}
+
+fn le32(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::{WavWriter, MAX_DATA_SIZE};
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        data[offset] as u16 | (data[offset + 1] as u16) << 8
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u16_at(data, offset) as u32 | (u16_at(data, offset + 2) as u32) << 16
+    }
+
+    #[test]
+    fn finish_fills_in_the_header() {
+        let path = env::temp_dir().join(format!("gba-sim-wav-{}.wav", process::id()));
+        let path = path.to_string_lossy().into_owned();
+
+        let mut writer = WavWriter::create(&path, 22050, 2).unwrap();
+        writer.write_samples(&[1, -1, 0x1234, -0x8000]).unwrap();
+        writer.write_samples(&[7, 8]).unwrap();
+        writer.finish().unwrap();
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(data.len(), 44 + 12);
+
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32_at(&data, 4), 36 + 12);
+        assert_eq!(&data[8..12], b"WAVE");
+
+        assert_eq!(&data[12..16], b"fmt ");
+        assert_eq!(u32_at(&data, 16), 16);
+        assert_eq!(u16_at(&data, 20), 1);
+        assert_eq!(u16_at(&data, 22), 2);
+        assert_eq!(u32_at(&data, 24), 22050);
+        assert_eq!(u32_at(&data, 28), 22050 * 4);
+        assert_eq!(u16_at(&data, 32), 4);
+        assert_eq!(u16_at(&data, 34), 16);
+
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(u32_at(&data, 40), 12);
+
+        // Samples follow as little endian 16-bit words
+        assert_eq!(&data[44..52], &[0x01, 0x00, 0xFF, 0xFF, 0x34, 0x12, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn samples_past_the_riff_limit_are_refused() {
+        let path = env::temp_dir().join(format!("gba-sim-wav-limit-{}.wav", process::id()));
+        let path = path.to_string_lossy().into_owned();
+
+        let mut writer = WavWriter::create(&path, 22050, 2).unwrap();
+        writer.data_size = MAX_DATA_SIZE - 4;
+
+        assert!(writer.write_samples(&[1, 2, 3]).is_err());
+        assert_eq!(writer.data_size, MAX_DATA_SIZE - 4);
+
+        writer.write_samples(&[1, 2]).unwrap();
+        assert_eq!(writer.data_size, MAX_DATA_SIZE);
+
+        drop(writer);
+        fs::remove_file(&path).unwrap();
+    }
+}